@@ -0,0 +1,18 @@
+mod util {
+    pub fn two() -> u32 {
+        2
+    }
+}
+
+fn foo() -> u32 {
+    let n = ::util::two();
+    fun_name(n)
+}
+
+fn fun_name(n: u32) -> u32 {
+    n + 2
+}
+
+fn main() {
+
+}
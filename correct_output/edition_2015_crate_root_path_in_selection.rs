@@ -0,0 +1,19 @@
+mod util {
+    pub fn two() -> u32 {
+        2
+    }
+}
+
+fn foo() -> u32 {
+    let n = fun_name();
+    n + 2
+}
+
+fn fun_name() -> u32 {
+    let n = ::util::two() + 1;
+    n
+}
+
+fn main() {
+
+}
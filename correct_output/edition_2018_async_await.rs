@@ -0,0 +1,16 @@
+async fn foo() -> u32 {
+    let n = 2;
+    fun_name(n).await
+}
+
+async fn fun_name(n: u32) -> u32 {
+    bar().await + n
+}
+
+async fn bar() -> u32 {
+    1
+}
+
+fn main() {
+
+}
@@ -0,0 +1,12 @@
+fn foo() -> u32 {
+    let gen = 2;
+    fun_name(gen)
+}
+
+fn fun_name(gen: u32) -> u32 {
+    gen + 2
+}
+
+fn main() {
+
+}
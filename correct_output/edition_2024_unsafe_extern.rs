@@ -0,0 +1,16 @@
+unsafe extern "C" {
+    pub safe fn abs(input: i32) -> i32;
+}
+
+fn foo() -> i32 {
+    let n = -2;
+    fun_name(n)
+}
+
+fn fun_name(n: i32) -> i32 {
+    abs(n) + 1
+}
+
+fn main() {
+
+}
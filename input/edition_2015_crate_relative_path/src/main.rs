@@ -0,0 +1,14 @@
+mod util {
+    pub fn two() -> u32 {
+        2
+    }
+}
+
+fn foo() -> u32 {
+    let n = ::util::two();
+    n + 2
+}
+
+fn main() {
+
+}
@@ -0,0 +1,12 @@
+async fn foo() -> u32 {
+    let n = 2;
+    bar().await + n
+}
+
+async fn bar() -> u32 {
+    1
+}
+
+fn main() {
+
+}
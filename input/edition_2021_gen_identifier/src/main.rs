@@ -0,0 +1,8 @@
+fn foo() -> u32 {
+    let gen = 2;
+    gen + 2
+}
+
+fn main() {
+
+}
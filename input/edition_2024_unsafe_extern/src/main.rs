@@ -0,0 +1,12 @@
+unsafe extern "C" {
+    pub safe fn abs(input: i32) -> i32;
+}
+
+fn foo() -> i32 {
+    let n = -2;
+    abs(n) + 1
+}
+
+fn main() {
+
+}
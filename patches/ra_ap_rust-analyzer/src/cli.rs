@@ -4,6 +4,7 @@
 
 mod analysis_stats;
 mod diagnostics;
+mod extract;
 pub mod flags;
 mod highlight;
 mod lsif;
@@ -0,0 +1,125 @@
+//! Extracts a selection of a file into a new function, the same way as
+//! `rem-extract`: checks the name and selection, runs the `extract_function`
+//! assist, then renames the new function and imports `ControlFlow`, all with
+//! `rem_extract_fixup`.
+
+use anyhow::{bail, format_err, Context};
+use ide::{
+    AnalysisHost, AssistConfig, AssistKind, AssistResolveStrategy, DiagnosticsConfig, Edition,
+    FileRange, SingleResolve, TextRange, TextSize,
+};
+use ide_db::imports::insert_use::{ImportGranularity, InsertUseConfig, PrefixKind};
+use load_cargo::{load_workspace_at, LoadCargoConfig, ProcMacroServerChoice};
+use project_model::{CargoConfig, RustLibSource};
+use rem_extract_fixup::{check_selection, fixup_extracted, CheckedSelection};
+use syntax::SourceFile;
+use vfs::{AbsPathBuf, VfsPath};
+
+use crate::cli::flags;
+
+impl flags::Extract {
+    pub fn run(self) -> anyhow::Result<()> {
+        if self.start >= self.end {
+            bail!(
+                "the start of the selection ({}) must be before its end ({})",
+                self.start,
+                self.end
+            );
+        }
+
+        let path = std::fs::canonicalize(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        let Some(root) = path.parent() else { bail!("{} is not a file", path.display()) };
+        let path = AbsPathBuf::assert_utf8(path.clone());
+
+        let cargo_config = CargoConfig {
+            sysroot: Some(RustLibSource::Discover),
+            all_targets: true,
+            ..Default::default()
+        };
+        let with_proc_macro_server = if self.disable_proc_macros {
+            ProcMacroServerChoice::None
+        } else if let Some(p) = &self.proc_macro_srv {
+            let path = AbsPathBuf::assert_utf8(std::env::current_dir()?.join(p));
+            ProcMacroServerChoice::Explicit(path)
+        } else {
+            ProcMacroServerChoice::Sysroot
+        };
+        let load_cargo_config = LoadCargoConfig {
+            load_out_dirs_from_check: !self.disable_build_scripts,
+            with_proc_macro_server,
+            prefill_caches: false,
+        };
+        let (db, vfs, _proc_macro) =
+            load_workspace_at(root, &cargo_config, &load_cargo_config, &|_| {})?;
+        let file_id = vfs
+            .file_id(&VfsPath::from(path.clone()))
+            .ok_or_else(|| format_err!("{path} is not part of the workspace"))?;
+
+        let host = AnalysisHost::with_database(db);
+        let analysis = host.analysis();
+        let original = analysis.file_text(file_id)?.to_string();
+        let edition = match analysis.crates_for(file_id)?.first() {
+            Some(&krate) => analysis.crate_edition(krate)?,
+            None => Edition::CURRENT,
+        };
+        let source_file = SourceFile::parse(&original, edition).tree();
+        let CheckedSelection { range: (start, end), .. } =
+            check_selection(&source_file, (self.start, self.end), &self.name, edition)
+                .map_err(|e| format_err!("{e}"))?;
+        let frange = FileRange {
+            file_id,
+            range: TextRange::new(TextSize::new(start), TextSize::new(end)),
+        };
+        let resolve = AssistResolveStrategy::Single(SingleResolve {
+            assist_id: "extract_function".to_owned(),
+            assist_kind: AssistKind::RefactorExtract,
+        });
+        let diagnostics_config = DiagnosticsConfig::test_sample();
+        let assist = analysis
+            .assists_with_fixes(&assist_config(), &diagnostics_config, resolve, frange)?
+            .into_iter()
+            .find(|assist| assist.id.0 == "extract_function")
+            .ok_or_else(|| format_err!("extract function is not applicable to the selection"))?;
+        let source_change = assist
+            .source_change
+            .ok_or_else(|| format_err!("extract function did not produce an edit"))?;
+        let (edit, snippet_edit) = source_change
+            .get_source_and_snippet_edit(file_id)
+            .ok_or_else(|| format_err!("extract function did not edit {path}"))?;
+
+        let mut text = original.clone();
+        edit.apply(&mut text);
+        if let Some(snippet_edit) = snippet_edit {
+            snippet_edit.apply(&mut text);
+        }
+        let text = fixup_extracted(&original, text, &self.name, edition);
+
+        if self.in_place {
+            std::fs::write(&path, text).with_context(|| format!("failed to write {path}"))?;
+        } else {
+            print!("{text}");
+        }
+        Ok(())
+    }
+}
+
+fn assist_config() -> AssistConfig {
+    AssistConfig {
+        snippet_cap: None,
+        allowed: Some(vec![AssistKind::RefactorExtract]),
+        insert_use: InsertUseConfig {
+            granularity: ImportGranularity::Preserve,
+            enforce_granularity: false,
+            prefix_kind: PrefixKind::ByCrate,
+            group: false,
+            skip_glob_imports: false,
+        },
+        prefer_no_std: false,
+        prefer_prelude: false,
+        prefer_absolute: false,
+        assist_emit_must_use: false,
+        term_search_fuel: 2048,
+        term_search_borrowck: false,
+    }
+}
@@ -172,6 +172,27 @@ xflags::xflags! {
             /// Exclude code from vendored libraries from the resulting index.
             optional --exclude-vendored-libraries
         }
+
+        /// Extract a selection of a file into a new function and print the modified file.
+        cmd extract {
+            /// The file to extract from.
+            required path: PathBuf
+            /// Name of the new function.
+            required name: String
+            /// Byte offset of the start of the selection.
+            required start: u32
+            /// Byte offset of the end of the selection.
+            required end: u32
+
+            /// Write the modified file back instead of printing it.
+            optional --in-place
+            /// Don't run build scripts or load `OUT_DIR` values by running `cargo check` before analysis.
+            optional --disable-build-scripts
+            /// Don't use expand proc macros.
+            optional --disable-proc-macros
+            /// Run the proc-macro-srv binary at the specified path.
+            optional --proc-macro-srv path: PathBuf
+        }
     }
 }
 
@@ -203,6 +224,7 @@ pub enum RustAnalyzerCmd {
     Search(Search),
     Lsif(Lsif),
     Scip(Scip),
+    Extract(Extract),
 }
 
 #[derive(Debug)]
@@ -307,6 +329,19 @@ pub struct Scip {
     pub exclude_vendored_libraries: bool,
 }
 
+#[derive(Debug)]
+pub struct Extract {
+    pub path: PathBuf,
+    pub name: String,
+    pub start: u32,
+    pub end: u32,
+
+    pub in_place: bool,
+    pub disable_build_scripts: bool,
+    pub disable_proc_macros: bool,
+    pub proc_macro_srv: Option<PathBuf>,
+}
+
 impl RustAnalyzer {
     #[allow(dead_code)]
     pub fn from_env_or_exit() -> Self {
@@ -0,0 +1,227 @@
+//! Checks on the input and post-processing of the output of rust-analyzer's
+//! `extract_function` assist, shared by `rem-extract` and the `extract`
+//! subcommand of the patched rust-analyzer CLI (which can't depend on
+//! `rem-extract` itself).
+//!
+//! The checks (see `check_selection`) refuse invalid function names and
+//! selections of only a comment or brace, and trim whitespace around the
+//! selection. The assist always names the new function `fun_name` (or `fun_name1`,
+//! `fun_name2`... if that name is taken), and may use `ControlFlow` without
+//! importing it.
+
+mod selection;
+
+pub use selection::{
+    check_braces,
+    check_comment,
+    check_fn_name,
+    check_selection,
+    parent_method,
+    trim_range,
+    CheckedSelection,
+    SelectionError,
+};
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    Edition,
+    NodeOrToken,
+    SourceFile,
+    TextRange,
+};
+
+/// The name the assist gives the new function
+pub const ASSIST_FN_NAME: &str = "fun_name";
+
+const CONTROLFLOW_IMPORT: &str = "use std::ops::ControlFlow;";
+
+/// Renames the new function in `extracted` to `new_name` and imports
+/// `ControlFlow` if needed. `original` is the file the assist ran on.
+pub fn fixup_extracted(
+    original: &str,
+    extracted: String,
+    new_name: &str,
+    edition: Edition,
+) -> String {
+    let renamed: String = rename_function( original, extracted, new_name, edition );
+    fixup_controlflow( renamed, edition )
+}
+
+/// Renames the function that the assist added to `text` (the one named
+/// `fun_name...` that `original` doesn't define), along with its calls.
+/// Other occurrences of the name, e.g. in strings, other identifiers or
+/// existing functions, are left alone. Passing `fun_name` keeps whichever name
+/// the assist picked.
+pub fn rename_function(
+    original: &str,
+    text: String,
+    new_name: &str,
+    edition: Edition,
+) -> String {
+    if new_name == ASSIST_FN_NAME {
+        return text;
+    }
+
+    let existing: Vec<String> = fn_names( &SourceFile::parse( original, edition ).tree() );
+    let source_file: SourceFile = SourceFile::parse( &text, edition ).tree();
+    let generated: String = match fn_names( &source_file )
+        .into_iter()
+        .find(|name| name.starts_with( ASSIST_FN_NAME ) && !existing.contains( name ))
+    {
+        Some( generated ) => generated,
+        None => return text,
+    };
+
+    let ranges: Vec<TextRange> = source_file
+        .syntax()
+        .descendants()
+        .filter_map(|node| {
+            if let Some( name ) = ast::Name::cast( node.clone() ) {
+                let is_fn: bool = name.syntax().parent().and_then( ast::Fn::cast ).is_some();
+                return ( is_fn && name.text().as_str() == generated ).then(|| name.syntax().text_range());
+            }
+            let name_ref: ast::NameRef = ast::NameRef::cast( node )?;
+            ( name_ref.text().as_str() == generated && is_callee( &name_ref ) )
+                .then(|| name_ref.syntax().text_range())
+        })
+        .collect();
+
+    // Replace from the end so that the earlier ranges stay valid
+    let mut text: String = text;
+    for range in ranges.into_iter().rev() {
+        text.replace_range( std::ops::Range::<usize>::from( range ), new_name );
+    }
+    text
+}
+
+/// Imports `std::ops::ControlFlow` if `text` uses `ControlFlow::...` without
+/// importing it. The import goes after the inner attributes and doc comments
+/// at the top of the file, which must come first.
+pub fn fixup_controlflow( text: String, edition: Edition ) -> String {
+    let source_file: SourceFile = SourceFile::parse( &text, edition ).tree();
+
+    let uses_controlflow: bool = source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Path::cast )
+        .any(|path| path.qualifier().is_some_and(|qualifier| {
+            qualifier.qualifier().is_none() && qualifier.syntax().text() == "ControlFlow"
+        }));
+    let imports_controlflow: bool = source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Use::cast )
+        .any(|item| item
+            .syntax()
+            .descendants()
+            .filter_map( ast::NameRef::cast )
+            .any(|name_ref| name_ref.text().as_str() == "ControlFlow"));
+    if !uses_controlflow || imports_controlflow {
+        return text;
+    }
+
+    // The end of the last inner attribute or inner doc comment
+    let mut offset: usize = 0;
+    for element in source_file.syntax().children_with_tokens() {
+        match element {
+            NodeOrToken::Node( node ) => match ast::Attr::cast( node.clone() ) {
+                Some( attr ) if attr.excl_token().is_some() => offset = node.text_range().end().into(),
+                _ => break,
+            },
+            NodeOrToken::Token( token ) => {
+                if let Some( comment ) = ast::Comment::cast( token.clone() ) {
+                    if comment.is_inner() {
+                        offset = token.text_range().end().into();
+                    }
+                }
+            },
+        }
+    }
+
+    let mut text: String = text;
+    if offset == 0 {
+        text.insert_str( 0, &format!("{}\n\n", CONTROLFLOW_IMPORT) );
+    } else {
+        text.insert_str( offset, &format!("\n\n{}", CONTROLFLOW_IMPORT) );
+    }
+    text
+}
+
+/// The names of every function in the file, in order
+fn fn_names( source_file: &SourceFile ) -> Vec<String> {
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Fn::cast )
+        .filter_map(|function| function.name())
+        .map(|name| name.text().to_string())
+        .collect()
+}
+
+/// Whether `name_ref` is the function called by a call or method call
+fn is_callee( name_ref: &ast::NameRef ) -> bool {
+    let node = name_ref.syntax();
+    if let Some( call ) = node.parent().and_then( ast::MethodCallExpr::cast ) {
+        return call.name_ref().as_ref() == Some( name_ref );
+    }
+    node.ancestors()
+        .find_map( ast::CallExpr::cast )
+        .and_then(|call| call.expr())
+        .is_some_and(|callee| callee.syntax().text_range().contains_range( node.text_range() ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EDITION: Edition = Edition::Edition2021;
+
+    #[test]
+    fn test_rename_function() {
+        let original = "fn main() {\n    let my_fun_name = 1;\n    println!(\"fun_name\");\n}\n";
+        let extracted = "fn main() {\n    let my_fun_name = 1;\n    fun_name();\n    println!(\"fun_name\");\n}\n\nfn fun_name() {}\n";
+        assert_eq!(
+            rename_function(original, extracted.to_string(), "foo", EDITION),
+            "fn main() {\n    let my_fun_name = 1;\n    foo();\n    println!(\"fun_name\");\n}\n\nfn foo() {}\n",
+        );
+    }
+
+    #[test]
+    fn test_rename_function_with_suffix() {
+        let original = "struct S;\nimpl S {\n    fn fun_name(&self) {}\n    fn run(&self) {\n        let x = 0;\n    }\n}\n";
+        let extracted = "struct S;\nimpl S {\n    fn fun_name(&self) {}\n    fn run(&self) {\n        self.fun_name1();\n    }\n\n    fn fun_name1(&self) {\n        let x = 0;\n    }\n}\n";
+        let renamed = rename_function(original, extracted.to_string(), "foo", EDITION);
+        assert!(renamed.contains("fn fun_name(&self) {}"));
+        assert!(renamed.contains("self.foo();"));
+        assert!(renamed.contains("fn foo(&self) {"));
+
+        // The default name keeps the name the assist picked
+        assert_eq!(rename_function(original, extracted.to_string(), ASSIST_FN_NAME, EDITION), extracted);
+    }
+
+    #[test]
+    fn test_fixup_controlflow() {
+        let text = "fn f() -> ControlFlow<()> {\n    ControlFlow::Continue(())\n}\n";
+        assert_eq!(fixup_controlflow(text.to_string(), EDITION), format!("use std::ops::ControlFlow;\n\n{}", text));
+
+        // Imports go after the inner attributes and doc comments
+        let text = "//! Docs\n#![allow(unused)]\n\nfn f() {\n    ControlFlow::Break(());\n}\n";
+        assert_eq!(
+            fixup_controlflow(text.to_string(), EDITION),
+            "//! Docs\n#![allow(unused)]\n\nuse std::ops::ControlFlow;\n\nfn f() {\n    ControlFlow::Break(());\n}\n",
+        );
+
+        // Files that don't use it, or already import it, are unchanged
+        for text in [
+            "fn f() {}\n",
+            "use std::ops::ControlFlow;\nfn f() {\n    ControlFlow::Break(());\n}\n",
+            "use core::ops::{ControlFlow, Range};\nfn f() {\n    ControlFlow::Break(());\n}\n",
+        ] {
+            assert_eq!(fixup_controlflow(text.to_string(), EDITION), text);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Checks on the selection and the new function name, run before the assist.
+//! The assist itself accepts some selections that give useless results, such
+//! as a lone comment or brace.
+
+use std::fmt;
+
+use ra_ap_syntax::{
+    algo,
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    Direction,
+    Edition,
+    SourceFile,
+    SyntaxElement,
+    SyntaxKind,
+    TextRange,
+    TextSize,
+    T,
+};
+
+/// Why a selection or function name is refused
+#[derive(Debug, Clone)]
+pub enum SelectionError {
+    /// The name is not a valid identifier in the edition of the crate. Holds
+    /// the reason.
+    InvalidFunctionName(String),
+    /// The selection only covers this comment
+    Comment(SyntaxElement),
+    /// The selection only covers this brace, bracket or parenthesis
+    Brace(SyntaxElement),
+    /// The selection is not inside a function
+    ParentMethodNotFound,
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            SelectionError::InvalidFunctionName( reason ) => write!( f, "Invalid function name: {}", reason ),
+            SelectionError::Comment( _ ) => write!( f, "Extraction not applicable for comment" ),
+            SelectionError::Brace( brace ) => write!( f, "Extraction not applicable for braces: the selection only covers a `{}`", brace ),
+            SelectionError::ParentMethodNotFound => write!( f, "Parent method not found" ),
+        }
+    }
+}
+
+impl std::error::Error for SelectionError {}
+
+/// The result of `check_selection`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckedSelection {
+    /// The selection without leading and trailing whitespace
+    pub range: (u32, u32),
+    /// The name of the function containing the selection
+    pub parent_method: String,
+}
+
+/// Runs every check in the order rem-extract runs them: the name, then the
+/// comment, brace and parent method checks on the trimmed selection.
+/// `source_file` must be parsed with `edition`.
+pub fn check_selection(
+    source_file: &SourceFile,
+    range: (u32, u32),
+    new_name: &str,
+    edition: Edition,
+) -> Result<CheckedSelection, SelectionError> {
+    check_fn_name( new_name, edition )?;
+    let range: (u32, u32) = trim_range( source_file, range );
+    check_comment( source_file, range )?;
+    check_braces( source_file, range )?;
+    let parent_method: String = parent_method( source_file, range )?;
+    Ok( CheckedSelection { range, parent_method } )
+}
+
+/// Checks that the new function name is a valid identifier in the edition of
+/// the crate being edited, e.g. `async` is allowed in 2015 but not in 2018.
+/// Raw identifiers (`r#async`) are accepted for any keyword that allows them.
+pub fn check_fn_name( name: &str, edition: Edition ) -> Result<(), SelectionError> {
+    let (ident, is_raw) = match name.strip_prefix( "r#" ) {
+        Some( ident ) => ( ident, true ),
+        None => ( name, false ),
+    };
+
+    let mut chars = ident.chars();
+    let valid_start: bool = chars
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_');
+    if !valid_start || ident == "_" || !chars.all(|c| c.is_alphanumeric() || c == '_') {
+        return Err( SelectionError::InvalidFunctionName(
+            format!("`{}` is not a valid identifier", name)
+        ) );
+    }
+
+    if is_raw && matches!( ident, "crate" | "self" | "super" | "Self" ) {
+        return Err( SelectionError::InvalidFunctionName(
+            format!("`{}` cannot be a raw identifier", ident)
+        ) );
+    }
+
+    if !is_raw && SyntaxKind::from_keyword( ident, edition ).is_some() {
+        return Err( SelectionError::InvalidFunctionName(
+            format!("`{}` is a keyword in edition {}, use `r#{}` instead", ident, edition, ident)
+        ) );
+    }
+
+    Ok(())
+}
+
+/// Trims the selected range to remove any whitespace. A selection of only
+/// whitespace is returned as is.
+pub fn trim_range( source_file: &SourceFile, range: (u32, u32) ) -> (u32, u32) {
+    let start: TextSize = TextSize::new( range.0 );
+    let end: TextSize = TextSize::new( range.1 );
+    let left = source_file
+        .syntax()
+        .token_at_offset( start )
+        .right_biased()
+        .and_then(|t| algo::skip_whitespace_token( t, Direction::Next ))
+        .map(|t| t.text_range().start().clamp( start, end ));
+    let right = source_file
+        .syntax()
+        .token_at_offset( end )
+        .left_biased()
+        .and_then(|t| algo::skip_whitespace_token( t, Direction::Prev ))
+        .map(|t| t.text_range().end().clamp( start, end ));
+
+    let trimmed_range: TextRange = match ( left, right ) {
+        ( Some( left ), Some( right ) ) if left <= right => TextRange::new( left, right ),
+        _ => TextRange::new( start, end ),
+    };
+
+    ( trimmed_range.start().into(), trimmed_range.end().into() )
+}
+
+/// Checks that the selection covers more than a comment
+pub fn check_comment( source_file: &SourceFile, range: (u32, u32) ) -> Result<(), SelectionError> {
+    let element: SyntaxElement = covering_element( source_file, range );
+    if element.kind() == SyntaxKind::COMMENT {
+        return Err( SelectionError::Comment( element ) );
+    }
+    Ok(())
+}
+
+/// Checks that the selection covers more than one of `{}`, `()` or `[]`
+pub fn check_braces( source_file: &SourceFile, range: (u32, u32) ) -> Result<(), SelectionError> {
+    let element: SyntaxElement = covering_element( source_file, range );
+    if matches!( element.kind(), T!['{'] | T!['}'] | T!['('] | T![')'] | T!['['] | T![']'] ) {
+        return Err( SelectionError::Brace( element ) );
+    }
+    Ok(())
+}
+
+/// The name of the innermost function containing the start of the selection
+pub fn parent_method( source_file: &SourceFile, range: (u32, u32) ) -> Result<String, SelectionError> {
+    algo::find_node_at_offset::<ast::Fn>( source_file.syntax(), TextSize::new( range.0 ) )
+        .and_then(|function| function.name())
+        .map(|name| name.text().trim().to_string())
+        .filter(|name| !name.is_empty())
+        .ok_or( SelectionError::ParentMethodNotFound )
+}
+
+fn covering_element( source_file: &SourceFile, range: (u32, u32) ) -> SyntaxElement {
+    let range: TextRange = TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) );
+    source_file.syntax().covering_element( range )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODE: &str = "fn main() {\n    // comment\n    let x = 1;\n    foo(x);\n}\n";
+
+    fn range_of(text: &str) -> (u32, u32) {
+        let start = CODE.find(text).unwrap() as u32;
+        (start, start + text.len() as u32)
+    }
+
+    // Test that keywords are checked against the edition of the crate
+    #[test]
+    fn test_check_fn_name_edition_keywords() {
+        assert!(check_fn_name("async", Edition::Edition2015).is_ok());
+        assert!(check_fn_name("async", Edition::Edition2018).is_err());
+        assert!(check_fn_name("gen", Edition::Edition2021).is_ok());
+        assert!(check_fn_name("gen", Edition::Edition2024).is_err());
+        assert!(check_fn_name("fn", Edition::Edition2015).is_err());
+    }
+
+    // Test that raw identifiers and invalid identifiers are handled
+    #[test]
+    fn test_check_fn_name_identifiers() {
+        assert!(check_fn_name("fun_name", Edition::Edition2021).is_ok());
+        assert!(check_fn_name("r#async", Edition::Edition2021).is_ok());
+        assert!(check_fn_name("r#self", Edition::Edition2021).is_err());
+        assert!(check_fn_name("1fun", Edition::Edition2021).is_err());
+        assert!(check_fn_name("fun-name", Edition::Edition2021).is_err());
+        assert!(check_fn_name("_", Edition::Edition2021).is_err());
+        assert!(check_fn_name("", Edition::Edition2021).is_err());
+    }
+
+    #[test]
+    fn test_check_selection() {
+        let source_file = SourceFile::parse(CODE, Edition::Edition2021).tree();
+
+        // The whitespace around the selection is trimmed
+        let (start, end) = range_of("let x = 1;");
+        let checked = check_selection(&source_file, (start - 4, end + 1), "foo", Edition::Edition2021).unwrap();
+        assert_eq!(checked, CheckedSelection { range: (start, end), parent_method: "main".to_string() });
+
+        assert!(matches!(
+            check_selection(&source_file, range_of("// comment"), "foo", Edition::Edition2021),
+            Err(SelectionError::Comment(_)),
+        ));
+        let brace = CODE.find('}').unwrap() as u32;
+        assert!(matches!(
+            check_selection(&source_file, (brace, brace + 1), "foo", Edition::Edition2021),
+            Err(SelectionError::Brace(_)),
+        ));
+        assert!(matches!(
+            check_selection(&source_file, range_of("let x = 1;"), "fn", Edition::Edition2021),
+            Err(SelectionError::InvalidFunctionName(_)),
+        ));
+
+        let source_file = SourceFile::parse("const X: i32 = 1 + 2;\n", Edition::Edition2021).tree();
+        assert!(matches!(parent_method(&source_file, (15, 20)), Err(SelectionError::ParentMethodNotFound)));
+    }
+}
@@ -1,8 +1,18 @@
-use clap::{ArgAction, Parser, Subcommand};
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum};
 
 use std::path::PathBuf;
 
-use crate::messages::{about::ABOUT, author::AUTHOR, version::VERSION};
+use crate::{
+    differential::DifferentialMode,
+    extraction::{
+        ExtractionOptions,
+        LoadOptions,
+        LoadStrategy,
+        VerifyMode,
+    },
+    messages::{about::ABOUT, author::AUTHOR, version::VERSION},
+    placeholders::PlaceholderMode,
+};
 
 #[derive(Parser)]
 #[command(
@@ -13,6 +23,37 @@ use crate::messages::{about::ABOUT, author::AUTHOR, version::VERSION};
 pub struct EXTRACTArgs {
     #[command(subcommand)]
     pub command: EXTRACTCommands,
+
+    #[arg(short, long, global = true, help = "Only print results and errors, no status messages", action = ArgAction::SetTrue)]
+    pub quiet: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// The modified file, followed by a status line
+    #[default]
+    Text,
+    /// A single JSON object describing the result or the error
+    Json,
+}
+
+/// The values of `--load-strategy`, see `LoadStrategy`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, ValueEnum)]
+pub enum LoadStrategyArg {
+    /// Load the whole workspace and every (transitive) dependency
+    #[default]
+    Full,
+    /// Load only the crate containing the input file, its direct dependencies and the sysroot
+    Minimal,
+}
+
+impl From<LoadStrategyArg> for LoadStrategy {
+    fn from(strategy: LoadStrategyArg) -> Self {
+        match strategy {
+            LoadStrategyArg::Full => LoadStrategy::Full,
+            LoadStrategyArg::Minimal => LoadStrategy::Minimal,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -33,6 +74,67 @@ pub enum EXTRACTCommands {
 
         #[arg(short, long, help = "Enable verbose output", action = ArgAction::SetTrue)]
         verbose: bool,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        checks: CheckArgs,
+
+        #[arg(long, help = "Report the progress of each phase of the extraction on stderr", action = ArgAction::SetTrue)]
+        progress: bool,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "The format of the output on stdout")]
+        format: OutputFormat,
+
+        #[arg(short, long, help = "Write the modified code back to the input file", action = ArgAction::SetTrue, conflicts_with = "output")]
+        in_place: bool,
+
+        #[arg(long, help = "With --in-place, save the original file as <file>.bak first", action = ArgAction::SetTrue, requires = "in_place")]
+        backup: bool,
+
+        #[arg(short, long, help = "Write the modified code to this file instead of stdout")]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "Print a unified diff of the changes instead of the modified code (text format only)", action = ArgAction::SetTrue)]
+        diff: bool,
+    },
+
+    // Inline a function into its callers (or a single call), undoing an extraction
+    #[command(group(ArgGroup::new("target").args(["function", "offset"]).required(true).multiple(false)))]
+    Inline {
+        #[arg(help = "The path to the file containing the function or the call")]
+        file_path: PathBuf,
+
+        #[arg(long, help = "Inline every call of this function and remove it")]
+        function: Option<String>,
+
+        #[arg(long, help = "Inline the call at this byte offset, keeping the function")]
+        offset: Option<u32>,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[arg(long, value_enum, default_value_t = VerifyMode::Off, help = "Run rust-analyzer's diagnostics on the modified file. `warn` reports new diagnostics as warnings, `deny` fails if there are new errors")]
+        verify: VerifyMode,
+
+        #[arg(long, help = "Report the progress of each phase on stderr", action = ArgAction::SetTrue)]
+        progress: bool,
+
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, help = "The format of the output on stdout")]
+        format: OutputFormat,
+
+        #[arg(short, long, help = "Write the modified code back to the input file, and to the other files with inlined calls", action = ArgAction::SetTrue, conflicts_with = "output")]
+        in_place: bool,
+
+        #[arg(long, help = "With --in-place, save each original file as <file>.bak first", action = ArgAction::SetTrue, requires = "in_place")]
+        backup: bool,
+
+        #[arg(short, long, help = "Write the modified code to this file instead of stdout")]
+        output: Option<PathBuf>,
+
+        #[arg(long, help = "Print a unified diff of the changes instead of the modified code (text format only)", action = ArgAction::SetTrue)]
+        diff: bool,
     },
 
     // Test the extraction process
@@ -42,5 +144,129 @@ pub enum EXTRACTCommands {
 
         # [arg(short, long, help = "Enable spammy output - rustc will yell at you", action = ArgAction::SetTrue)]
         spammy: bool,
+
+        #[arg(long, value_enum, default_value_t = DifferentialMode::Off, help = "Also run each input program and its extracted version, and fail the test if their behaviour differs. `tests` also compares `cargo test`")]
+        differential: DifferentialMode,
+    },
+
+    // Run the extractions in a JSONL file against one loaded workspace
+    Batch {
+        #[arg(help = "The JSONL file of requests, one serialized ExtractionInput per line (`-` for stdin)")]
+        requests_path: PathBuf,
+
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        checks: CheckArgs,
+
+        #[arg(long, help = "Report the progress of each phase of the extraction on stderr", action = ArgAction::SetTrue)]
+        progress: bool,
+    },
+
+    // Serve JSON-RPC requests on stdin/stdout, keeping projects loaded
+    Serve {
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        checks: CheckArgs,
     },
+
+    // Run a language server offering extract method as a code action
+    Lsp {
+        #[command(flatten)]
+        load: LoadArgs,
+
+        #[command(flatten)]
+        checks: CheckArgs,
+    },
+
+    // Manage the on-disk cache of project models
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+}
+
+/// Options controlling how projects are loaded, shared by the subcommands
+#[derive(Args, Debug)]
+pub struct LoadArgs {
+    #[arg(long, help = "Path to the sysroot (defaults to `rustc --print sysroot`)")]
+    pub sysroot: Option<PathBuf>,
+
+    #[arg(long, help = "Path to the sysroot library sources (defaults to {sysroot}/lib/rustlib/src/rust/library)")]
+    pub sysroot_src: Option<PathBuf>,
+
+    #[arg(long, help = "The rustup toolchain to load the project with, overriding rust-toolchain.toml")]
+    pub toolchain: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = LoadStrategyArg::Full, help = "How much of the workspace to load. `minimal` only loads the target crate and its direct dependencies")]
+    pub load_strategy: LoadStrategyArg,
+
+    #[arg(long, help = "Do not read or write the on-disk cache of the project model", action = ArgAction::SetTrue)]
+    pub no_cache: bool,
+
+    #[arg(long, help = "Run the build scripts of the workspace (with `cargo check`) so that `OUT_DIR` and the cfgs they set resolve", action = ArgAction::SetTrue)]
+    pub build_scripts: bool,
+}
+
+impl LoadArgs {
+    pub fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            sysroot: self.sysroot.as_ref().map(|p| p.to_string_lossy().to_string()),
+            sysroot_src: self.sysroot_src.as_ref().map(|p| p.to_string_lossy().to_string()),
+            toolchain: self.toolchain.clone(),
+            load_strategy: self.load_strategy.into(),
+            use_cache: !self.no_cache,
+            build_scripts: self.build_scripts,
+        }
+    }
+}
+
+/// Checks run on the result of each extraction, shared by the subcommands
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    #[arg(long, value_enum, default_value_t = VerifyMode::Off, help = "Run rust-analyzer's diagnostics on the modified file. `warn` reports new diagnostics as warnings, `deny` fails if there are new errors")]
+    pub verify: VerifyMode,
+
+    #[arg(long, help = "Run `cargo check --offline` on a copy of the project containing the result, and report where the errors are", action = ArgAction::SetTrue)]
+    pub verify_build: bool,
+
+    #[arg(long, value_enum, default_value_t = DifferentialMode::Off, help = "Build and run the original and the extracted program in a copy of the project and compare their exit status, stdout and stderr. `tests` also compares `cargo test`")]
+    pub differential: DifferentialMode,
+
+    #[arg(long, value_enum, default_value_t = PlaceholderMode::Off, help = "Resolve the placeholder types (`_`) in the signature. `annotations` uses the annotations in the parent method, `build` also uses the types suggested by `cargo check`")]
+    pub resolve_placeholders: PlaceholderMode,
+
+    #[arg(long, help = "Extract even if the file has syntax errors, warning about those that overlap the selection", action = ArgAction::SetTrue)]
+    pub allow_errors: bool,
+
+    #[arg(long, help = "Inline the new function back in memory and report how the result differs from the original file", action = ArgAction::SetTrue)]
+    pub round_trip: bool,
+}
+
+impl CheckArgs {
+    pub fn extraction_options(&self) -> ExtractionOptions {
+        ExtractionOptions {
+            verify: self.verify,
+            verify_build: self.verify_build,
+            differential: self.differential,
+            placeholders: self.resolve_placeholders,
+            allow_errors: self.allow_errors,
+            round_trip: self.round_trip,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    // Remove cached project models
+    Clear {
+        #[arg(help = "Only clear the entries for the project with this Cargo.toml")]
+        manifest_path: Option<PathBuf>,
+    },
+
+    // Print the location of the cache
+    Dir,
 }
@@ -0,0 +1,214 @@
+//! Batch mode: runs many extractions against one `ExtractionSession`.
+//!
+//! The input is JSONL, one request per line. A request is a serialized
+//! `ExtractionInput` (`file_path`, `new_fn_name`, `start_idx`, `end_idx`, or
+//! the short forms `file`, `name`, `start`, `end`) with an optional `id` and
+//! `options`, which override the extraction options the batch was started
+//! with (see `RequestOptions`). Load options apply to the whole batch. Blank
+//! lines are skipped. For every request one result line is written, with the
+//! `line` and `id` of the request, a `status` of `ok` or `error`, the output or
+//! error object (see `ExtractionOutput::to_json` and
+//! `ExtractionError::to_json`) and the time taken in `total_ms`.
+
+use std::{
+    io::{
+        self,
+        BufRead,
+        Write,
+    },
+    time::Instant,
+};
+
+use log::info;
+
+use serde::Deserialize;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use crate::{
+    differential::DifferentialMode,
+    extraction::{
+        ExtractionInput,
+        ExtractionOptions,
+        ProgressEvent,
+        VerifyMode,
+    },
+    placeholders::PlaceholderMode,
+    session::ExtractionSession,
+};
+
+/// A line of the batch input
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct BatchRequest {
+    /// Echoed back in the result, to match results to requests
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(default)]
+    pub options: RequestOptions,
+    #[serde(flatten)]
+    pub input: ExtractionInput,
+}
+
+/// Extraction options of a single request. The options that are not given
+/// are the ones the batch was started with. The names are those of the
+/// command line flags.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestOptions {
+    pub verify: Option<VerifyMode>,
+    pub verify_build: Option<bool>,
+    pub differential: Option<DifferentialMode>,
+    pub resolve_placeholders: Option<PlaceholderMode>,
+    pub allow_errors: Option<bool>,
+    pub round_trip: Option<bool>,
+}
+
+impl RequestOptions {
+    /// The options of the batch, overridden by the ones of the request
+    pub fn apply( &self, options: &ExtractionOptions ) -> ExtractionOptions {
+        ExtractionOptions {
+            verify: self.verify.unwrap_or( options.verify ),
+            verify_build: self.verify_build.unwrap_or( options.verify_build ),
+            differential: self.differential.unwrap_or( options.differential ),
+            placeholders: self.resolve_placeholders.unwrap_or( options.placeholders ),
+            allow_errors: self.allow_errors.unwrap_or( options.allow_errors ),
+            round_trip: self.round_trip.unwrap_or( options.round_trip ),
+        }
+    }
+}
+
+/// Counts of the results written by `run_batch`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Runs every request read from `requests` in the session, writing one JSON
+/// result line per request to `results` as soon as it is done. Only I/O errors
+/// on `requests` or `results` stop the batch; a malformed request gets an
+/// `invalid_request` error result.
+pub fn run_batch(
+    session: &mut ExtractionSession,
+    requests: impl BufRead,
+    mut results: impl Write,
+    progress: &dyn Fn(ProgressEvent),
+) -> io::Result<BatchSummary> {
+    let mut summary: BatchSummary = BatchSummary::default();
+
+    for (index, line) in requests.lines().enumerate() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_number: usize = index + 1;
+        let start: Instant = Instant::now();
+
+        let (id, mut result): (Option<Value>, Value) = match serde_json::from_str::<BatchRequest>( &line ) {
+            Ok( request ) => {
+                info!("Batch request {}: {:?} {:?}", line_number, request.input, request.options);
+                let options: ExtractionOptions = request.options.apply( session.extraction_options() );
+                match session.extract_configured( request.input, &options, progress ) {
+                    Ok( output ) => {
+                        summary.succeeded += 1;
+                        (request.id, output.to_json())
+                    },
+                    Err( e ) => {
+                        info!("Batch request {} failed: {}", line_number, e);
+                        summary.failed += 1;
+                        (request.id, e.to_json())
+                    },
+                }
+            },
+            Err( e ) => {
+                summary.failed += 1;
+                (None, invalid_request( &e.to_string() ))
+            },
+        };
+
+        result["line"] = json!( line_number );
+        result["id"] = id.unwrap_or( Value::Null );
+        result["total_ms"] = json!( start.elapsed().as_secs_f64() * 1000.0 );
+        writeln!( results, "{}", result )?;
+        results.flush()?;
+    }
+
+    Ok( summary )
+}
+
+/// The error result for a line that is not a valid request
+fn invalid_request( message: &str ) -> Value {
+    json!({
+        "status": "error",
+        "error": {
+            "code": "invalid_request",
+            "class": "bad_input",
+            "message": message,
+            "location": null,
+            "causes": [],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::LoadOptions;
+
+    #[test]
+    fn test_parse_request() {
+        let request: BatchRequest = serde_json::from_str(
+            r#"{"id": 7, "file": "src/main.rs", "name": "foo", "start": 10, "end": 20}"#
+        ).unwrap();
+        assert_eq!(request.id, Some(json!(7)));
+        assert_eq!(request.input, ExtractionInput::new("src/main.rs", "foo", 10, 20));
+        assert_eq!(request.options, RequestOptions::default());
+    }
+
+    #[test]
+    fn test_request_options_override_the_batch() {
+        let request: BatchRequest = serde_json::from_str(
+            r#"{"file": "src/main.rs", "name": "foo", "start": 10, "end": 20, "options": {"verify": "deny", "round_trip": true}}"#
+        ).unwrap();
+        let batch = ExtractionOptions { allow_errors: true, round_trip: false, ..Default::default() };
+        assert_eq!(request.options.apply(&batch), ExtractionOptions {
+            verify: VerifyMode::Deny,
+            allow_errors: true,
+            round_trip: true,
+            ..Default::default()
+        });
+
+        let unknown = serde_json::from_str::<BatchRequest>(
+            r#"{"file": "src/main.rs", "name": "foo", "start": 10, "end": 20, "options": {"load_strategy": "minimal"}}"#
+        );
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn test_run_batch_reports_errors_per_line() {
+        let mut session = ExtractionSession::new(LoadOptions::default());
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = dir.path().join("missing.rs");
+        let request = serde_json::json!({ "file_path": missing, "new_fn_name": "foo", "start_idx": 1, "end_idx": 2 });
+        let requests = format!("not json\n\n{}\n", request);
+        let mut results: Vec<u8> = vec![];
+
+        let summary = run_batch(&mut session, requests.as_bytes(), &mut results, &|_| ()).unwrap();
+        assert_eq!(summary, BatchSummary { succeeded: 0, failed: 2 });
+
+        let lines: Vec<Value> = String::from_utf8(results).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["line"], 1);
+        assert_eq!(lines[0]["error"]["code"], "invalid_request");
+        assert_eq!(lines[1]["line"], 3);
+        assert_eq!(lines[1]["status"], "error");
+        assert_eq!(lines[1]["error"]["class"], "bad_input");
+        assert_eq!(session.loaded_projects(), 0);
+    }
+}
@@ -0,0 +1,524 @@
+//! On-disk cache of the project model between invocations of rem-extract.
+//!
+//! Running `cargo metadata` and the build scripts dominates the start-up time
+//! of an extraction, and rem-cli runs the extractor as a new process for every
+//! refactoring. The cargo workspace is stored as a `rust-project.json` after
+//! the first load (see `extraction_utils::cargo_to_project_json`), with the
+//! outputs of the build scripts (`OUT_DIR`, cfgs and environment variables)
+//! if they were run. Later invocations load that directly with
+//! `ProjectWorkspace::load_inline` (pruned for the minimal load strategy),
+//! without calling cargo at all.
+//!
+//! An entry is keyed by the project manifest, the toolchain version, the
+//! sysroot options and whether the build scripts were run. It is invalidated
+//! as soon as the `Cargo.lock`, the manifest or build script of a member or
+//! path dependency, or a `.cargo/config.toml` that applies to the workspace
+//! changes.
+
+use std::{
+    collections::{
+        hash_map::DefaultHasher,
+        BTreeMap,
+        HashMap,
+    },
+    env,
+    fs,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Stdio,
+    },
+};
+
+use log::info;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use ra_ap_project_model::{
+    CargoConfig,
+    CargoWorkspace,
+    ProjectManifest,
+    ProjectWorkspace,
+    ProjectWorkspaceKind,
+    TargetKind,
+};
+
+use crate::{
+    error::ExtractionError,
+    extraction::LoadOptions,
+};
+
+/// Bumped whenever the layout of a cache entry changes
+const CACHE_VERSION: u32 = 2;
+
+/// What a package's build script adds to the crates of the package
+#[derive(Debug, PartialEq, Default)]
+pub struct BuildScriptOutput {
+    pub out_dir: Option<String>,
+    /// In the `key="value"` form of `cargo::rustc-cfg`
+    pub cfgs: Vec<String>,
+    pub envs: Vec<(String, String)>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub version: u32,
+    /// The manifest the project was loaded from
+    pub manifest: String,
+    /// Output of `rustc --version` for the project's toolchain
+    pub toolchain: Option<String>,
+    /// Hashes of the files that invalidate the entry (see `watched_files`).
+    /// A `None` hash means the file did not exist.
+    pub fingerprints: BTreeMap<String, Option<String>>,
+    /// The workspace as a `rust-project.json`
+    pub project: Value,
+}
+
+impl CacheEntry {
+    /// Checks that none of the fingerprinted files have changed
+    pub fn is_fresh(&self) -> bool {
+        self.version == CACHE_VERSION
+            && self.fingerprints
+                .iter()
+                .all(|(path, hash)| hash_file( Path::new( path ) ) == *hash)
+    }
+}
+
+/// Returns the directory cache entries are stored in. In order of preference:
+/// `$REM_EXTRACT_CACHE_DIR`, `$XDG_CACHE_HOME/rem-extract`,
+/// `~/.cache/rem-extract`, `%LOCALAPPDATA%\rem-extract`, or a directory in
+/// the system temp dir.
+pub fn cache_dir() -> PathBuf {
+    if let Some( dir ) = env::var_os( "REM_EXTRACT_CACHE_DIR" ) {
+        return PathBuf::from( dir );
+    }
+    if let Some( dir ) = env::var_os( "XDG_CACHE_HOME" ) {
+        return PathBuf::from( dir ).join( "rem-extract" );
+    }
+    if let Some( dir ) = env::var_os( "HOME" ) {
+        return PathBuf::from( dir ).join( ".cache" ).join( "rem-extract" );
+    }
+    if let Some( dir ) = env::var_os( "LOCALAPPDATA" ) {
+        return PathBuf::from( dir ).join( "rem-extract" );
+    }
+    env::temp_dir().join( "rem-extract" )
+}
+
+/// Reads the cached project for the manifest, if there is a fresh entry for
+/// the current toolchain and sysroot options.
+pub fn read(
+    manifest: &ProjectManifest,
+    cargo_config: &CargoConfig,
+    load_options: &LoadOptions,
+) -> Option<Value> {
+    let toolchain: Option<String> = toolchain_version( manifest, cargo_config );
+    let path: PathBuf = entry_path( manifest, &toolchain, load_options );
+
+    let entry: CacheEntry = read_entry( &path )?;
+    if entry.toolchain != toolchain || !entry.is_fresh() {
+        info!("Cache entry {} is stale", path.display());
+        let _ = fs::remove_file( &path );
+        return None;
+    }
+
+    info!("Loaded project from cache entry {}", path.display());
+    Some( entry.project )
+}
+
+/// Stores the project in the cache. Failing to write the cache is not an
+/// error, the next invocation will just have to load the project again.
+pub fn write(
+    manifest: &ProjectManifest,
+    cargo_config: &CargoConfig,
+    load_options: &LoadOptions,
+    workspace: &ProjectWorkspace,
+    project: &Value,
+) {
+    let cargo: &CargoWorkspace = match &workspace.kind {
+        ProjectWorkspaceKind::Cargo { cargo, .. } => cargo,
+        _ => return,
+    };
+
+    let watched: Vec<PathBuf> = watched_files( manifest, cargo, load_options );
+    let toolchain: Option<String> = toolchain_version( manifest, cargo_config );
+    let entry: CacheEntry = CacheEntry {
+        version: CACHE_VERSION,
+        manifest: manifest.manifest_path().to_string(),
+        toolchain: toolchain.clone(),
+        fingerprints: fingerprint( &watched ),
+        project: project.clone(),
+    };
+
+    let path: PathBuf = entry_path( manifest, &toolchain, load_options );
+    match write_entry( &path, &entry ) {
+        Ok(()) => info!("Wrote cache entry {}", path.display()),
+        Err(e) => info!("Failed to write cache entry {}: {}", path.display(), e),
+    }
+}
+
+/// The files whose changes invalidate a cache entry: the `Cargo.lock`, the
+/// manifest, the manifests of the members and path dependencies (and their
+/// build scripts, if they were run), and every `.cargo/config.toml` cargo
+/// would read for the workspace, whether or not it exists yet.
+fn watched_files(
+    manifest: &ProjectManifest,
+    cargo: &CargoWorkspace,
+    load_options: &LoadOptions,
+) -> Vec<PathBuf> {
+    let mut watched: Vec<PathBuf> = vec![
+        PathBuf::from( cargo.workspace_root().join( "Cargo.lock" ).as_str() ),
+        PathBuf::from( manifest.manifest_path().as_str() ),
+    ];
+    for pkg in cargo.packages().filter(|&pkg| cargo[pkg].is_local) {
+        watched.push( PathBuf::from( cargo[pkg].manifest.as_str() ) );
+        if load_options.build_scripts {
+            watched.extend(
+                cargo[pkg]
+                    .targets
+                    .iter()
+                    .filter(|&&tgt| matches!( cargo[tgt].kind, TargetKind::BuildScript ))
+                    .map(|&tgt| PathBuf::from( cargo[tgt].root.as_str() ))
+            );
+        }
+    }
+
+    let cargo_home: Option<PathBuf> = env::var_os( "CARGO_HOME" )
+        .map( PathBuf::from )
+        .or_else(|| env::var_os( "HOME" ).map(|home| PathBuf::from( home ).join( ".cargo" )));
+    let config_dirs = Path::new( cargo.workspace_root().as_str() )
+        .ancestors()
+        .map(|dir| dir.join( ".cargo" ))
+        .chain( cargo_home );
+    for dir in config_dirs {
+        watched.push( dir.join( "config.toml" ) );
+        watched.push( dir.join( "config" ) );
+    }
+
+    watched
+}
+
+/// Runs the build scripts of the workspace with `cargo check`, the same way as
+/// rust-analyzer does for a cargo workspace, and returns their outputs by the
+/// manifest directory of their package. Packages without a build script, or
+/// whose build script failed, have no output.
+pub fn run_build_scripts(
+    workspace: &ProjectWorkspace,
+    cargo_config: &CargoConfig,
+) -> Result<HashMap<String, BuildScriptOutput>, ExtractionError> {
+    let cargo: &CargoWorkspace = match &workspace.kind {
+        ProjectWorkspaceKind::Cargo { cargo, .. } => cargo,
+        _ => return Ok( HashMap::new() ),
+    };
+    let manifest_dirs: HashMap<&str, &str> = cargo
+        .packages()
+        .map(|pkg| ( cargo[pkg].id.as_str(), cargo[pkg].manifest.parent().as_str() ))
+        .collect();
+
+    let output = Command::new( "cargo" )
+        .args( ["check", "--workspace", "--all-targets", "--keep-going", "--message-format=json", "--manifest-path"] )
+        .arg( cargo.workspace_root().join( "Cargo.toml" ).as_str() )
+        .current_dir( cargo.workspace_root().as_str() )
+        .envs( &cargo_config.extra_env )
+        .stderr( Stdio::null() )
+        .output()
+        .map_err( ExtractionError::InternalIo )?;
+
+    let mut outputs: HashMap<String, BuildScriptOutput> = HashMap::new();
+    for line in String::from_utf8_lossy( &output.stdout ).lines() {
+        let Ok( message ) = serde_json::from_str::<Value>( line ) else { continue };
+        if message["reason"] != "build-script-executed" {
+            continue;
+        }
+        let Some( &manifest_dir ) = message["package_id"].as_str().and_then(|id| manifest_dirs.get( id )) else {
+            continue;
+        };
+        let strings = |value: &Value| -> Vec<String> {
+            value
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item.as_str().map( str::to_string ))
+                .collect()
+        };
+        let envs: Vec<(String, String)> = message["env"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|pair| Some( ( pair[0].as_str()?.to_string(), pair[1].as_str()?.to_string() ) ))
+            .collect();
+        outputs.insert( manifest_dir.to_string(), BuildScriptOutput {
+            out_dir: message["out_dir"].as_str().map( str::to_string ),
+            cfgs: strings( &message["cfgs"] ),
+            envs,
+        } );
+    }
+
+    Ok( outputs )
+}
+
+/// Adds the build script outputs to the crates of a `rust-project.json` made
+/// by `cargo_to_project_json`, matching them by `CARGO_MANIFEST_DIR`. The
+/// `OUT_DIR` is also added to the source directories of the crate, so that
+/// the files it includes from there are loaded.
+pub fn apply_build_script_outputs( project: &mut Value, outputs: &HashMap<String, BuildScriptOutput> ) {
+    let Some( crates ) = project["crates"].as_array_mut() else { return };
+    for krate in crates {
+        let Some( output ) = krate["env"]["CARGO_MANIFEST_DIR"].as_str().and_then(|dir| outputs.get( dir )) else {
+            continue;
+        };
+        if let Some( out_dir ) = &output.out_dir {
+            krate["env"]["OUT_DIR"] = json!( out_dir );
+            if let Some( include_dirs ) = krate["source"]["include_dirs"].as_array_mut() {
+                include_dirs.push( json!( out_dir ) );
+            }
+        }
+        for ( key, value ) in &output.envs {
+            krate["env"][key.as_str()] = json!( value );
+        }
+        if let Some( cfg ) = krate["cfg"].as_array_mut() {
+            cfg.extend( output.cfgs.iter().map(|cfg| json!( cfg )) );
+        }
+    }
+}
+
+/// Removes cache entries. If a manifest is given, only the entries for that
+/// project are removed, otherwise the whole cache is cleared.
+/// Returns the number of entries removed.
+pub fn clear( manifest: Option<&Path> ) -> io::Result<usize> {
+    let dir: PathBuf = cache_dir();
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut removed: usize = 0;
+    for entry in fs::read_dir( &dir )? {
+        let path: PathBuf = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "json") {
+            continue;
+        }
+
+        let matches: bool = match manifest {
+            Some( manifest ) => read_entry( &path )
+                .map_or(true, |entry| Path::new( &entry.manifest ) == manifest),
+            None => true,
+        };
+        if matches {
+            fs::remove_file( &path )?;
+            removed += 1;
+        }
+    }
+
+    Ok( removed )
+}
+
+fn entry_path(
+    manifest: &ProjectManifest,
+    toolchain: &Option<String>,
+    load_options: &LoadOptions,
+) -> PathBuf {
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    manifest.manifest_path().as_str().hash( &mut hasher );
+    toolchain.hash( &mut hasher );
+    load_options.sysroot.hash( &mut hasher );
+    load_options.sysroot_src.hash( &mut hasher );
+    load_options.build_scripts.hash( &mut hasher );
+    cache_dir().join( format!( "{:016x}.json", hasher.finish() ) )
+}
+
+fn read_entry( path: &Path ) -> Option<CacheEntry> {
+    let contents: String = fs::read_to_string( path ).ok()?;
+    serde_json::from_str( &contents ).ok()
+}
+
+/// Writes the entry to a temporary file first, so a concurrent invocation
+/// never reads a half written entry
+fn write_entry( path: &Path, entry: &CacheEntry ) -> io::Result<()> {
+    if let Some( parent ) = path.parent() {
+        fs::create_dir_all( parent )?;
+    }
+    let tmp_path: PathBuf = path.with_extension( format!( "json.{}.tmp", std::process::id() ) );
+    fs::write( &tmp_path, serde_json::to_vec( entry )? )?;
+    fs::rename( &tmp_path, path )
+}
+
+fn fingerprint( paths: &[PathBuf] ) -> BTreeMap<String, Option<String>> {
+    paths
+        .iter()
+        .map(|path| (path.to_string_lossy().to_string(), hash_file( path )))
+        .collect()
+}
+
+fn hash_file( path: &Path ) -> Option<String> {
+    let contents: Vec<u8> = fs::read( path ).ok()?;
+    let mut hasher: DefaultHasher = DefaultHasher::new();
+    contents.hash( &mut hasher );
+    Some( format!( "{:016x}", hasher.finish() ) )
+}
+
+/// Runs `rustc --version` from the project directory, so that any
+/// `rust-toolchain.toml` or `RUSTUP_TOOLCHAIN` override is taken into account
+fn toolchain_version(
+    manifest: &ProjectManifest,
+    cargo_config: &CargoConfig,
+) -> Option<String> {
+    let output = Command::new( "rustc" )
+        .arg( "--version" )
+        .current_dir( manifest.manifest_path().parent().as_str() )
+        .envs( &cargo_config.extra_env )
+        .output()
+        .ok()?;
+
+    output.status.success().then(|| String::from_utf8_lossy( &output.stdout ).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::{extract_method_configured, ExtractionInput, ExtractionOptions, LoadStrategy, ProgressEvent};
+    use crate::extraction_utils::test_project::TestProject;
+    use std::cell::RefCell;
+    use tempfile::TempDir;
+
+    fn sample_entry( watched: &[PathBuf] ) -> CacheEntry {
+        CacheEntry {
+            version: CACHE_VERSION,
+            manifest: "/project/Cargo.toml".to_string(),
+            toolchain: Some("rustc 1.86.0-nightly".to_string()),
+            fingerprints: fingerprint(watched),
+            project: serde_json::json!({ "crates": [] }),
+        }
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("entry.json");
+        let entry = sample_entry(&[]);
+
+        write_entry(&path, &entry).unwrap();
+        assert_eq!(read_entry(&path), Some(entry));
+    }
+
+    #[test]
+    fn test_entry_is_stale_after_manifest_change() {
+        let dir = TempDir::new().unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        fs::write(&manifest, "[package]\nname = \"a\"").unwrap();
+
+        let entry = sample_entry(&[manifest.clone()]);
+        assert!(entry.is_fresh());
+
+        fs::write(&manifest, "[package]\nname = \"b\"").unwrap();
+        assert!(!entry.is_fresh());
+    }
+
+    #[test]
+    fn test_entry_is_stale_when_lockfile_appears() {
+        let dir = TempDir::new().unwrap();
+        let lockfile = dir.path().join("Cargo.lock");
+
+        let entry = sample_entry(&[lockfile.clone()]);
+        assert!(entry.is_fresh());
+
+        fs::write(&lockfile, "version = 3").unwrap();
+        assert!(!entry.is_fresh());
+    }
+
+    const BUILD_RS: &str = r#"use std::{env, fs, path::Path};
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("generated.rs"), "pub struct Generated(pub u64);\npub fn generated() -> Generated { Generated(1) }\n").unwrap();
+    println!("cargo::rustc-check-cfg=cfg(from_build_script)");
+    println!("cargo::rustc-cfg=from_build_script");
+}
+"#;
+
+    const MAIN_RS: &str = r#"include!(concat!(env!("OUT_DIR"), "/generated.rs"));
+
+#[cfg(from_build_script)]
+struct Configured(u64);
+
+fn main() {
+    let g = generated();
+    let c = Configured(2);
+    let n = g.0 + c.0;
+    println!("{}", n);
+}
+"#;
+
+    #[test]
+    fn test_build_script_outputs_resolve_on_warm_cache() {
+        let project = TestProject::new("build_script_test", &[("build.rs", BUILD_RS), ("src/main.rs", MAIN_RS)]);
+        let cargo_toml = project.cargo_toml();
+        let main_rs = project.path("src/main.rs");
+
+        let start = MAIN_RS.find("let n").unwrap() as u32;
+        let end = MAIN_RS.find("println").unwrap() as u32;
+        let input = ExtractionInput::new(&main_rs.to_string_lossy(), "fun_name", start, end);
+
+        for load_strategy in [LoadStrategy::Minimal, LoadStrategy::Full] {
+            let load_options = LoadOptions { load_strategy, use_cache: true, build_scripts: true, ..Default::default() };
+
+            // The first run writes the cache entry, the second one reads it
+            // instead of running cargo and the build scripts
+            for warm in [false, true] {
+                let messages = RefCell::new(Vec::new());
+                let progress = |event: ProgressEvent| {
+                    if let ProgressEvent::Report(_, message) = event {
+                        messages.borrow_mut().push(message);
+                    }
+                };
+                let code = extract_method_configured(input.clone(), &load_options, &ExtractionOptions::default(), &progress).unwrap().code;
+                let from_cache = messages.borrow().iter().any(|message| message == "Loaded project from cache");
+                assert_eq!(from_cache, warm, "{:?}", load_strategy);
+
+                let signature = code.lines().find(|line| line.starts_with("fn fun_name")).unwrap();
+                assert!(signature.contains("g: Generated"), "{}", signature);
+                assert!(signature.contains("c: Configured"), "{}", signature);
+                assert!(signature.contains("-> u64"), "{}", signature);
+            }
+        }
+
+        clear(Some(&cargo_toml)).unwrap();
+    }
+
+    #[test]
+    fn test_apply_build_script_outputs() {
+        let mut project = serde_json::json!({
+            "crates": [
+                { "cfg": ["feature=\"std\""], "env": { "CARGO_MANIFEST_DIR": "/a" }, "source": { "include_dirs": ["/a"], "exclude_dirs": ["/a/target"] } },
+                { "cfg": [], "env": { "CARGO_MANIFEST_DIR": "/b" }, "source": { "include_dirs": ["/b"], "exclude_dirs": ["/b/target"] } },
+            ],
+        });
+        let outputs = HashMap::from([("/a".to_string(), BuildScriptOutput {
+            out_dir: Some("/a/target/debug/build/a-1/out".to_string()),
+            cfgs: vec!["has_simd".to_string()],
+            envs: vec![("VERSION".to_string(), "1".to_string())],
+        })]);
+
+        apply_build_script_outputs(&mut project, &outputs);
+        let a = &project["crates"][0];
+        assert_eq!(a["env"]["OUT_DIR"], "/a/target/debug/build/a-1/out");
+        assert_eq!(a["env"]["VERSION"], "1");
+        assert_eq!(a["cfg"], serde_json::json!(["feature=\"std\"", "has_simd"]));
+        assert_eq!(a["source"]["include_dirs"], serde_json::json!(["/a", "/a/target/debug/build/a-1/out"]));
+        assert_eq!(project["crates"][1]["env"], serde_json::json!({ "CARGO_MANIFEST_DIR": "/b" }));
+    }
+}
@@ -0,0 +1,391 @@
+//! Source locations attached to `ExtractionError`s, and their rendering as
+//! annotated source snippets (line numbers, carets under the selection and a
+//! note on the offending token), in the style of rustc's diagnostics.
+
+use std::fmt::Write;
+
+use colored::Colorize;
+
+use serde::Serialize;
+
+use ra_ap_syntax::SyntaxElement;
+
+/// Selections spanning more lines than this are shown with the middle elided
+const MAX_SNIPPET_LINES: usize = 8;
+
+/// Tabs are expanded to this many spaces so the carets line up
+const TAB_WIDTH: usize = 4;
+
+/// A syntax node (or token) relevant to an error
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct SyntaxNodeInfo {
+    /// The rust-analyzer `SyntaxKind` of the node, e.g. `L_CURLY`
+    pub kind: String,
+    /// Byte range of the node in the file
+    pub range: (u32, u32),
+    /// Source text of the node
+    pub text: String,
+}
+
+impl SyntaxNodeInfo {
+    pub fn from_element( element: &SyntaxElement ) -> Self {
+        let text: String = match element {
+            SyntaxElement::Node( node ) => node.text().to_string(),
+            SyntaxElement::Token( token ) => token.text().to_string(),
+        };
+        SyntaxNodeInfo {
+            kind: format!( "{:?}", element.kind() ),
+            range: (
+                element.text_range().start().into(),
+                element.text_range().end().into(),
+            ),
+            text,
+        }
+    }
+}
+
+/// A syntax error in the input file
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ParseErrorInfo {
+    pub message: String,
+    /// Byte range of the error in the file
+    pub range: (u32, u32),
+    /// 1-based line of the start of the error
+    pub line: usize,
+    /// 1-based column (in characters) of the start of the error
+    pub column: usize,
+}
+
+impl ParseErrorInfo {
+    pub fn new( message: String, range: (u32, u32), source: &str ) -> Self {
+        let ( start, _ ) = clamp_range( source, range );
+        let line_starts: Vec<usize> = line_starts( source );
+        let line: usize = line_of( &line_starts, start );
+        ParseErrorInfo {
+            message,
+            range,
+            line: line + 1,
+            column: source[line_starts[line]..start].chars().count() + 1,
+        }
+    }
+
+    /// Whether the error overlaps the byte range (or touches it, for errors
+    /// with an empty range such as a missing token)
+    pub fn overlaps( &self, range: (u32, u32) ) -> bool {
+        self.range.0 <= range.1 && range.0 <= self.range.1
+    }
+}
+
+impl std::fmt::Display for ParseErrorInfo {
+    fn fmt( &self, f: &mut std::fmt::Formatter<'_> ) -> std::fmt::Result {
+        write!( f, "{}:{}: {}", self.line, self.column, self.message )
+    }
+}
+
+/// Where in the input file an extraction was refused
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ErrorLocation {
+    pub file_path: String,
+    /// The selected byte range. Once the file has been parsed this is the
+    /// range after trimming whitespace, as that is the range that is checked.
+    pub selection: (u32, u32),
+    /// The syntax nodes relevant to the error, innermost first. The first node
+    /// is the offending token (e.g. the brace the selection covers).
+    pub nodes: Vec<SyntaxNodeInfo>,
+    /// Explains what is wrong with the offending node, or with the selection
+    /// if there is no node
+    pub note: Option<String>,
+    /// The text of the file that the error was found in, which may differ from
+    /// the file on disk (e.g. an unsaved file under `serve` or `lsp`). `None`
+    /// for errors found before the file was read.
+    #[serde(skip)]
+    pub source: Option<String>,
+}
+
+impl ErrorLocation {
+    /// A location with no syntax nodes, for errors found before parsing
+    pub fn selection(
+        file_path: &str,
+        selection: (u32, u32),
+        note: Option<String>,
+    ) -> Self {
+        ErrorLocation {
+            file_path: file_path.to_string(),
+            selection,
+            nodes: vec![],
+            note,
+            source: None,
+        }
+    }
+
+    /// The offending node, if there is one
+    pub fn offending_node( &self ) -> Option<&SyntaxNodeInfo> {
+        self.nodes.first()
+    }
+}
+
+/// A range to underline in the snippet
+struct Label {
+    range: (usize, usize),
+    message: String,
+    primary: bool,
+}
+
+/// Renders the header of an error, i.e. `error[code]: message`
+pub fn render_header( code: &str, message: &str, color: bool ) -> String {
+    let error: String = format!( "error[{}]", code );
+    if color {
+        format!( "{}{} {}", error.red().bold(), ":".bold(), message.bold() )
+    } else {
+        format!( "{}: {}", error, message )
+    }
+}
+
+/// Renders an error with a location as an annotated snippet of `source`, the
+/// contents of `location.file_path`. Offsets outside of `source` are clamped.
+pub fn render_snippet(
+    code: &str,
+    message: &str,
+    location: &ErrorLocation,
+    source: &str,
+    color: bool,
+) -> String {
+    let selection: (usize, usize) = clamp_range( source, location.selection );
+    let line_starts: Vec<usize> = line_starts( source );
+
+    // If the offending node is exactly the selection, put the note under the
+    // selection itself. Otherwise underline the node separately.
+    let mut labels: Vec<Label> = vec![];
+    let mut footer_note: Option<&str> = None;
+    match location.offending_node() {
+        Some( node ) if clamp_range( source, node.range ) == selection => {
+            labels.push( Label {
+                range: selection,
+                message: location.note.clone().unwrap_or_else(|| format!( "`{}` ({})", node.text, node.kind )),
+                primary: true,
+            } );
+        },
+        Some( node ) => {
+            labels.push( Label {
+                range: selection,
+                message: "selected here".to_string(),
+                primary: true,
+            } );
+            labels.push( Label {
+                range: clamp_range( source, node.range ),
+                message: location.note.clone().unwrap_or_else(|| format!( "`{}` ({})", node.text, node.kind )),
+                primary: false,
+            } );
+        },
+        None => {
+            labels.push( Label {
+                range: selection,
+                message: if selection.0 == selection.1 { "empty selection".to_string() } else { "selected here".to_string() },
+                primary: true,
+            } );
+            footer_note = location.note.as_deref();
+        },
+    }
+
+    let first_line: usize = labels.iter().map(|l| line_of( &line_starts, l.range.0 )).min().unwrap_or(0);
+    let last_line: usize = labels.iter().map(|l| last_line_of( &line_starts, l.range )).max().unwrap_or(0);
+    let (line, column) = line_col( source, &line_starts, selection.0 );
+    let gutter: usize = (last_line + 1).to_string().len();
+    let blank: String = " ".repeat( gutter );
+
+    let paint = |s: String, primary: bool| -> String {
+        match (color, primary) {
+            (false, _) => s,
+            (true, true) => s.red().bold().to_string(),
+            (true, false) => s.blue().bold().to_string(),
+        }
+    };
+    let bar: String = paint( "|".to_string(), false );
+
+    let mut out: String = render_header( code, message, color );
+    let _ = write!( out, "\n{}{} {}:{}:{}", blank, paint( "-->".to_string(), false ), location.file_path, line, column );
+    let _ = write!( out, "\n{} {}", blank, bar );
+
+    for line_idx in shown_lines( first_line, last_line ) {
+        let Some( line_idx ) = line_idx else {
+            let _ = write!( out, "\n{}", paint( "...".to_string(), false ) );
+            continue;
+        };
+        let (start, end) = line_bounds( source, &line_starts, line_idx );
+        let text: &str = &source[start..end];
+        let _ = write!(
+            out,
+            "\n{} {} {}",
+            paint( format!( "{:>width$}", line_idx + 1, width = gutter ), false ),
+            bar,
+            expand_tabs( text ),
+        );
+
+        for label in &labels {
+            let label_last_line: usize = last_line_of( &line_starts, label.range );
+            if line_of( &line_starts, label.range.0 ) > line_idx || label_last_line < line_idx {
+                continue;
+            }
+            let from: usize = label.range.0.clamp( start, end );
+            let to: usize = label.range.1.clamp( start, end );
+            let offset: usize = display_width( &source[start..from] );
+            let width: usize = display_width( &source[from..to] ).max( 1 );
+            let marker: &str = if label.primary { "^" } else { "-" };
+            let mut underline: String = marker.repeat( width );
+            if label_last_line == line_idx {
+                underline = format!( "{} {}", underline, label.message );
+            }
+            let _ = write!( out, "\n{} {} {}{}", blank, bar, " ".repeat( offset ), paint( underline, label.primary ) );
+        }
+    }
+
+    if let Some( note ) = footer_note {
+        let _ = write!( out, "\n{} {} note: {}", blank, paint( "=".to_string(), false ), note );
+    }
+
+    out
+}
+
+/// The lines to show, with `None` standing in for elided lines
+fn shown_lines( first: usize, last: usize ) -> Vec<Option<usize>> {
+    if last - first < MAX_SNIPPET_LINES {
+        return (first..=last).map(Some).collect();
+    }
+    let keep: usize = MAX_SNIPPET_LINES / 2;
+    (first..first + keep)
+        .map(Some)
+        .chain( std::iter::once( None ) )
+        .chain( (last + 1 - keep..=last).map(Some) )
+        .collect()
+}
+
+/// Clamps a byte range to the source, rounding down to char boundaries
+fn clamp_range( source: &str, range: (u32, u32) ) -> (usize, usize) {
+    let clamp = |offset: u32| {
+        let mut offset: usize = (offset as usize).min( source.len() );
+        while !source.is_char_boundary( offset ) {
+            offset -= 1;
+        }
+        offset
+    };
+    let (start, end) = ( clamp( range.0 ), clamp( range.1 ) );
+    ( start.min( end ), start.max( end ) )
+}
+
+fn line_starts( source: &str ) -> Vec<usize> {
+    std::iter::once( 0 )
+        .chain( source.match_indices( '\n' ).map(|(i, _)| i + 1) )
+        .collect()
+}
+
+/// 0-based line containing the offset
+fn line_of( line_starts: &[usize], offset: usize ) -> usize {
+    line_starts.partition_point(|&start| start <= offset) - 1
+}
+
+/// 0-based last line touched by the range. A range ending at the start of a
+/// line does not touch that line.
+fn last_line_of( line_starts: &[usize], range: (usize, usize) ) -> usize {
+    if range.1 > range.0 {
+        line_of( line_starts, range.1 - 1 )
+    } else {
+        line_of( line_starts, range.0 )
+    }
+}
+
+/// Byte range of the line, without the line ending
+fn line_bounds( source: &str, line_starts: &[usize], line: usize ) -> (usize, usize) {
+    let start: usize = line_starts[line];
+    let end: usize = line_starts.get( line + 1 ).map_or( source.len(), |&next| next - 1 );
+    let end: usize = if end > start && source[..end].ends_with( '\r' ) { end - 1 } else { end };
+    ( start, end )
+}
+
+/// 1-based line and column (in characters) of the offset
+fn line_col( source: &str, line_starts: &[usize], offset: usize ) -> (usize, usize) {
+    let line: usize = line_of( line_starts, offset );
+    let column: usize = source[line_starts[line]..offset].chars().count();
+    ( line + 1, column + 1 )
+}
+
+fn display_width( text: &str ) -> usize {
+    text.chars().map(|c| if c == '\t' { TAB_WIDTH } else { 1 }).sum()
+}
+
+fn expand_tabs( text: &str ) -> String {
+    text.replace( '\t', &" ".repeat( TAB_WIDTH ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "fn main() {\n    let x = {\n        1 + 2\n    };\n}\n";
+
+    fn brace_location() -> ErrorLocation {
+        ErrorLocation {
+            file_path: "src/main.rs".to_string(),
+            selection: (24, 25),
+            nodes: vec![SyntaxNodeInfo {
+                kind: "L_CURLY".to_string(),
+                range: (24, 25),
+                text: "{".to_string(),
+            }],
+            note: Some("the selection only covers this `{`".to_string()),
+            source: Some(SOURCE.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_render_snippet_offending_token() {
+        let rendered = render_snippet("braces_not_applicable", "Extraction not applicable for braces", &brace_location(), SOURCE, false);
+        let expected = "\
+error[braces_not_applicable]: Extraction not applicable for braces
+ --> src/main.rs:2:13
+  |
+2 |     let x = {
+  |             ^ the selection only covers this `{`";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_render_snippet_footer_note() {
+        let location = ErrorLocation::selection("src/main.rs", (39, 24), Some("the start index is after the end index".to_string()));
+        let rendered = render_snippet("invalid_idx_pair", "Invalid pair of start and end indices", &location, SOURCE, false);
+        let expected = "\
+error[invalid_idx_pair]: Invalid pair of start and end indices
+ --> src/main.rs:2:13
+  |
+2 |     let x = {
+  |             ^
+3 |         1 + 2
+  | ^^^^^^^^^^^^^ selected here
+  = note: the start index is after the end index";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_render_snippet_clamps_offsets() {
+        let location = ErrorLocation::selection("src/main.rs", (1000, 1000), None);
+        let rendered = render_snippet("same_idx", "Start and end indices are the same", &location, SOURCE, false);
+        assert!(rendered.contains("src/main.rs:6:1"));
+        assert!(rendered.ends_with("^ empty selection"));
+    }
+
+    #[test]
+    fn test_shown_lines_elides_middle() {
+        let lines = shown_lines(0, 20);
+        assert_eq!(lines.len(), MAX_SNIPPET_LINES + 1);
+        assert_eq!(lines[MAX_SNIPPET_LINES / 2], None);
+        assert_eq!(lines.last(), Some(&Some(20)));
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let error = ParseErrorInfo::new("expected SEMICOLON".to_string(), (24, 24), SOURCE);
+        assert_eq!((error.line, error.column), (2, 13));
+        assert_eq!(error.to_string(), "2:13: expected SEMICOLON");
+        assert!(error.overlaps((20, 24)));
+        assert!(!error.overlaps((30, 40)));
+    }
+}
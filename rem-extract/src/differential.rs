@@ -0,0 +1,369 @@
+//! Differential testing of an extraction: the original and the extracted
+//! program are built and run in a sandbox (see `sandbox`), and their exit
+//! status, stdout and stderr are compared. Optionally their `#[test]`s are run
+//! and compared as well.
+//!
+//! Both programs run in the same copy of the workspace, one after the other,
+//! so they see the same files. The executables cargo builds are run directly,
+//! as `cargo run` and `cargo test` would, so that a program that doesn't stop
+//! is itself killed after the timeout. Output that changes from run to run
+//! (such as the time the tests report) is normalised before the comparison.
+
+use std::{
+    io::{
+        self,
+        Read,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Child,
+        Command,
+        ExitStatus,
+        Output,
+        Stdio,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use log::info;
+
+use regex::Regex;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use serde_json::Value;
+
+use crate::sandbox::Sandbox;
+
+/// How long each program (or test binary) may run before it is killed
+const RUN_TIMEOUT: Duration = Duration::from_secs( 30 );
+
+/// How often a running program is polled for completion
+const POLL_INTERVAL: Duration = Duration::from_millis( 20 );
+
+/// What to run to compare the behaviour of the original and extracted code
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DifferentialMode {
+    /// Don't run anything
+    #[default]
+    Off,
+    /// Run the program (`cargo run`)
+    Run,
+    /// Run the program and its tests (`cargo test`)
+    Tests,
+}
+
+/// The observable result of running a program
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct RunResult {
+    /// Whether the program built. If it didn't, `stderr` has the errors.
+    pub built: bool,
+    /// The exit code, `None` if the program was killed (e.g. by a signal or
+    /// after the timeout)
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A difference between the original and the extracted program
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BehaviourChange {
+    /// Only one of the two programs built
+    Build,
+    ExitStatus,
+    Stdout,
+    Stderr,
+}
+
+/// The results of running the same command on the original and the extracted
+/// program
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct RunComparison {
+    pub original: RunResult,
+    pub extracted: RunResult,
+    /// Empty if the programs behaved the same
+    pub changes: Vec<BehaviourChange>,
+}
+
+impl RunComparison {
+    fn new( original: RunResult, extracted: RunResult ) -> Self {
+        let mut changes: Vec<BehaviourChange> = vec![];
+        if original.built != extracted.built {
+            changes.push( BehaviourChange::Build );
+        } else if original.built {
+            if original.exit_code != extracted.exit_code || original.timed_out != extracted.timed_out {
+                changes.push( BehaviourChange::ExitStatus );
+            }
+            if original.stdout != extracted.stdout {
+                changes.push( BehaviourChange::Stdout );
+            }
+            if original.stderr != extracted.stderr {
+                changes.push( BehaviourChange::Stderr );
+            }
+        }
+        RunComparison {
+            original,
+            extracted,
+            changes,
+        }
+    }
+}
+
+/// The result of comparing the behaviour of the original and extracted code
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BehaviourReport {
+    /// `cargo run`
+    pub run: RunComparison,
+    /// `cargo test`, if enabled
+    pub tests: Option<RunComparison>,
+}
+
+impl BehaviourReport {
+    /// Whether the extracted code behaved the same as the original
+    pub fn is_equivalent( &self ) -> bool {
+        self.run.changes.is_empty()
+            && self.tests.iter().all(|tests| tests.changes.is_empty())
+    }
+
+    /// One line per changed aspect, e.g. `cargo run: stdout differs`
+    pub fn describe_changes( &self ) -> Vec<String> {
+        let comparisons = std::iter::once( ( "cargo run", &self.run ) )
+            .chain( self.tests.iter().map(|tests| ( "cargo test", tests )) );
+        comparisons
+            .flat_map(|( command, comparison )| comparison.changes.iter().map( move |change| {
+                let what: &str = match change {
+                    BehaviourChange::Build => "only one of the programs builds",
+                    BehaviourChange::ExitStatus => "the exit status differs",
+                    BehaviourChange::Stdout => "stdout differs",
+                    BehaviourChange::Stderr => "stderr differs",
+                };
+                format!( "{}: {}", command, what )
+            } ))
+            .collect()
+    }
+}
+
+/// Builds and runs the package with the original and the modified text of
+/// `file_path`, and compares what they do
+pub fn compare_behaviour(
+    cargo_toml: &Path,
+    file_path: &Path,
+    original_code: &str,
+    modified_code: &str,
+    mode: DifferentialMode,
+) -> io::Result<BehaviourReport> {
+    let sandbox: Sandbox = Sandbox::create( cargo_toml )?;
+
+    let run_both = |subcommand: &str| -> io::Result<RunComparison> {
+        sandbox.write_file( file_path, original_code )?;
+        let original: RunResult = build_and_run( &sandbox, cargo_toml, subcommand, RUN_TIMEOUT )?;
+        sandbox.write_file( file_path, modified_code )?;
+        let extracted: RunResult = build_and_run( &sandbox, cargo_toml, subcommand, RUN_TIMEOUT )?;
+        Ok( RunComparison::new( original, extracted ) )
+    };
+
+    let run: RunComparison = run_both( "run" )?;
+    let tests: Option<RunComparison> = match mode {
+        DifferentialMode::Tests => Some( run_both( "test" )? ),
+        DifferentialMode::Run | DifferentialMode::Off => None,
+    };
+
+    let report: BehaviourReport = BehaviourReport { run, tests };
+    info!("Behaviour comparison: {:?}", report.describe_changes());
+    Ok( report )
+}
+
+/// Builds the binaries (`run`) or the test binaries (`test`) of the package,
+/// then runs each of them directly, stopping at the first one that fails as
+/// `cargo test` does. Binaries run in the workspace root like `cargo run`,
+/// and test binaries in the package directory like `cargo test`.
+fn build_and_run( sandbox: &Sandbox, cargo_toml: &Path, subcommand: &str, timeout: Duration ) -> io::Result<RunResult> {
+    let tests: bool = subcommand == "test";
+    let build: Output = sandbox
+        .cargo( "build", cargo_toml )
+        .arg( if tests { "--tests" } else { "--bins" } )
+        .arg( "--message-format=json" )
+        .output()?;
+    let messages: String = String::from_utf8_lossy( &build.stdout ).to_string();
+    if !build.status.success() {
+        return Ok( RunResult {
+            built: false,
+            exit_code: build.status.code(),
+            timed_out: false,
+            stdout: String::new(),
+            stderr: rendered_errors( &messages, &String::from_utf8_lossy( &build.stderr ) ),
+        } );
+    }
+
+    let working_dir: PathBuf = if tests {
+        sandbox.path_of( cargo_toml ).parent().map_or( sandbox.root().to_path_buf(), Path::to_path_buf )
+    } else {
+        sandbox.root().to_path_buf()
+    };
+    let mut result: RunResult = RunResult {
+        built: true,
+        exit_code: Some( 0 ),
+        timed_out: false,
+        stdout: String::new(),
+        stderr: String::new(),
+    };
+    for executable in executables( &messages, tests ) {
+        let mut command: Command = Command::new( &executable );
+        command.current_dir( &working_dir );
+        let ( status, stdout, stderr ) = run_with_timeout( command, timeout )?;
+        result.stdout.push_str( &normalise_output( &stdout ) );
+        result.stderr.push_str( &normalise_output( &stderr ) );
+        result.exit_code = status.and_then(|status| status.code());
+        result.timed_out = status.is_none();
+        if result.exit_code != Some( 0 ) {
+            break;
+        }
+    }
+    Ok( result )
+}
+
+/// The executables in cargo's JSON messages: the binaries, or the test
+/// binaries if `tests` is set. Sorted, so that both programs run them in the
+/// same order.
+fn executables( messages: &str, tests: bool ) -> Vec<PathBuf> {
+    let mut executables: Vec<PathBuf> = messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>( line ).ok())
+        .filter(|message| message["reason"] == "compiler-artifact")
+        .filter(|message| message["profile"]["test"].as_bool() == Some( tests ))
+        .filter(|message| tests || message["target"]["kind"].as_array().is_some_and(|kinds| kinds.iter().any(|kind| kind == "bin")))
+        .filter_map(|message| message["executable"].as_str().map( PathBuf::from ))
+        .collect();
+    executables.sort();
+    executables.dedup();
+    executables
+}
+
+/// The errors rustc reported in cargo's JSON messages, as rustc prints them,
+/// or cargo's stderr if there are none (e.g. the manifest is invalid)
+fn rendered_errors( messages: &str, stderr: &str ) -> String {
+    let rendered: String = messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>( line ).ok())
+        .filter(|message| message["reason"] == "compiler-message")
+        .filter_map(|message| message["message"]["rendered"].as_str().map( str::to_string ))
+        .collect();
+    if rendered.is_empty() { stderr.to_string() } else { rendered }
+}
+
+/// Runs the command, killing it after the timeout. Returns `None` as the
+/// status if it was killed.
+fn run_with_timeout( mut command: Command, timeout: Duration ) -> io::Result<(Option<ExitStatus>, String, String)> {
+    let mut child: Child = command
+        .stdin( Stdio::null() )
+        .stdout( Stdio::piped() )
+        .stderr( Stdio::piped() )
+        .spawn()?;
+
+    // Read the pipes on their own threads so that a chatty program can't
+    // block on a full pipe
+    let read_pipe = |pipe: Option<Box<dyn Read + Send>>| thread::spawn( move || {
+        let mut bytes: Vec<u8> = vec![];
+        if let Some( mut pipe ) = pipe {
+            let _ = pipe.read_to_end( &mut bytes );
+        }
+        String::from_utf8_lossy( &bytes ).to_string()
+    });
+    let stdout = read_pipe( child.stdout.take().map(|p| Box::new( p ) as Box<dyn Read + Send>) );
+    let stderr = read_pipe( child.stderr.take().map(|p| Box::new( p ) as Box<dyn Read + Send>) );
+
+    let start: Instant = Instant::now();
+    let status: Option<ExitStatus> = loop {
+        if let Some( status ) = child.try_wait()? {
+            break Some( status );
+        }
+        if start.elapsed() > timeout {
+            child.kill()?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep( POLL_INTERVAL );
+    };
+
+    Ok( (
+        status,
+        stdout.join().unwrap_or_default(),
+        stderr.join().unwrap_or_default(),
+    ) )
+}
+
+/// Replaces the parts of the output that change between runs
+fn normalise_output( output: &str ) -> String {
+    let test_time: Regex = Regex::new( r"finished in \d+(\.\d+)?s" ).unwrap();
+    test_time.replace_all( output, "finished in <time>" ).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction_utils::test_project::TestProject;
+
+    fn result(exit_code: i32, stdout: &str) -> RunResult {
+        RunResult {
+            built: true,
+            exit_code: Some(exit_code),
+            timed_out: false,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_changes_are_detected() {
+        let same = RunComparison::new(result(0, "1\n"), result(0, "1\n"));
+        assert!(same.changes.is_empty());
+
+        let changed = RunComparison::new(result(0, "1\n"), result(101, "2\n"));
+        assert_eq!(changed.changes, vec![BehaviourChange::ExitStatus, BehaviourChange::Stdout]);
+
+        let not_built = RunResult { built: false, ..result(101, "") };
+        let build = RunComparison::new(result(0, "1\n"), not_built);
+        assert_eq!(build.changes, vec![BehaviourChange::Build]);
+
+        let report = BehaviourReport { run: same, tests: Some(changed) };
+        assert!(!report.is_equivalent());
+        assert_eq!(report.describe_changes(), vec![
+            "cargo test: the exit status differs".to_string(),
+            "cargo test: stdout differs".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_program_that_never_stops_is_killed() {
+        let project = TestProject::new("loops_forever", &[("src/main.rs", "fn main() {\n    println!(\"started\");\n    loop {}\n}\n")]);
+        let cargo_toml = project.cargo_toml();
+        let sandbox = Sandbox::create(&cargo_toml).unwrap();
+
+        let result = build_and_run(&sandbox, &cargo_toml, "run", Duration::from_millis(500)).unwrap();
+        assert!(result.built, "{}", result.stderr);
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.stdout, "started\n");
+    }
+
+    #[test]
+    fn test_normalise_output() {
+        assert_eq!(
+            normalise_output("test result: ok. 2 passed; 0 failed; finished in 0.01s\n"),
+            "test result: ok. 2 passed; 0 failed; finished in <time>\n",
+        );
+    }
+}
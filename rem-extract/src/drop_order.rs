@@ -0,0 +1,404 @@
+//! Detection of values whose drop point moves because of the extraction.
+//!
+//! The call to the new function takes the place of the selection, so the
+//! selection is still evaluated at the same point. What can change is when
+//! values are dropped:
+//!
+//! - a local declared in the selection and not used after it was dropped at
+//!   the end of its block in the parent, and is now dropped at the end of the
+//!   new function;
+//! - a local passed to the new function by value is dropped at the end of the
+//!   new function, unless the selection already moved it elsewhere;
+//! - a temporary in a selected expression that is part of a larger expression
+//!   lived until the end of the enclosing statement, and now only lives until
+//!   the end of the new function.
+//!
+//! Only values of types with a significant `Drop` impl are reported, e.g. a
+//! `MutexGuard`, a `File` or a user type implementing `Drop`. Standard types
+//! whose drop only frees memory, such as `Vec` or `String`, are ignored, but
+//! not what they contain: a `Vec<File>` is reported.
+//!
+//! Changes to the evaluation order of the selection are not checked.
+
+use std::{
+    collections::HashSet,
+    fmt,
+};
+
+use ra_ap_hir::{
+    Adt,
+    HirDisplay,
+    Local,
+    Module,
+    PathResolution,
+    Semantics,
+    Trait,
+    Type,
+};
+
+use ra_ap_ide::{
+    AnalysisHost,
+    RootDatabase,
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_ide_db::{
+    famous_defs::FamousDefs,
+    EditionedFileId,
+};
+
+use ra_ap_parser::Edition;
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    NodeOrToken,
+    SourceFile,
+    SyntaxNode,
+};
+
+use ra_ap_vfs::FileId;
+
+/// Standard types that implement `Drop` only to free memory, by the path they
+/// are defined at. The same idea as rustc's `#[rustc_insignificant_dtor]`.
+const INSIGNIFICANT_DROP_TYPES: [&str; 12] = [
+    "alloc::vec::Vec",
+    "alloc::collections::vec_deque::VecDeque",
+    "alloc::string::String",
+    "alloc::boxed::Box",
+    "alloc::rc::Rc",
+    "alloc::sync::Arc",
+    "std::collections::hash::map::HashMap",
+    "std::collections::hash::set::HashSet",
+    "alloc::collections::btree::map::BTreeMap",
+    "alloc::collections::btree::set::BTreeSet",
+    "alloc::collections::binary_heap::BinaryHeap",
+    "alloc::collections::linked_list::LinkedList",
+];
+
+/// How deep into the fields of a type to look for a `Drop` impl
+const MAX_FIELD_DEPTH: usize = 3;
+
+/// Why the drop point of a value moves
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DropChangeKind {
+    /// A local declared in the selection and not used after it
+    DeclaredInSelection,
+    /// A local passed to the new function by value
+    MovedIntoFunction,
+    /// A temporary in a selected sub-expression
+    Temporary,
+}
+
+/// A value with a significant `Drop` impl whose drop point moves
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DropOrderChange {
+    pub kind: DropChangeKind,
+    /// The name of the local, or the text of the temporary's expression
+    pub name: String,
+    pub ty: String,
+    /// Byte range of the declaration, parameter use or temporary in the
+    /// original file
+    pub range: (u32, u32),
+}
+
+impl fmt::Display for DropOrderChange {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self.kind {
+            DropChangeKind::DeclaredInSelection => write!(
+                f, "`{}` ({}) is now dropped at the end of the new function instead of the end of its block",
+                self.name, self.ty,
+            ),
+            DropChangeKind::MovedIntoFunction => write!(
+                f, "`{}` ({}) is passed by value and may now be dropped at the end of the new function instead of the end of its block",
+                self.name, self.ty,
+            ),
+            DropChangeKind::Temporary => write!(
+                f, "the temporary `{}` ({}) is now dropped at the end of the new function instead of the end of the enclosing statement",
+                self.name, self.ty,
+            ),
+        }
+    }
+}
+
+/// Finds the values in the selection of the original file whose drop point
+/// moves. `new_fn` is the new function in the output, whose parameters tell
+/// which locals are passed by value.
+pub(crate) fn drop_order_changes(
+    host: &AnalysisHost,
+    file_id: FileId,
+    range: (u32, u32),
+    new_fn: Option<&ast::Fn>,
+) -> Vec<DropOrderChange> {
+    let sema: Semantics<'_, RootDatabase> = Semantics::new( host.raw_database() );
+    let editioned_file_id: EditionedFileId = sema
+        .attach_first_edition( file_id )
+        .unwrap_or_else(|| EditionedFileId::current_edition( file_id ));
+    let source_file: SourceFile = sema.parse( editioned_file_id );
+    let selection: TextRange = TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) );
+    if selection.end() > source_file.syntax().text_range().end() {
+        return vec![];
+    }
+    let covering: SyntaxNode = match source_file.syntax().covering_element( selection ) {
+        NodeOrToken::Node( node ) => node,
+        NodeOrToken::Token( token ) => match token.parent() {
+            Some( node ) => node,
+            None => return vec![],
+        },
+    };
+    let Some( drop_trait ) = sema
+        .scope( &covering )
+        .and_then(|scope| FamousDefs( &sema, scope.krate() ).core_ops_Drop())
+    else {
+        return vec![];
+    };
+
+    let analysis: DropAnalysis<'_, '_> = DropAnalysis {
+        sema: &sema,
+        drop_trait,
+        edition: editioned_file_id.edition(),
+        selection,
+    };
+    let mut changes: Vec<DropOrderChange> = vec![];
+    changes.extend( analysis.declared_in_selection( &covering ) );
+    if let Some( new_fn ) = new_fn {
+        changes.extend( analysis.moved_into_function( &covering, new_fn ) );
+    }
+    changes.extend( analysis.temporaries( &covering ) );
+    changes
+}
+
+struct DropAnalysis<'a, 'db> {
+    sema: &'a Semantics<'db, RootDatabase>,
+    drop_trait: Trait,
+    edition: Edition,
+    selection: TextRange,
+}
+
+impl DropAnalysis<'_, '_> {
+    /// Locals bound by a `let` in the selection whose block continues after
+    /// the selection, and which are not used after it (otherwise the new
+    /// function returns them)
+    fn declared_in_selection( &self, covering: &SyntaxNode ) -> Vec<DropOrderChange> {
+        covering
+            .descendants()
+            .filter_map( ast::IdentPat::cast )
+            .filter(|pat| self.selection.contains_range( pat.syntax().text_range() ))
+            .filter_map(|pat| {
+                let let_stmt: ast::LetStmt = pat.syntax().parent().and_then( ast::LetStmt::cast )?;
+                let block: ast::StmtList = let_stmt.syntax().ancestors().find_map( ast::StmtList::cast )?;
+                if self.selection.contains_range( block.syntax().text_range() ) {
+                    return None;
+                }
+                let local: Local = self.sema.to_def( &pat )?;
+                if self.used_after_selection( block.syntax(), local ) {
+                    return None;
+                }
+                self.change( DropChangeKind::DeclaredInSelection, pat.syntax(), local.ty( self.sema.db ) )
+            })
+            .collect()
+    }
+
+    /// Locals declared outside the selection that the new function takes by
+    /// value
+    fn moved_into_function( &self, covering: &SyntaxNode, new_fn: &ast::Fn ) -> Vec<DropOrderChange> {
+        let by_value: HashSet<String> = new_fn
+            .param_list()
+            .into_iter()
+            .flat_map(|params| params.params())
+            .filter(|param| !matches!( param.ty(), Some( ast::Type::RefType( _ ) ) ))
+            .filter_map(|param| match param.pat()? {
+                ast::Pat::IdentPat( pat ) => Some( pat.name()?.text().to_string() ),
+                _ => None,
+            })
+            .collect();
+
+        let mut seen: HashSet<Local> = HashSet::new();
+        covering
+            .descendants()
+            .filter_map( ast::PathExpr::cast )
+            .filter(|expr| self.selection.contains_range( expr.syntax().text_range() ))
+            .filter_map(|expr| {
+                let name: String = expr.path()?.as_single_name_ref()?.text().to_string();
+                if !by_value.contains( &name ) {
+                    return None;
+                }
+                let PathResolution::Local( local ) = self.sema.resolve_path( &expr.path()? )? else {
+                    return None;
+                };
+                if !seen.insert( local ) {
+                    return None;
+                }
+                self.change( DropChangeKind::MovedIntoFunction, expr.syntax(), local.ty( self.sema.db ) )
+            })
+            .collect()
+    }
+
+    /// Temporaries in the selection, if the selection is an expression that
+    /// is part of a larger expression
+    fn temporaries( &self, covering: &SyntaxNode ) -> Vec<DropOrderChange> {
+        let Some( selected ) = covering
+            .ancestors()
+            .filter_map( ast::Expr::cast )
+            .find(|expr| expr.syntax().text_range() == self.selection)
+        else {
+            return vec![];
+        };
+        if !selected.syntax().parent().is_some_and(|parent| ast::Expr::can_cast( parent.kind() )) {
+            return vec![];
+        }
+
+        selected
+            .syntax()
+            .descendants()
+            .filter(|node| node != selected.syntax())
+            .filter(|node| ast::CallExpr::can_cast( node.kind() ) || ast::MethodCallExpr::can_cast( node.kind() ))
+            .filter(|node| node.parent().is_some_and(|parent| is_temporary_use( &parent, node )))
+            .filter_map(|node| {
+                let expr: ast::Expr = ast::Expr::cast( node.clone() )?;
+                let ty: Type = self.sema.type_of_expr( &expr )?.original;
+                self.change( DropChangeKind::Temporary, &node, ty )
+            })
+            .collect()
+    }
+
+    fn used_after_selection( &self, block: &SyntaxNode, local: Local ) -> bool {
+        block
+            .descendants()
+            .filter_map( ast::PathExpr::cast )
+            .filter(|expr| expr.syntax().text_range().start() >= self.selection.end())
+            .filter_map(|expr| self.sema.resolve_path( &expr.path()? ))
+            .any(|resolution| matches!( resolution, PathResolution::Local( used ) if used == local ))
+    }
+
+    fn change( &self, kind: DropChangeKind, node: &SyntaxNode, ty: Type ) -> Option<DropOrderChange> {
+        if !self.has_significant_drop( &ty, MAX_FIELD_DEPTH ) {
+            return None;
+        }
+        Some( DropOrderChange {
+            kind,
+            name: node.text().to_string(),
+            ty: ty.display( self.sema.db, self.edition ).to_string(),
+            range: (
+                node.text_range().start().into(),
+                node.text_range().end().into(),
+            ),
+        } )
+    }
+
+    /// Whether the type or one of its fields implements `Drop`. The
+    /// `INSIGNIFICANT_DROP_TYPES` only count for their type arguments.
+    fn has_significant_drop( &self, ty: &Type, depth: usize ) -> bool {
+        let db: &RootDatabase = self.sema.db;
+        if ty.is_reference() {
+            return false;
+        }
+        if ty.as_adt().is_some_and(|adt| INSIGNIFICANT_DROP_TYPES.contains( &adt_path( db, adt ).as_str() )) {
+            // The arguments are smaller types, so this terminates without
+            // using up the depth
+            return ty
+                .type_arguments()
+                .any(|argument| self.has_significant_drop( &argument, depth ));
+        }
+        if ty.impls_trait( db, self.drop_trait, &[] ) {
+            return true;
+        }
+        depth > 0 && ty
+            .fields( db )
+            .into_iter()
+            .map(|( _, field_ty )| field_ty)
+            .chain( ty.tuple_fields( db ) )
+            .any(|field_ty| self.has_significant_drop( &field_ty, depth - 1 ))
+    }
+}
+
+/// The path an ADT is defined at, starting with its crate, e.g.
+/// `alloc::vec::Vec`
+fn adt_path( db: &RootDatabase, adt: Adt ) -> String {
+    let module: Module = adt.module( db );
+    let mut segments: Vec<String> = module
+        .path_to_root( db )
+        .into_iter()
+        .rev()
+        .filter_map(|module| module.name( db ))
+        .map(|name| name.as_str().to_string())
+        .collect();
+    let krate: Option<String> = module.krate().display_name( db ).map(|name| name.to_string());
+    segments.insert( 0, krate.unwrap_or_default() );
+    segments.push( adt.name( db ).as_str().to_string() );
+    segments.join( "::" )
+}
+
+/// Whether the value of `child` is only used by `parent` as a temporary, i.e.
+/// as the receiver of a method call, a field access, an index or a reference
+fn is_temporary_use( parent: &SyntaxNode, child: &SyntaxNode ) -> bool {
+    if let Some( call ) = ast::MethodCallExpr::cast( parent.clone() ) {
+        return call.receiver().is_some_and(|receiver| receiver.syntax() == child);
+    }
+    ast::FieldExpr::can_cast( parent.kind() )
+        || ast::IndexExpr::cast( parent.clone() )
+            .and_then(|index| index.base())
+            .is_some_and(|base| base.syntax() == child)
+        || ast::RefExpr::can_cast( parent.kind() )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction_utils::test_project::TestProject;
+
+    #[test]
+    fn test_is_temporary_use() {
+        let file = SourceFile::parse("fn f() { g(m.lock().unwrap().len(), h()); }", Edition::CURRENT).tree();
+        let call = |text: &str| file.syntax().descendants()
+            .find(|node| node.text() == text && ast::Expr::can_cast(node.kind()))
+            .unwrap();
+
+        let lock = call("m.lock()");
+        assert!(is_temporary_use(&lock.parent().unwrap(), &lock));
+        let h = call("h()");
+        assert!(!is_temporary_use(&h.parent().unwrap(), &h));
+    }
+
+    const MAIN_RS: &str = r#"use std::{fs::File, sync::Mutex};
+
+fn main() {
+    let m = Mutex::new(1);
+    let f = File::open("Cargo.toml").unwrap();
+    let guard = m.lock().unwrap();
+    let files = vec![File::open("Cargo.toml").unwrap()];
+    let numbers = vec![1, 2];
+    let size = f.metadata().unwrap().len();
+    let n = *guard as u64 + files.len() as u64 + numbers.len() as u64 + size;
+    println!("{}", n);
+}
+"#;
+
+    const NEW_FN: &str = "fn fun_name(m: &Mutex<i32>, f: File) -> u64 { 0 }";
+
+    #[test]
+    fn test_drop_order_changes() {
+        let project = TestProject::new("drop_order_test", &[("src/main.rs", MAIN_RS)]);
+        let (host, _vfs, file_id) = project.load("src/main.rs");
+
+        let start = MAIN_RS.find("let guard").unwrap() as u32;
+        let end = MAIN_RS.find("    println").unwrap() as u32 - 1;
+        let new_fn = SourceFile::parse(NEW_FN, Edition::CURRENT).tree().syntax().descendants().find_map(ast::Fn::cast).unwrap();
+        let changes = drop_order_changes(&host, file_id, (start, end), Some(&new_fn));
+
+        let found: Vec<(DropChangeKind, &str)> = changes.iter().map(|c| (c.kind, c.name.as_str())).collect();
+        assert_eq!(found, vec![
+            (DropChangeKind::DeclaredInSelection, "guard"),
+            (DropChangeKind::DeclaredInSelection, "files"),
+            (DropChangeKind::MovedIntoFunction, "f"),
+        ]);
+        assert!(changes[0].ty.contains("MutexGuard"), "{}", changes[0].ty);
+        assert!(changes[1].ty.contains("Vec<File"), "{}", changes[1].ty);
+        let (from, to) = changes[2].range;
+        assert_eq!(&MAIN_RS[from as usize..to as usize], "f");
+    }
+}
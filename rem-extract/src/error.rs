@@ -1,37 +1,299 @@
+use std::any::Any;
+use std::error::Error;
 use std::fmt;
+use std::fs;
 use std::io;
 use syn::Error as SynError;
 use ra_ap_ide_assists::Assist;
 
+use serde::Serialize;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use crate::{
+    diagnostic::{
+        render_header,
+        render_snippet,
+        ErrorLocation,
+        ParseErrorInfo,
+    },
+    verify::NewDiagnostic,
+};
+
+/// Errors returned by rust-analyzer's project loading (which uses `anyhow`)
+pub type BoxedError = Box<dyn Error + Send + Sync + 'static>;
+
+/// Broad classes of `ExtractionError`, each with its own exit code in the CLI
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// The arguments are wrong (e.g. an invalid range or function name)
+    BadInput,
+    /// The project containing the file could not be loaded
+    ProjectLoad,
+    /// The selection cannot be extracted into a function
+    NotApplicable,
+    /// Something went wrong inside rem-extract or rust-analyzer
+    Internal,
+}
+
+impl ErrorClass {
+    /// The exit code the CLI uses for errors of this class. Exit code 1 is
+    /// used for internal errors, including panics, which `main` catches, and
+    /// 5 for failed tests in `rem-extract test` and failed requests in
+    /// `rem-extract batch`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorClass::Internal => 1,
+            ErrorClass::BadInput => 2,
+            ErrorClass::ProjectLoad => 3,
+            ErrorClass::NotApplicable => 4,
+        }
+    }
+}
+
+/// The message of a panic caught with `catch_unwind`
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
 #[derive(Debug)]
 pub enum ExtractionError {
+    /// The input file (or the file of batch requests) could not be read
     Io(io::Error),
+    /// Any other I/O error, e.g. when writing the output, running cargo or
+    /// talking to a client
+    InternalIo(io::Error),
     Parse(SynError),
     InvalidManifest,
-    InvalidStartIdx,
-    InvalidEndIdx,
-    SameIdx,
-    InvalidIdxPair,
+    InvalidStartIdx(ErrorLocation),
+    InvalidEndIdx(ErrorLocation),
+    SameIdx(ErrorLocation),
+    InvalidIdxPair(ErrorLocation),
     NoExtractFunction(Vec<Assist>),
-    CommentNotApplicable,
-    BracesNotApplicable,
+    CommentNotApplicable(ErrorLocation),
+    BracesNotApplicable(ErrorLocation),
     ParentMethodNotFound,
+    Sysroot(String),
+    MinimalLoad(String),
+    InvalidFunctionName(String),
+    InvalidPath(String),
+    ManifestLoad(BoxedError),
+    WorkspaceLoad(BoxedError),
+    FileNotInWorkspace(String),
+    AnalysisCancelled,
+    NoSourceChange,
+    /// The modified file has error diagnostics the original file didn't have
+    VerificationFailed(Vec<NewDiagnostic>),
+    /// `cargo check` could not be run on a copy of the project
+    BuildCheck(io::Error),
+    /// The original or the extracted program could not be run
+    Differential(io::Error),
+    /// The input file has syntax errors (unless they are allowed)
+    SyntaxErrors(Vec<ParseErrorInfo>),
+    /// The function to inline is not defined in the file
+    FunctionNotFound(String),
+    /// rust-analyzer doesn't offer to inline the function or call
+    NoInlineAssist(String),
+}
+
+impl ExtractionError {
+    /// A stable, machine-readable code for the error. These codes will not
+    /// change between releases, so tools can match on them instead of on the
+    /// `Display` message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ExtractionError::Io(_) => "io",
+            ExtractionError::InternalIo(_) => "internal_io",
+            ExtractionError::Parse(_) => "parse",
+            ExtractionError::InvalidManifest => "invalid_manifest",
+            ExtractionError::InvalidStartIdx(_) => "invalid_start_idx",
+            ExtractionError::InvalidEndIdx(_) => "invalid_end_idx",
+            ExtractionError::SameIdx(_) => "same_idx",
+            ExtractionError::InvalidIdxPair(_) => "invalid_idx_pair",
+            ExtractionError::NoExtractFunction(_) => "no_extract_function",
+            ExtractionError::CommentNotApplicable(_) => "comment_not_applicable",
+            ExtractionError::BracesNotApplicable(_) => "braces_not_applicable",
+            ExtractionError::ParentMethodNotFound => "parent_method_not_found",
+            ExtractionError::Sysroot(_) => "sysroot",
+            ExtractionError::MinimalLoad(_) => "minimal_load",
+            ExtractionError::InvalidFunctionName(_) => "invalid_function_name",
+            ExtractionError::InvalidPath(_) => "invalid_path",
+            ExtractionError::ManifestLoad(_) => "manifest_load",
+            ExtractionError::WorkspaceLoad(_) => "workspace_load",
+            ExtractionError::FileNotInWorkspace(_) => "file_not_in_workspace",
+            ExtractionError::AnalysisCancelled => "analysis_cancelled",
+            ExtractionError::NoSourceChange => "no_source_change",
+            ExtractionError::VerificationFailed(_) => "verification_failed",
+            ExtractionError::BuildCheck(_) => "build_check",
+            ExtractionError::Differential(_) => "differential",
+            ExtractionError::SyntaxErrors(_) => "syntax_errors",
+            ExtractionError::FunctionNotFound(_) => "function_not_found",
+            ExtractionError::NoInlineAssist(_) => "no_inline_assist",
+        }
+    }
+
+    /// The class of the error, which determines the CLI's exit code
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ExtractionError::Io(_)
+            | ExtractionError::InvalidStartIdx(_)
+            | ExtractionError::InvalidEndIdx(_)
+            | ExtractionError::SameIdx(_)
+            | ExtractionError::InvalidIdxPair(_)
+            | ExtractionError::InvalidFunctionName(_)
+            | ExtractionError::InvalidPath(_)
+            | ExtractionError::SyntaxErrors(_)
+            | ExtractionError::FunctionNotFound(_) => ErrorClass::BadInput,
+            ExtractionError::InvalidManifest
+            | ExtractionError::Sysroot(_)
+            | ExtractionError::MinimalLoad(_)
+            | ExtractionError::ManifestLoad(_)
+            | ExtractionError::WorkspaceLoad(_)
+            | ExtractionError::FileNotInWorkspace(_) => ErrorClass::ProjectLoad,
+            ExtractionError::NoExtractFunction(_)
+            | ExtractionError::CommentNotApplicable(_)
+            | ExtractionError::BracesNotApplicable(_)
+            | ExtractionError::ParentMethodNotFound
+            | ExtractionError::VerificationFailed(_)
+            | ExtractionError::NoInlineAssist(_) => ErrorClass::NotApplicable,
+            ExtractionError::InternalIo(_)
+            | ExtractionError::Parse(_)
+            | ExtractionError::AnalysisCancelled
+            | ExtractionError::NoSourceChange
+            | ExtractionError::BuildCheck(_)
+            | ExtractionError::Differential(_) => ErrorClass::Internal,
+        }
+    }
+
+    /// The file, selection and syntax nodes the error refers to, for errors
+    /// caused by the selection
+    pub fn location(&self) -> Option<&ErrorLocation> {
+        match self {
+            ExtractionError::InvalidStartIdx(location)
+            | ExtractionError::InvalidEndIdx(location)
+            | ExtractionError::SameIdx(location)
+            | ExtractionError::InvalidIdxPair(location)
+            | ExtractionError::CommentNotApplicable(location)
+            | ExtractionError::BracesNotApplicable(location) => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Renders the error for a terminal. Errors with a location are shown as an
+    /// annotated snippet of the text the error was found in (or of the input
+    /// file, if the error was found before reading it), followed by the chain
+    /// of sources.
+    pub fn render(&self, color: bool) -> String {
+        let message: String = self.to_string();
+        let mut rendered: String = match self.location() {
+            Some(location) => match location.source.clone().map_or_else(|| fs::read_to_string(&location.file_path), Ok) {
+                Ok(source) => render_snippet(self.code(), &message, location, &source, color),
+                Err(_) => format!(
+                    "{}\n --> {} (bytes {}..{})",
+                    render_header(self.code(), &message, color),
+                    location.file_path,
+                    location.selection.0,
+                    location.selection.1,
+                ),
+            },
+            None => render_header(self.code(), &message, color),
+        };
+
+        for cause in self.causes() {
+            rendered.push_str(&format!("\n  = caused by: {}", cause));
+        }
+        rendered
+    }
+
+    /// The error as a JSON object, as printed by `--format json`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "status": "error",
+            "error": {
+                "code": self.code(),
+                "class": self.class(),
+                "message": self.to_string(),
+                "location": self.location(),
+                "causes": self.causes(),
+            },
+        })
+    }
+
+    /// The messages of the chain of sources of the error. For a failed
+    /// verification, these are the new diagnostics, and for syntax errors the
+    /// errors with their positions.
+    fn causes(&self) -> Vec<String> {
+        if let ExtractionError::VerificationFailed(diagnostics) = self {
+            return diagnostics.iter().map(|d| d.to_string()).collect();
+        }
+        if let ExtractionError::SyntaxErrors(errors) = self {
+            return errors.iter().map(|e| e.to_string()).collect();
+        }
+        let mut causes: Vec<String> = vec![];
+        let mut source: Option<&(dyn Error + 'static)> = self.source();
+        while let Some(e) = source {
+            causes.push(e.to_string());
+            source = e.source();
+        }
+        causes
+    }
 }
 
 impl fmt::Display for ExtractionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ExtractionError::Io(e) => write!(f, "I/O error: {}", e),
+            ExtractionError::InternalIo(e) => write!(f, "I/O error: {}", e),
             ExtractionError::Parse(e) => write!(f, "Parse error: {}", e),
             ExtractionError::InvalidManifest => write!(f, "Could not find a manifest file for the given path"),
-            ExtractionError::InvalidStartIdx => write!(f, "Invalid start index"),
-            ExtractionError::InvalidEndIdx => write!(f, "Invalid end index"),
-            ExtractionError::SameIdx => write!(f, "Start and end indices are the same"),
-            ExtractionError::InvalidIdxPair => write!(f, "Invalid pair of start and end indices"),
+            ExtractionError::InvalidStartIdx(_) => write!(f, "Invalid start index"),
+            ExtractionError::InvalidEndIdx(_) => write!(f, "Invalid end index"),
+            ExtractionError::SameIdx(_) => write!(f, "Start and end indices are the same"),
+            ExtractionError::InvalidIdxPair(_) => write!(f, "Invalid pair of start and end indices"),
             ExtractionError::NoExtractFunction(assists) => write!(f, "No Extract Function Assist found for the given selection of assists {:?}", assists),
-            ExtractionError::CommentNotApplicable => write!(f, "Extraction not applicable for comment"),
-            ExtractionError::BracesNotApplicable => write!(f, "Extraction not applicable for braces"),
+            ExtractionError::CommentNotApplicable(_) => write!(f, "Extraction not applicable for comment"),
+            ExtractionError::BracesNotApplicable(_) => write!(f, "Extraction not applicable for braces"),
             ExtractionError::ParentMethodNotFound => write!(f, "Parent method not found"),
+            ExtractionError::Sysroot(e) => write!(f, "Sysroot error: {}", e),
+            ExtractionError::MinimalLoad(e) => write!(f, "Could not build a minimal workspace: {}", e),
+            ExtractionError::InvalidFunctionName(e) => write!(f, "Invalid function name: {}", e),
+            ExtractionError::InvalidPath(path) => write!(f, "Invalid path: {}", path),
+            ExtractionError::ManifestLoad(_) => write!(f, "Failed to load the project manifest"),
+            ExtractionError::WorkspaceLoad(_) => write!(f, "Failed to load the workspace"),
+            ExtractionError::FileNotInWorkspace(path) => write!(f, "File is not part of the loaded workspace: {}", path),
+            ExtractionError::AnalysisCancelled => write!(f, "Analysis was cancelled"),
+            ExtractionError::NoSourceChange => write!(f, "The extract function assist did not produce a source change for the file"),
+            ExtractionError::VerificationFailed(diagnostics) => write!(f, "The extracted code has {} new diagnostics", diagnostics.len()),
+            ExtractionError::BuildCheck(_) => write!(f, "Failed to run cargo check on a copy of the project"),
+            ExtractionError::Differential(_) => write!(f, "Failed to run the original and extracted programs"),
+            ExtractionError::SyntaxErrors(errors) => write!(f, "The file has {} syntax errors", errors.len()),
+            ExtractionError::FunctionNotFound(name) => write!(f, "No function named `{}` in the file", name),
+            ExtractionError::NoInlineAssist(reason) => write!(f, "Inlining not applicable: {}", reason),
+        }
+    }
+}
+
+impl Error for ExtractionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExtractionError::Io(e) => Some(e),
+            ExtractionError::InternalIo(e) => Some(e),
+            ExtractionError::BuildCheck(e) => Some(e),
+            ExtractionError::Differential(e) => Some(e),
+            ExtractionError::Parse(e) => Some(e),
+            ExtractionError::ManifestLoad(e) => Some(e.as_ref()),
+            ExtractionError::WorkspaceLoad(e) => Some(e.as_ref()),
+            _ => None,
         }
     }
 }
@@ -46,4 +308,101 @@ impl From<SynError> for ExtractionError {
     fn from(error: SynError) -> Self {
         ExtractionError::Parse(error)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_chains_io_error() {
+        let error = ExtractionError::from(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        let source = error.source().expect("I/O errors should have a source");
+        assert_eq!(source.to_string(), "missing");
+    }
+
+    #[test]
+    fn test_source_chains_boxed_error() {
+        let inner: BoxedError = "cargo metadata failed".into();
+        let error = ExtractionError::WorkspaceLoad(inner);
+        assert_eq!(error.source().map(|e| e.to_string()), Some("cargo metadata failed".to_string()));
+        assert_eq!(error.code(), "workspace_load");
+    }
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(ExtractionError::InvalidManifest.code(), "invalid_manifest");
+        assert_eq!(ExtractionError::AnalysisCancelled.code(), "analysis_cancelled");
+        assert_eq!(ExtractionError::NoSourceChange.code(), "no_source_change");
+        assert!(ExtractionError::ParentMethodNotFound.source().is_none());
+    }
+
+    #[test]
+    fn test_classes() {
+        let location = ErrorLocation::selection("src/main.rs", (20, 10), None);
+        assert_eq!(ExtractionError::InvalidIdxPair(location).class(), ErrorClass::BadInput);
+        assert_eq!(ExtractionError::InvalidManifest.class(), ErrorClass::ProjectLoad);
+        assert_eq!(ExtractionError::NoExtractFunction(vec![]).class(), ErrorClass::NotApplicable);
+        assert_eq!(ExtractionError::NoSourceChange.class(), ErrorClass::Internal);
+        // Only failing to read the input is the caller's fault
+        let io_error = || io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(ExtractionError::Io(io_error()).class(), ErrorClass::BadInput);
+        assert_eq!(ExtractionError::InternalIo(io_error()).class(), ErrorClass::Internal);
+        assert_eq!(ExtractionError::NoInlineAssist("no call".to_string()).class(), ErrorClass::NotApplicable);
+        assert_eq!(ErrorClass::NotApplicable.exit_code(), 4);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let location = ErrorLocation::selection("src/main.rs", (10, 10), None);
+        let json = ExtractionError::SameIdx(location).to_json();
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["error"]["code"], "same_idx");
+        assert_eq!(json["error"]["class"], "bad_input");
+        assert_eq!(json["error"]["location"]["selection"], serde_json::json!([10, 10]));
+    }
+
+    #[test]
+    fn test_location() {
+        let location = ErrorLocation::selection("src/main.rs", (10, 10), None);
+        let error = ExtractionError::SameIdx(location.clone());
+        assert_eq!(error.location(), Some(&location));
+        assert_eq!(ExtractionError::InvalidManifest.location(), None);
+    }
+
+    #[test]
+    fn test_verification_failed_lists_diagnostics() {
+        let error = ExtractionError::VerificationFailed(vec![NewDiagnostic {
+            code: "E0425".to_string(),
+            severity: "error".to_string(),
+            message: "no such value in this scope".to_string(),
+            range: (40, 43),
+        }]);
+        assert_eq!(error.class(), ErrorClass::NotApplicable);
+        assert_eq!(error.to_json()["error"]["causes"], serde_json::json!(["error[E0425]: no such value in this scope (bytes 40..43)"]));
+    }
+
+    #[test]
+    fn test_syntax_errors_list_positions() {
+        let error = ExtractionError::SyntaxErrors(vec![ParseErrorInfo::new("expected SEMICOLON".to_string(), (17, 17), "fn main() {\n    1\n}\n")]);
+        assert_eq!(error.class(), ErrorClass::BadInput);
+        assert_eq!(error.code(), "syntax_errors");
+        assert_eq!(error.to_json()["error"]["causes"], serde_json::json!(["2:6: expected SEMICOLON"]));
+    }
+
+    #[test]
+    fn test_render_uses_the_checked_text() {
+        // The file doesn't exist on disk, e.g. an unsaved file of a session
+        let mut location = ErrorLocation::selection("/nonexistent/src/main.rs", (16, 26), None);
+        location.source = Some("fn main() {\n    let x = 1;\n}\n".to_string());
+        let rendered = ExtractionError::SameIdx(location).render(false);
+        assert!(rendered.contains("let x = 1;"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_without_location() {
+        let inner: BoxedError = "cargo metadata failed".into();
+        let rendered = ExtractionError::WorkspaceLoad(inner).render(false);
+        assert_eq!(rendered, "error[workspace_load]: Failed to load the workspace\n  = caused by: cargo metadata failed");
+    }
+}
@@ -2,7 +2,10 @@ use std::{
     fs,
     time::Instant,
     time::Duration,
-    path::PathBuf,
+    path::{
+        Path,
+        PathBuf,
+    },
     process::Command,
 };
 use syn::{
@@ -15,6 +18,11 @@ use log::info;
 use regex::Regex;
 
 use crate::{
+    differential::{
+        compare_behaviour,
+        BehaviourReport,
+        DifferentialMode,
+    },
     extraction::extract_method,
     extraction::ExtractionInput,
     error::ExtractionError,
@@ -147,8 +155,25 @@ fn remove_all_files(dir: &PathBuf) -> () {
     }
 }
 
+/// Runs the original input program and the extracted one (see
+/// `differential`), and returns the status to print for the test
+fn behaviour_status(test_file: &TestFile<'_>, input: &TestInput, extracted: &str, mode: DifferentialMode) -> (bool, String) {
+    let cargo_toml: PathBuf = PathBuf::from("input").join(test_file.input_file).join("Cargo.toml");
+    let comparison: std::io::Result<BehaviourReport> = fs::read_to_string(&input.file_path).and_then(|original| {
+        compare_behaviour(&cargo_toml, Path::new(&input.file_path), &original, extracted, mode)
+    });
+
+    match comparison {
+        Ok(report) if report.is_equivalent() => (true, "SAME".green().to_string()),
+        Ok(report) => (false, format!("CHANGED: {}", report.describe_changes().join(", ")).red().to_string()),
+        Err(e) => (false, format!("Error: {}", e).red().to_string()),
+    }
+}
+
+/// Returns the number of failed tests. With a `DifferentialMode`, a test also
+/// fails if the extracted program does not behave like the original.
 #[allow(dead_code)]
-pub fn test() {
+pub fn test(differential: DifferentialMode) -> i32 {
     // Clear the output directory before running tests
     let output_dir = PathBuf::from("./output");
     remove_all_files(&output_dir);
@@ -217,6 +242,7 @@ pub fn test() {
         let test_name: &str = test_file.input_file.trim_end_matches(".rs");
         let mut extraction_status: String = "FAILED".red().to_string();
         let mut comparison_status: String = "N/A".to_string(); // Default to not applicable
+        let mut behaviour_column: String = String::new(); // Only shown in differential mode
 
         if extraction_result.is_ok() {
             // Unwrap the result to get the output code (as we know that it is
@@ -228,32 +254,45 @@ pub fn test() {
             passed_stage_1 += 1;
 
             // Compare the output file with the expected file's AST
-            match parse_and_compare_ast(&extraction_result, &expected_file_path) {
+            let mut passed: bool = match parse_and_compare_ast(&extraction_result, &expected_file_path) {
                 Ok(is_identical) => {
                     if is_identical {
                         comparison_status = "PASSED".green().to_string();
-                        passed_tests += 1;
                     } else {
                         comparison_status = "FAILED".red().to_string();
-                        failed_tests += 1;
                     }
+                    is_identical
                 }
                 Err(e) => {
                     comparison_status = format!("Error: {}", e).red().to_string();
-                    failed_tests += 1;
+                    false
                 }
+            };
+
+            // Compare the behaviour of the input and output programs
+            if differential != DifferentialMode::Off {
+                let (same_behaviour, status) = behaviour_status(test_file, &input, &extraction_result, differential);
+                behaviour_column = format!(" | {}", status);
+                passed &= same_behaviour;
+            }
+
+            if passed {
+                passed_tests += 1;
+            } else {
+                failed_tests += 1;
             }
         } else if let Err(e) = extraction_result {
             extraction_status = format!("FAILED: {}", e).red().to_string();
             failed_tests += 1;
         }
 
-        println!("Test {} | {} | {}: {} in {}", index + 1, extraction_status, comparison_status, test_name, test_elapsed_time_str);
+        println!("Test {} | {} | {}{}: {} in {}", index + 1, extraction_status, comparison_status, behaviour_column, test_name, test_elapsed_time_str);
         // Strip ANSI color codes before logging
         let clean_extraction_status = strip_ansi_codes(&extraction_status);
         let clean_comparison_status = strip_ansi_codes(&comparison_status);
+        let clean_behaviour_column = strip_ansi_codes(&behaviour_column);
 
-        info!("Test {} | {} | {}: {} in {}", index + 1, clean_extraction_status, clean_comparison_status, test_name, test_elapsed_time_str);
+        info!("Test {} | {} | {}{}: {} in {}", index + 1, clean_extraction_status, clean_comparison_status, clean_behaviour_column, test_name, test_elapsed_time_str);
 
     }
 
@@ -318,10 +357,13 @@ pub fn test() {
         println!("Longest test time: {}", max_time_str);
         info!("Longest test time: {}", max_time_str);
     }
+
+    failed_tests
 }
 
+/// Returns the number of failed tests
 #[allow(dead_code)]
-pub fn test_verbose() {
+pub fn test_verbose() -> i32 {
     // Clear the output directory before running tests
     let output_dir = PathBuf::from("./output");
     remove_all_files(&output_dir);
@@ -561,10 +603,13 @@ pub fn test_verbose() {
         println!("Longest test time: {}", max_time_str);
         info!("Longest test time: {}", max_time_str);
     }
+
+    failed_tests
 }
 
+/// Returns the number of failed tests
 #[allow(dead_code)]
-pub fn test_spammy() {
+pub fn test_spammy() -> i32 {
     // Clear the output directory before running tests
     let output_dir = PathBuf::from("./output");
     remove_all_files(&output_dir);
@@ -804,6 +849,8 @@ pub fn test_spammy() {
         println!("Longest test time: {}", max_time_str);
         info!("Longest test time: {}", max_time_str);
     }
+
+    failed_tests
 }
 
 #[cfg(test)]
@@ -1,37 +1,100 @@
 use std::{
+    collections::HashMap,
     fs,
     io::{
         self,
         ErrorKind
     },
-    path::PathBuf
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+use log::info;
+
 use ra_ap_ide_db::EditionedFileId;
 use ra_ap_project_model::{
     CargoConfig,
     ProjectWorkspace,
     ProjectManifest,
+    WorkspaceBuildScripts,
 };
 
 use ra_ap_ide::{
     Analysis,
     AnalysisHost,
-    TextSize,
+    Edition,
 };
 
 use ra_ap_syntax::{
-    algo, ast::HasName, AstNode, SourceFile
+    ast, AstNode, SourceFile
+};
+
+use rem_extract_fixup::{
+    check_braces,
+    check_comment,
+    check_fn_name,
+    parent_method,
+    trim_range,
 };
 
 use ra_ap_hir::Semantics;
 
 use ra_ap_ide_assists::Assist;
 
-use ra_ap_vfs::AbsPathBuf;
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+    Vfs,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+    Serializer,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
 
 use crate::{
+    cache::{
+        self,
+        apply_build_script_outputs,
+        BuildScriptOutput,
+    },
+    diagnostic::{
+        ErrorLocation,
+        ParseErrorInfo,
+    },
+    drop_order::drop_order_changes,
+    differential::{
+        compare_behaviour,
+        BehaviourReport,
+        DifferentialMode,
+    },
     error::ExtractionError,
+    lifetimes::{
+        analyse_lifetimes,
+        LifetimeReport,
+    },
+    placeholders::{
+        resolve_placeholders,
+        Placeholder,
+        PlaceholderMode,
+        PlaceholderResolution,
+    },
+    round_trip::{
+        round_trip,
+        RoundTripReport,
+    },
     extraction_utils::{
         apply_extract_function,
         convert_to_abs_path_buf,
@@ -43,19 +106,228 @@ use crate::{
         load_project_manifest,
         load_project_workspace,
         load_workspace_data,
+        load_project_json,
+        cargo_to_project_json,
+        prune_project_json,
         run_analysis,
-        check_braces,
-        check_comment,
-        trim_range,
+        check_sysroot,
+        selection_error,
         generate_frange,
+        compute_edits,
+        find_call_site,
+        find_fn,
+        signature_info,
+    },
+    sandbox::{
+        check_build,
+        BuildErrorLocation,
+        BuildReport,
+    },
+    verify::{
+        inactive_code_ranges,
+        new_diagnostics,
+        NewDiagnostic,
+    },
+    warnings::{
+        placeholder_range,
+        signature_range,
+        syntactic_warnings,
+        ExtractionWarning,
+        WarningCode,
     },
 };
 
+/// Controls which parts of the workspace are loaded into the VFS and database
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadStrategy {
+    /// Load the whole workspace and every (transitive) dependency, exactly as
+    /// rust-analyzer would
+    #[default]
+    Full,
+    /// Load only the crate containing the input file, the signatures of its
+    /// direct dependencies and the sysroot. Types coming from transitive
+    /// dependencies may not resolve.
+    Minimal,
+}
+
+/// Options controlling how the project containing the input file is loaded.
+/// The defaults mirror rust-analyzer: the sysroot is discovered through
+/// `rustc --print sysroot` using whichever toolchain is active for the project.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LoadOptions {
+    /// How much of the workspace to load into the analysis database
+    pub load_strategy: LoadStrategy,
+    /// Path to the sysroot. If `None`, the sysroot is discovered.
+    pub sysroot: Option<String>,
+    /// Path to the sysroot library sources. If `None`, this defaults to
+    /// `{sysroot}/lib/rustlib/src/rust/library`.
+    pub sysroot_src: Option<String>,
+    /// The rustup toolchain to use for `cargo metadata` and sysroot discovery,
+    /// overriding any `rust-toolchain.toml` in the project.
+    pub toolchain: Option<String>,
+    /// Whether to use the on-disk cache of the project model (see `cache`)
+    pub use_cache: bool,
+    /// Whether to run the build scripts of the workspace, so that `OUT_DIR`
+    /// and the cfgs they set resolve. This builds the workspace's
+    /// dependencies with `cargo check`, so it is off by default.
+    pub build_scripts: bool,
+}
+
+/// What to do with the diagnostics that the extraction introduces
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyMode {
+    /// Don't run the diagnostics on the modified file
+    #[default]
+    Off,
+    /// Report new diagnostics as warnings
+    Warn,
+    /// Fail the extraction if there are new error diagnostics
+    Deny,
+}
+
+/// Options controlling the extraction itself, as opposed to how the project
+/// is loaded
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ExtractionOptions {
+    /// Whether to check the modified file with rust-analyzer's diagnostics
+    /// (see `verify`)
+    pub verify: VerifyMode,
+    /// Whether to run `cargo check` on a copy of the project containing the
+    /// result (see `sandbox`)
+    pub verify_build: bool,
+    /// Whether to run the original and the extracted program (and their
+    /// tests) and compare what they do (see `differential`)
+    pub differential: DifferentialMode,
+    /// How to resolve the placeholder types (`_`) in the signature (see
+    /// `placeholders`). They are always reported.
+    pub placeholders: PlaceholderMode,
+    /// Whether to extract from files with syntax errors. Otherwise they fail
+    /// with `ExtractionError::SyntaxErrors`.
+    pub allow_errors: bool,
+    /// Whether to inline the new function back and compare the result with
+    /// the original file (see `round_trip`)
+    pub round_trip: bool,
+}
+
+/// The phases of an extraction, in the order they are reported
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractionPhase {
+    /// Finding and parsing the `Cargo.toml` for the input file
+    ManifestDiscovery,
+    /// Running `cargo metadata` (or reading the project from the cache)
+    CargoMetadata,
+    /// Running the build scripts of the workspace for their `OUT_DIR` and
+    /// cfgs, if enabled (a no-op for a `rust-project.json`)
+    BuildScripts,
+    /// Loading the source files into the VFS and building the database
+    VfsLoad,
+    /// Parsing the input file and checking the selection
+    Analysis,
+    /// Asking rust-analyzer for the extract function (or inline) assist
+    AssistComputation,
+    /// Applying the edit, renaming the function and finding the parent method
+    PostProcessing,
+    /// Running the diagnostics on the modified file, if enabled
+    Verification,
+    /// Inlining the new function back and comparing with the original, if
+    /// enabled
+    RoundTrip,
+    /// Running `cargo check` on a copy of the project, if enabled
+    BuildCheck,
+    /// Running the original and the extracted program, if enabled
+    Differential,
+}
+
+impl ExtractionPhase {
+    /// Human readable description of the phase
+    pub fn description(&self) -> &'static str {
+        match self {
+            ExtractionPhase::ManifestDiscovery => "Discovering manifest",
+            ExtractionPhase::CargoMetadata => "Loading cargo metadata",
+            ExtractionPhase::BuildScripts => "Running build scripts",
+            ExtractionPhase::VfsLoad => "Loading source files",
+            ExtractionPhase::Analysis => "Analysing selection",
+            ExtractionPhase::AssistComputation => "Computing assist",
+            ExtractionPhase::PostProcessing => "Applying edits",
+            ExtractionPhase::Verification => "Checking the modified file",
+            ExtractionPhase::RoundTrip => "Inlining the new function back",
+            ExtractionPhase::BuildCheck => "Running cargo check",
+            ExtractionPhase::Differential => "Comparing the behaviour of the programs",
+        }
+    }
+}
+
+/// Events passed to the progress callback of `extract_method_configured`.
+/// If the extraction fails, the phase it failed in is never `Finished`.
 #[derive(Debug, PartialEq, Clone)]
+pub enum ProgressEvent {
+    Started(ExtractionPhase),
+    /// A status message from within a phase (e.g. from `cargo metadata`)
+    Report(ExtractionPhase, String),
+    Finished(ExtractionPhase, Duration),
+}
+
+/// How long a phase of the extraction took
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct PhaseTiming {
+    pub phase: ExtractionPhase,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+/// Reports the start and end of each phase to a progress callback, and
+/// records how long each phase took
+pub(crate) struct PhaseReporter<'a> {
+    progress: &'a dyn Fn(ProgressEvent),
+    current: Option<(ExtractionPhase, Instant)>,
+    pub(crate) timings: Vec<PhaseTiming>,
+}
+
+impl<'a> PhaseReporter<'a> {
+    pub(crate) fn new( progress: &'a dyn Fn(ProgressEvent) ) -> Self {
+        PhaseReporter { progress, current: None, timings: vec![] }
+    }
+
+    /// Finishes the current phase (if any) and starts the next one
+    pub(crate) fn begin( &mut self, phase: ExtractionPhase ) {
+        self.finish();
+        (self.progress)( ProgressEvent::Started( phase ) );
+        self.current = Some( (phase, Instant::now()) );
+    }
+
+    fn report( &self, message: String ) {
+        if let Some( (phase, _) ) = self.current {
+            (self.progress)( ProgressEvent::Report( phase, message ) );
+        }
+    }
+
+    /// Finishes the current phase (if any)
+    pub(crate) fn finish( &mut self ) {
+        if let Some( (phase, start) ) = self.current.take() {
+            let duration: Duration = start.elapsed();
+            (self.progress)( ProgressEvent::Finished( phase, duration ) );
+            self.timings.push( PhaseTiming { phase, duration } );
+        }
+    }
+}
+
+/// Serializes a `Duration` as a number of milliseconds
+pub(crate) fn serialize_millis<S: Serializer>( duration: &Duration, serializer: S ) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64( duration.as_secs_f64() * 1000.0 )
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ExtractionInput {
+    #[serde(alias = "file")]
     pub file_path: String,
+    #[serde(alias = "name")]
     pub new_fn_name: String,
+    #[serde(alias = "start")]
     pub start_idx: u32,
+    #[serde(alias = "end")]
     pub end_idx: u32,
 }
 
@@ -80,7 +352,11 @@ impl ExtractionInput {
         start_idx: u32,
         end_idx: u32,
     ) -> Self { ExtractionInput {
-            file_path: convert_to_abs_path_buf(file_path).unwrap().as_str().to_string(),
+            // If the path can't be resolved, keep it as is and let the
+            // extraction report the error
+            file_path: convert_to_abs_path_buf(file_path)
+                .map(|path| path.as_str().to_string())
+                .unwrap_or_else(|_| file_path.to_string()),
             new_fn_name: new_fn_name.to_string(),
             start_idx,
             end_idx,
@@ -88,12 +364,93 @@ impl ExtractionInput {
     }
 }
 
+/// A replacement of a byte range of the original file
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct SourceEdit {
+    pub range: (u32, u32),
+    pub new_text: String,
+}
+
+/// A parameter of the extracted function
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ParamInfo {
+    pub pattern: String,
+    pub ty: Option<String>,
+}
+
+/// The signature of the extracted function, as generated by rust-analyzer
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct SignatureInfo {
+    /// The full signature, i.e. everything before the body
+    pub text: String,
+    pub self_param: Option<String>,
+    pub params: Vec<ParamInfo>,
+    pub return_type: Option<String>,
+    pub generic_params: Option<String>,
+    pub where_clause: Option<String>,
+    pub is_async: bool,
+    pub is_const: bool,
+    pub is_unsafe: bool,
+}
+
+/// Everything known about a successful extraction. Ranges are byte ranges into
+/// `code`, except for the ranges of the `edits`, which refer to the original
+/// file.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct ExtractionOutput {
+    pub file_path: String,
+    /// The whole modified file
+    pub code: String,
+    /// The line-based edits that turn the original file into `code`
+    pub edits: Vec<SourceEdit>,
+    pub new_fn_name: String,
+    /// Range of the new function, if it could be found in the output
+    pub new_fn_range: Option<(u32, u32)>,
+    /// Range of the call to the new function in the parent method
+    pub call_site_range: Option<(u32, u32)>,
+    pub parent_method: String,
+    pub signature: Option<SignatureInfo>,
+    /// Whether the signature needs explicit lifetimes, if the new function
+    /// could be found
+    pub lifetimes: Option<LifetimeReport>,
+    /// The placeholder types (`_`) in the signature, resolved or not
+    pub placeholders: Vec<Placeholder>,
+    /// Non-fatal problems with the result, for the caller to decide whether
+    /// to accept it
+    pub warnings: Vec<ExtractionWarning>,
+    /// Diagnostics of the modified file that the original file didn't have.
+    /// Only computed if verification is enabled.
+    pub new_diagnostics: Vec<NewDiagnostic>,
+    /// The differences between the original file and the result of inlining
+    /// the new function back. Only computed if the round-trip check is
+    /// enabled.
+    pub round_trip: Option<RoundTripReport>,
+    /// The result of `cargo check` on the modified project. Only computed if
+    /// `verify_build` is enabled.
+    pub build: Option<BuildReport>,
+    /// The behaviour of the original and the extracted program. Only
+    /// computed if differential testing is enabled.
+    pub behaviour: Option<BehaviourReport>,
+    pub timings: Vec<PhaseTiming>,
+    #[serde(rename = "total_ms", serialize_with = "serialize_millis")]
+    pub total_time: Duration,
+}
+
+impl ExtractionOutput {
+    /// The output as a JSON object, as printed by `--format json`
+    pub fn to_json( &self ) -> Value {
+        let mut value: Value = json!( self );
+        value["status"] = json!( "ok" );
+        value
+    }
+}
+
 // ========================================
 // Checks for the validity of the input
 // ========================================
 
 // Check if the file exists and is readable
-fn check_file_exists(file_path: &str) -> Result<(), ExtractionError> {
+pub(crate) fn check_file_exists(file_path: &str) -> Result<(), ExtractionError> {
     if fs::metadata(file_path).is_err() {
         return Err(ExtractionError::Io(io::Error::new(
             ErrorKind::NotFound,
@@ -105,16 +462,29 @@ fn check_file_exists(file_path: &str) -> Result<(), ExtractionError> {
 
 // Check if the idx pair is valid
 fn check_idx(input: &ExtractionInput) -> Result<(), ExtractionError> {
+    let location = |note: String| ErrorLocation::selection(
+        &input.file_path,
+        (input.start_idx, input.end_idx),
+        Some(note),
+    );
     if input.start_idx == input.end_idx {
-        return Err(ExtractionError::SameIdx);
+        return Err(ExtractionError::SameIdx(location(
+            format!("the start and end indices are both {}, so nothing is selected", input.start_idx)
+        )));
     } else if input.start_idx > input.end_idx {
-        return Err(ExtractionError::InvalidIdxPair);
+        return Err(ExtractionError::InvalidIdxPair(location(
+            format!("the start index {} is after the end index {}", input.start_idx, input.end_idx)
+        )));
     }
     if input.start_idx == 0 {
-        return Err(ExtractionError::InvalidStartIdx);
+        return Err(ExtractionError::InvalidStartIdx(location(
+            "the start index must be greater than 0".to_string()
+        )));
     }
     if input.end_idx == 0 {
-        return Err(ExtractionError::InvalidEndIdx);
+        return Err(ExtractionError::InvalidEndIdx(location(
+            "the end index must be greater than 0".to_string()
+        )));
     }
     Ok(())
 }
@@ -135,40 +505,139 @@ fn verify_input(input: &ExtractionInput) -> Result<(), ExtractionError> {
 /// If successful, returns the `String` of the output code, followed by a
 /// `String` of the caller method
 pub fn extract_method(input: ExtractionInput) -> Result<(String, String), ExtractionError> {
+    extract_method_configured( input, &LoadOptions::default(), &ExtractionOptions::default(), &|_| () )
+        .map(|output| (output.code, output.parent_method))
+}
 
-    // Extract the struct information
-    let input_path: &str = &input.file_path;
-    let callee_name: &str = &input.new_fn_name;
-    let start_idx: u32 = input.start_idx;
-    let end_idx: u32 = input.end_idx;
+/// Same as `extract_method`, but loads the project using the given
+/// `LoadOptions` (e.g. an explicit sysroot or toolchain), applies the
+/// `ExtractionOptions` (e.g. to verify the modified file) and reports the
+/// start and end of each `ExtractionPhase` (and any status messages within
+/// it) to `progress`. Returns everything known about the extraction (the
+/// edits, the new function and its call site, timings...).
+pub fn extract_method_configured(
+    input: ExtractionInput,
+    load_options: &LoadOptions,
+    extraction_options: &ExtractionOptions,
+    progress: &dyn Fn(ProgressEvent),
+) -> Result<ExtractionOutput, ExtractionError> {
+    let start_time: Instant = Instant::now();
+    let mut reporter: PhaseReporter<'_> = PhaseReporter::new( progress );
+
+    let input_abs_path: AbsPathBuf = check_input( &input )?;
+
+    reporter.begin( ExtractionPhase::ManifestDiscovery );
+    let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+    let (mut host, vfs) = load_project(
+        &cargo_toml,
+        &input_abs_path,
+        load_options,
+        &mut reporter,
+    )?;
+
+    extract_from_project(
+        &input,
+        &input_abs_path,
+        &mut host,
+        &vfs,
+        extraction_options,
+        &mut reporter,
+        start_time,
+    )
+}
+
+/// Converts the input path to an `AbsPathBuf` and verifies the input data
+pub(crate) fn check_input( input: &ExtractionInput ) -> Result<AbsPathBuf, ExtractionError> {
+    let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( &input.file_path )
+        .map_err(|_| ExtractionError::InvalidPath( input.file_path.to_string() ))?;
 
-    // Convert the input and output path to an `AbsPathBuf`
-    let input_abs_path: AbsPathBuf = convert_to_abs_path_buf(input_path).unwrap();
+    verify_input( input )?;
 
-    // Verify the input data
-    verify_input(&input)?;
+    Ok( input_abs_path )
+}
 
+/// Finds the `Cargo.toml` of the project containing the input file
+pub(crate) fn find_cargo_toml( input_abs_path: &AbsPathBuf ) -> Result<AbsPathBuf, ExtractionError> {
     let manifest_dir: PathBuf = get_manifest_dir(
         &PathBuf::from(input_abs_path.as_str())
     )?;
-    let cargo_toml: AbsPathBuf = get_cargo_toml( &manifest_dir );
-    // println!("Cargo.toml {:?}", cargo_toml);
+    get_cargo_toml( &manifest_dir )
+}
 
-    let project_manifest: ProjectManifest = load_project_manifest( &cargo_toml );
+/// Loads the project with the given `Cargo.toml` into an `AnalysisHost`.
+/// Reports the rest of the `ManifestDiscovery` phase, and the `CargoMetadata`,
+/// `BuildScripts` (if enabled) and `VfsLoad` phases.
+pub(crate) fn load_project(
+    cargo_toml: &AbsPathBuf,
+    input_abs_path: &AbsPathBuf,
+    load_options: &LoadOptions,
+    reporter: &mut PhaseReporter<'_>,
+) -> Result<(AnalysisHost, Vfs), ExtractionError> {
+    let project_manifest: ProjectManifest = load_project_manifest( cargo_toml )?;
     // println!("Project Manifest {:?}", project_manifest);
 
-    let cargo_config: CargoConfig = get_cargo_config( &project_manifest );
+    let cargo_config: CargoConfig = get_cargo_config( &project_manifest, load_options )?;
     // println!("Cargo Config {:?}", cargo_config);
 
-    let workspace: ProjectWorkspace = load_project_workspace( &project_manifest, &cargo_config );
+    let workspace: ProjectWorkspace = project_workspace(
+        &project_manifest,
+        &cargo_config,
+        load_options,
+        input_abs_path,
+        reporter,
+    )?;
     // println!("Project Workspace {:?}", workspace);
 
-    let (db, vfs) = load_workspace_data(workspace, &cargo_config);
+    reporter.begin( ExtractionPhase::VfsLoad );
+    let load_start: Instant = Instant::now();
+    let (db, vfs) = load_workspace_data(workspace, &cargo_config)?;
+    info!(
+        "Loaded workspace ({:?} strategy, {} files) in {:.2?}",
+        load_options.load_strategy,
+        vfs.iter().count(),
+        load_start.elapsed()
+    );
+
+    Ok( (AnalysisHost::with_database( db ), vfs) )
+}
+
+/// The state of an extraction once rust-analyzer has offered the extract
+/// function assist for the selection
+pub(crate) struct PreparedExtraction {
+    pub(crate) assist: Assist,
+    pub(crate) source_file: SourceFile,
+    /// The selection, trimmed of whitespace
+    pub(crate) range: (u32, u32),
+    pub(crate) edition: Edition,
+    pub(crate) file_id: FileId,
+    /// The text of the input file in the analysis database
+    pub(crate) original_code: String,
+    /// Warnings about the selection and the syntax errors in the file, if
+    /// they are allowed
+    pub(crate) warnings: Vec<ExtractionWarning>,
+}
+
+/// Checks the selection and asks rust-analyzer for the extract function
+/// assist. Reports the `Analysis` and `AssistComputation` phases. Files with
+/// syntax errors are refused unless `allow_errors` is set.
+pub(crate) fn prepare_extraction(
+    input: &ExtractionInput,
+    input_abs_path: &AbsPathBuf,
+    host: &AnalysisHost,
+    vfs: &Vfs,
+    allow_errors: bool,
+    reporter: &mut PhaseReporter<'_>,
+) -> Result<PreparedExtraction, ExtractionError> {
+    // Extract the struct information
+    let input_path: &str = &input.file_path;
+    let callee_name: &str = &input.new_fn_name;
+
+    reporter.begin( ExtractionPhase::Analysis );
 
     // Parse the cursor positions into the range
     let range_: (u32, u32) = (
-        start_idx,
-        end_idx,
+        input.start_idx,
+        input.end_idx,
     );
 
     // Before we go too far, lets do few more quick checks now that we have the
@@ -176,60 +645,378 @@ pub fn extract_method(input: ExtractionInput) -> Result<(String, String), Extrac
     // 1. Check if the function to extract is not just a comment
     // 2. Check if the function to extract has matching braces
     // 3. Convert the range to a trimmed range.
-    let sema: Semantics<'_, ra_ap_ide::RootDatabase> = Semantics::new( &db );
-    let frange_: ra_ap_hir::FileRangeWrapper<ra_ap_vfs::FileId> = generate_frange( &input_abs_path, &vfs, range_.clone() );
-    let edition: EditionedFileId = EditionedFileId::current_edition( frange_.file_id );
-    let source_file: SourceFile = sema.parse( edition );
-    let range: (u32, u32) = trim_range( &source_file, &range_ );
-    check_comment( &source_file, &range )?;
-    check_braces( &source_file, &range )?;
-
-    let analysis_host: AnalysisHost = AnalysisHost::with_database( db );
-    let analysis: Analysis = run_analysis( analysis_host );
-
-    let assists: Vec<Assist> = get_assists( &analysis, &vfs, &input_abs_path, range );
+    let sema: Semantics<'_, ra_ap_ide::RootDatabase> = Semantics::new( host.raw_database() );
+    let frange_: ra_ap_hir::FileRangeWrapper<ra_ap_vfs::FileId> = generate_frange( input_abs_path, vfs, range_ )?;
+    // Parse with the edition of the crate that owns the file, so that e.g.
+    // `async` is an identifier in a 2015 crate
+    let editioned_file_id: EditionedFileId = sema
+        .attach_first_edition( frange_.file_id )
+        .unwrap_or_else(|| EditionedFileId::current_edition( frange_.file_id ));
+    let edition: Edition = editioned_file_id.edition();
+    info!("Edition: {}", edition);
+    check_fn_name( callee_name, edition ).map_err(|e| selection_error( e, input_path, &range_ ))?;
+    let source_file: SourceFile = sema.parse( editioned_file_id );
+    let range: (u32, u32) = trim_range( &source_file, range_ );
+
+    let analysis: Analysis = run_analysis( host );
+    // Use the text in the database rather than on disk, which may be out of
+    // date if a client has sent us its unsaved changes
+    let original_code: String = analysis
+        .file_text( frange_.file_id )
+        .map_err(|_| ExtractionError::AnalysisCancelled)?
+        .to_string();
+
+    // The assist gives unpredictable results on files that don't parse
+    let parse_errors: Vec<ParseErrorInfo> = SourceFile::parse( &original_code, edition )
+        .errors()
+        .iter()
+        .map(|e| ParseErrorInfo::new(
+            e.to_string(),
+            ( e.range().start().into(), e.range().end().into() ),
+            &original_code,
+        ))
+        .collect();
+    if !parse_errors.is_empty() && !allow_errors {
+        return Err( ExtractionError::SyntaxErrors( parse_errors ) );
+    }
+    let mut warnings: Vec<ExtractionWarning> = parse_errors
+        .iter()
+        .filter(|e| e.overlaps( range ))
+        .map(|e| ExtractionWarning::in_original(
+            WarningCode::SyntaxError,
+            format!( "the selection overlaps a syntax error at {}", e ),
+            Some( e.range ),
+        ))
+        .collect();
+    if warnings.len() < parse_errors.len() {
+        warnings.push( ExtractionWarning::in_original(
+            WarningCode::SyntaxError,
+            format!( "the file has {} syntax errors outside the selection", parse_errors.len() - warnings.len() ),
+            None,
+        ) );
+    }
+    if range != range_ {
+        warnings.push( ExtractionWarning::in_original(
+            WarningCode::SelectionTrimmed,
+            format!( "the selection {}..{} was trimmed to {}..{}", range_.0, range_.1, range.0, range.1 ),
+            Some( range ),
+        ) );
+    }
+
+    check_comment( &source_file, range ).map_err(|e| selection_error( e, input_path, &range ))?;
+    check_braces( &source_file, range ).map_err(|e| selection_error( e, input_path, &range ))?;
+
+    reporter.begin( ExtractionPhase::AssistComputation );
+    let assists: Vec<Assist> = get_assists( &analysis, vfs, input_abs_path, range )?;
     let assist: Assist = filter_extract_function_assist( assists )?;
+    let target: (u32, u32) = ( assist.target.start().into(), assist.target.end().into() );
+    if target != range {
+        warnings.push( ExtractionWarning::in_original(
+            WarningCode::SelectionSnapped,
+            format!( "rust-analyzer extracted {}..{} rather than the selection {}..{}", target.0, target.1, range.0, range.1 ),
+            Some( target ),
+        ) );
+    }
 
+    // rust-analyzer doesn't analyse code disabled by `#[cfg]`, so the
+    // extraction can't account for its uses of locals
+    if original_code.get( range.0 as usize..range.1 as usize ).is_some_and(|text| text.contains( "#[cfg" )) {
+        warnings.extend( inactive_code_ranges( host, frange_.file_id )?
+            .into_iter()
+            .filter(|inactive| inactive.0 < range.1 && range.0 < inactive.1)
+            .map(|inactive| ExtractionWarning::in_original(
+                WarningCode::InactiveCfg,
+                "the selection contains code disabled by `#[cfg]`, which rust-analyzer doesn't analyse".to_string(),
+                Some( inactive ),
+            )) );
+    }
 
-    let modified_code: String = apply_extract_function(
+    Ok( PreparedExtraction {
+        assist,
+        source_file,
+        range,
+        edition,
+        file_id: frange_.file_id,
+        original_code,
+        warnings,
+    } )
+}
+
+/// Runs the extraction against a loaded project. Reports the `Analysis`,
+/// `AssistComputation`, `PostProcessing`, `Verification`, `RoundTrip`,
+/// `BuildCheck` and
+/// `Differential` phases.
+pub(crate) fn extract_from_project(
+    input: &ExtractionInput,
+    input_abs_path: &AbsPathBuf,
+    host: &mut AnalysisHost,
+    vfs: &Vfs,
+    extraction_options: &ExtractionOptions,
+    reporter: &mut PhaseReporter<'_>,
+    start_time: Instant,
+) -> Result<ExtractionOutput, ExtractionError> {
+    let input_path: &str = &input.file_path;
+    let callee_name: &str = &input.new_fn_name;
+
+    let PreparedExtraction {
+        assist,
+        source_file,
+        range,
+        edition,
+        file_id,
+        original_code,
+        warnings: parse_warnings,
+    } = prepare_extraction( input, input_abs_path, host, vfs, extraction_options.allow_errors, reporter )?;
+
+    reporter.begin( ExtractionPhase::PostProcessing );
+    let extracted_code: String = apply_extract_function(
         &assist,
-        &input_abs_path,
-        &vfs,
-        &callee_name,
+        input_abs_path,
+        vfs,
+        callee_name,
+        &original_code,
+        edition,
     )?;
 
-    let parent_method: String = parent_method(
-        &source_file,
-        range,
+    let parent_method: String = parent_method( &source_file, range )
+        .map_err(|e| selection_error( e, input_path, &range ))?;
+
+    let check = |code: &str| -> Result<BuildReport, ExtractionError> {
+        let cargo_toml: AbsPathBuf = find_cargo_toml( input_abs_path )?;
+        check_build(
+            Path::new( cargo_toml.as_str() ),
+            Path::new( input_abs_path.as_str() ),
+            code,
+            None,
+            None,
+        ).map_err( ExtractionError::BuildCheck )
+    };
+    let PlaceholderResolution { code: modified_code, placeholders } = resolve_placeholders(
+        extracted_code,
+        edition,
+        callee_name,
+        &parent_method,
+        extraction_options.placeholders,
+        &check,
     )?;
 
-    Ok( (modified_code, parent_method) )
+    // Locate the new function and its call in the output
+    let output_file: SourceFile = SourceFile::parse( &modified_code, edition ).tree();
+    let new_fn: Option<ast::Fn> = find_fn( &output_file, callee_name );
+    let new_fn_range: Option<(u32, u32)> = new_fn.as_ref().map(|f| (
+        f.syntax().text_range().start().into(),
+        f.syntax().text_range().end().into(),
+    ));
+    let call_site_range: Option<(u32, u32)> = find_call_site( &output_file, callee_name, &parent_method );
+    let edits: Vec<SourceEdit> = compute_edits( &original_code, &modified_code );
+
+    let mut warnings: Vec<ExtractionWarning> = parse_warnings;
+    warnings.extend( syntactic_warnings( &source_file, range, &output_file, new_fn.as_ref() ) );
+    warnings.extend( drop_order_changes( host, file_id, range, new_fn.as_ref() )
+        .iter()
+        .map(|change| ExtractionWarning::in_original( WarningCode::DropOrder, change.to_string(), Some( change.range ) )) );
+    warnings.extend( placeholders
+        .iter()
+        .filter(|placeholder| placeholder.resolved.is_none())
+        .map(|placeholder| ExtractionWarning::in_output(
+            WarningCode::PlaceholderType,
+            format!( "{} could not be inferred and contains `_`", placeholder.position() ),
+            new_fn.as_ref().and_then(|f| placeholder_range( f, placeholder )),
+        )) );
+    let lifetimes: Option<LifetimeReport> = new_fn.as_ref().map(|f| analyse_lifetimes( f, edition ));
+    if let Some( report ) = &lifetimes {
+        let signature_range: Option<(u32, u32)> = new_fn.as_ref().map( signature_range );
+        warnings.extend( report.issues
+            .iter()
+            .filter(|issue| issue.needs_annotations())
+            .map(|issue| ExtractionWarning::in_output( WarningCode::LifetimesNeeded, issue.to_string(), signature_range )) );
+    }
+    let mut diagnostics: Vec<NewDiagnostic> = vec![];
+    if extraction_options.verify != VerifyMode::Off {
+        reporter.begin( ExtractionPhase::Verification );
+        diagnostics = new_diagnostics( host, file_id, &original_code, &modified_code )?;
+        info!("{} new diagnostics", diagnostics.len());
+        if extraction_options.verify == VerifyMode::Deny && diagnostics.iter().any( NewDiagnostic::is_error ) {
+            return Err( ExtractionError::VerificationFailed( diagnostics ) );
+        }
+        warnings.extend( diagnostics.iter().map(|d| ExtractionWarning::in_output( WarningCode::NewDiagnostic, d.to_string(), Some( d.range ) )) );
+    }
+
+    let mut round_trip_report: Option<RoundTripReport> = None;
+    if extraction_options.round_trip {
+        reporter.begin( ExtractionPhase::RoundTrip );
+        let report: RoundTripReport = round_trip(
+            host,
+            file_id,
+            &original_code,
+            &modified_code,
+            callee_name,
+            call_site_range,
+            edition,
+        )?;
+        if !report.inlined {
+            warnings.push( ExtractionWarning::in_output(
+                WarningCode::RoundTripMismatch,
+                "rust-analyzer could not inline the new function back".to_string(),
+                call_site_range,
+            ) );
+        }
+        warnings.extend( report.differences
+            .iter()
+            .map(|difference| ExtractionWarning::in_original(
+                WarningCode::RoundTripMismatch,
+                format!( "after inlining the new function back, {}", difference ),
+                Some( difference.original_range ),
+            )) );
+        round_trip_report = Some( report );
+    }
+
+    let mut build: Option<BuildReport> = None;
+    if extraction_options.verify_build {
+        reporter.begin( ExtractionPhase::BuildCheck );
+        let cargo_toml: AbsPathBuf = find_cargo_toml( input_abs_path )?;
+        let report: BuildReport = check_build(
+            Path::new( cargo_toml.as_str() ),
+            Path::new( input_abs_path.as_str() ),
+            &modified_code,
+            new_fn_range,
+            call_site_range,
+        ).map_err( ExtractionError::BuildCheck )?;
+        if !report.success {
+            warnings.push( ExtractionWarning::in_output(
+                WarningCode::BuildFailed,
+                format!(
+                    "cargo check failed with {} errors ({} in the new function, {} at the call site)",
+                    report.errors.len(),
+                    report.count( BuildErrorLocation::NewFunction ),
+                    report.count( BuildErrorLocation::CallSite ),
+                ),
+                None,
+            ) );
+        }
+        build = Some( report );
+    }
+
+    let mut behaviour: Option<BehaviourReport> = None;
+    if extraction_options.differential != DifferentialMode::Off {
+        reporter.begin( ExtractionPhase::Differential );
+        let cargo_toml: AbsPathBuf = find_cargo_toml( input_abs_path )?;
+        let report: BehaviourReport = compare_behaviour(
+            Path::new( cargo_toml.as_str() ),
+            Path::new( input_abs_path.as_str() ),
+            &original_code,
+            &modified_code,
+            extraction_options.differential,
+        ).map_err( ExtractionError::Differential )?;
+        warnings.extend( report
+            .describe_changes()
+            .into_iter()
+            .map(|change| ExtractionWarning::in_output( WarningCode::BehaviourChanged, change, None )) );
+        behaviour = Some( report );
+    }
+    reporter.finish();
+
+    Ok( ExtractionOutput {
+        file_path: input_path.to_string(),
+        new_fn_name: callee_name.to_string(),
+        new_fn_range,
+        call_site_range,
+        parent_method,
+        signature: new_fn.as_ref().map(|f| signature_info( f, &modified_code )),
+        lifetimes,
+        placeholders,
+        warnings,
+        new_diagnostics: diagnostics,
+        round_trip: round_trip_report,
+        build,
+        behaviour,
+        edits,
+        code: modified_code,
+        timings: std::mem::take( &mut reporter.timings ),
+        total_time: start_time.elapsed(),
+    } )
 }
 
-/// Gets the caller method, based on the input code and the cursor positions
-/// If successful, returns the `String` of the caller method
-/// If unsuccessful, returns an `ExtractionError`
-pub fn parent_method(
-    source_file: &SourceFile,
-    range: (u32, u32),
-) -> Result<String, ExtractionError> {
-    let start: TextSize = TextSize::new(range.0);
+/// Loads the `ProjectWorkspace` for the manifest using the given load strategy,
+/// and runs the build scripts if enabled. Reports the `CargoMetadata` and
+/// `BuildScripts` phases.
+/// Without caching, the full strategy loads the cargo workspace as
+/// rust-analyzer would. With caching enabled, the workspace is loaded from the on-disk cache when
+/// possible (skipping `cargo metadata` and the build scripts), and stored in it
+/// otherwise. Cached workspaces are always loaded from their
+/// `rust-project.json`, on cold and warm runs alike, so that both see exactly
+/// the same crate graph.
+fn project_workspace(
+    project_manifest: &ProjectManifest,
+    cargo_config: &CargoConfig,
+    load_options: &LoadOptions,
+    input_abs_path: &AbsPathBuf,
+    reporter: &mut PhaseReporter<'_>,
+) -> Result<ProjectWorkspace, ExtractionError> {
+    reporter.begin( ExtractionPhase::CargoMetadata );
+    let cached: Option<Value> = match load_options.use_cache {
+        true => cache::read( project_manifest, cargo_config, load_options ),
+        false => None,
+    };
+    if let Some( project_json ) = cached {
+        reporter.report( "Loaded project from cache".to_string() );
+        return json_project_workspace( project_json, load_options, input_abs_path, cargo_config );
+    }
 
-    // We want the last function that occurs before the start of the range
-    let node: Option<ra_ap_syntax::ast::Fn> = algo::find_node_at_offset::<ra_ap_syntax::ast::Fn>(
-        source_file.syntax(),
-        start,
-    );
+    let mut workspace: ProjectWorkspace = load_project_workspace(
+        project_manifest,
+        cargo_config,
+        &|message| reporter.report( message ),
+    )?;
+
+    // Without the std sources every std type in the signature becomes `_`, so
+    // fail loudly instead of producing a degraded extraction
+    check_sysroot( &workspace )?;
 
-    let fn_name: String = match node {
-        Some(n) => n.name().map_or("".to_string(), |name| name.text().to_string()),
-        None => "".to_string(),
+    // Cached and minimal workspaces go through a `rust-project.json`, which
+    // only cargo workspaces can be converted to
+    let project_json: Option<Value> = match load_options.use_cache || load_options.load_strategy == LoadStrategy::Minimal {
+        true => cargo_to_project_json( &workspace ),
+        false => None,
     };
+    if let Some( mut project_json ) = project_json {
+        if load_options.build_scripts {
+            reporter.begin( ExtractionPhase::BuildScripts );
+            let outputs: HashMap<String, BuildScriptOutput> = cache::run_build_scripts( &workspace, cargo_config )?;
+            apply_build_script_outputs( &mut project_json, &outputs );
+        }
+        if load_options.use_cache {
+            cache::write( project_manifest, cargo_config, load_options, &workspace, &project_json );
+        }
+        return json_project_workspace( project_json, load_options, input_abs_path, cargo_config );
+    }
 
-    if fn_name.is_empty() {
-        return Err(ExtractionError::ParentMethodNotFound);
+    // `load_workspace` only uses the build script outputs already in the
+    // workspace, so they are run here, as `load_workspace_at` would
+    if load_options.build_scripts {
+        reporter.begin( ExtractionPhase::BuildScripts );
+        let build_scripts: WorkspaceBuildScripts = workspace
+            .run_build_scripts( cargo_config, &|message| reporter.report( message ) )
+            .map_err(|e| ExtractionError::WorkspaceLoad( e.into() ))?;
+        workspace.set_build_scripts( build_scripts );
     }
 
-    Ok( fn_name.trim().to_string() )
+    Ok( workspace )
+}
 
-}
\ No newline at end of file
+/// Loads a workspace from its `rust-project.json`, pruned to the crate
+/// containing the input file for the minimal strategy
+fn json_project_workspace(
+    project_json: Value,
+    load_options: &LoadOptions,
+    input_abs_path: &AbsPathBuf,
+    cargo_config: &CargoConfig,
+) -> Result<ProjectWorkspace, ExtractionError> {
+    let project_json: Value = match load_options.load_strategy {
+        LoadStrategy::Full => project_json,
+        LoadStrategy::Minimal => prune_project_json( &project_json, input_abs_path )?,
+    };
+    let workspace: ProjectWorkspace = load_project_json( project_json, input_abs_path, cargo_config )?;
+    check_sysroot( &workspace )?;
+    Ok( workspace )
+}
@@ -1,20 +1,56 @@
 //! Utility functions for the rem-extract crate.
 //! At some point these will be merged into rem-utils.
 
-use crate::error::ExtractionError;
+use crate::{
+    diagnostic::{
+        ErrorLocation,
+        SyntaxNodeInfo,
+    },
+    error::ExtractionError,
+    extraction::{
+        LoadOptions,
+        ParamInfo,
+        SignatureInfo,
+        SourceEdit,
+    },
+};
 
 use std::{
+    collections::HashMap,
     env,
     fs,
-    path::PathBuf,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
 
 use camino::Utf8PathBuf;
 
+use rem_extract_fixup::{
+    fixup_extracted,
+    SelectionError,
+};
+
 use ra_ap_project_model::{
     CargoConfig,
+    CargoWorkspace,
+    Package,
+    ProjectJson,
+    ProjectJsonData,
     ProjectWorkspace,
+    ProjectWorkspaceKind,
     ProjectManifest,
+    RustLibSource,
+    Target,
+    TargetKind,
+};
+
+use log::info;
+
+use serde_json::{
+    json,
+    Value,
 };
 
 use ra_ap_ide::{
@@ -48,6 +84,7 @@ use ra_ap_ide_assists::{
 };
 
 use ra_ap_vfs::{
+    AbsPath,
     AbsPathBuf,
     VfsPath,
     Vfs,
@@ -60,15 +97,18 @@ use ra_ap_load_cargo::{
     load_workspace,
 };
 
-use ra_ap_parser::{
-    T,
-    SyntaxKind::COMMENT,
-};
+use ra_ap_parser::Edition;
 
 use ra_ap_syntax::{
-    algo,
+    ast::{
+        self,
+        HasGenericParams,
+        HasName,
+    },
     AstNode,
-    SourceFile
+    SourceFile,
+    SyntaxElement,
+    SyntaxNode,
 };
 
 
@@ -117,12 +157,12 @@ pub fn get_manifest_dir( path: &PathBuf ) -> Result<PathBuf, ExtractionError> {
 /// Will also canonicalize the path before returning it.
 pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
     if path.is_empty() {
-        return Err(Utf8PathBuf::from_path_buf(PathBuf::new()).unwrap());
+        return Err(Utf8PathBuf::new());
     }
 
     // Check if the path is valid for a file system
     if !path.is_ascii() {
-        return Err(Utf8PathBuf::from_path_buf(PathBuf::new()).unwrap());
+        return Err(Utf8PathBuf::new());
     }
 
     // Attempt to convert it as-is (absolute path).
@@ -130,9 +170,9 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
         Ok(abs_path_buf) => Ok(abs_path_buf),
         Err(_) => {
             // Resolve non-absolute path to the current working directory.
-            let current_dir = env::current_dir().expect("Failed to get current directory");
+            let current_dir = env::current_dir().map_err(|_| Utf8PathBuf::from(path))?;
             let utf8_current_dir = Utf8PathBuf::from_path_buf(current_dir)
-                .expect("Failed to convert current directory to Utf8PathBuf");
+                .map_err(|_| Utf8PathBuf::from(path))?;
 
             // println!("Current dir: {:?}", utf8_current_dir);
             // println!("Current path: {:?}", path);
@@ -143,18 +183,19 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
 
             // Create directories leading to the resolved path if they don't exist
             if let Some(parent) = normalized_path.parent() {
-                fs::create_dir_all(parent).expect("Failed to create directories");
+                fs::create_dir_all(parent).map_err(|_| Utf8PathBuf::from(path))?;
             }
 
             // Attempt to convert the normalized path to AbsPathBuf
-            let abs_path = AbsPathBuf::try_from(normalized_path.to_str().unwrap())
-                .map_err(|e| e); // Return the error if the resolved path is still invalid
+            let normalized_path = Utf8PathBuf::from_path_buf(normalized_path)
+                .map_err(|_| Utf8PathBuf::from(path))?;
+            let abs_path = AbsPathBuf::try_from(normalized_path)?; // Return the error if the resolved path is still invalid
             // println!("Resolved path: {:?}", abs_path);
 
             // If the abs_path as a string starts with either a \ or a ? (or some
             // combination), strip it out
 
-            let abs_path_str: String = abs_path.unwrap().to_string();
+            let abs_path_str: String = abs_path.to_string();
             let abs_path_str: String = abs_path_str
                 .replace(r"\\?\", "");
 
@@ -169,52 +210,324 @@ pub fn convert_to_abs_path_buf(path: &str) -> Result<AbsPathBuf, Utf8PathBuf> {
 
 /// Given a `PathBuf` to a folder, returns the `AbsPathBuf` to the `Cargo.toml`
 /// file in that folder.
-pub fn get_cargo_toml( manifest_dir: &PathBuf ) -> AbsPathBuf {
-    AbsPathBuf::try_from(
-        manifest_dir
-            .join( "Cargo.toml" )
-            .to_str()
-            .unwrap()
-    ).unwrap()
+pub fn get_cargo_toml( manifest_dir: &PathBuf ) -> Result<AbsPathBuf, ExtractionError> {
+    let cargo_toml: PathBuf = manifest_dir.join( "Cargo.toml" );
+    cargo_toml
+        .to_str()
+        .and_then(|path| AbsPathBuf::try_from( path ).ok())
+        .ok_or_else(|| ExtractionError::InvalidPath( cargo_toml.to_string_lossy().to_string() ))
 }
 
 /// Loads as `ProjectManifest` from the given `AbsPathBuf` to a `Cargo.toml` file.
-pub fn load_project_manifest( cargo_toml: &AbsPathBuf ) -> ProjectManifest {
+pub fn load_project_manifest( cargo_toml: &AbsPathBuf ) -> Result<ProjectManifest, ExtractionError> {
     ProjectManifest::from_manifest_file(
         cargo_toml.clone()
-    ).unwrap()
+    ).map_err(|e| ExtractionError::ManifestLoad( e.into() ))
 }
 
 /// Loads in the custom cargo configuration
-/// TODO This is currently just the derived default.
-pub fn get_cargo_config( _manifest: &ProjectManifest ) -> CargoConfig {
-    CargoConfig::default()
+/// The sysroot is discovered unless an explicit path is given in the
+/// `LoadOptions`. A toolchain override is passed to rustup through the
+/// `RUSTUP_TOOLCHAIN` environment variable, which is used for both
+/// `cargo metadata` and sysroot discovery.
+pub fn get_cargo_config(
+    _manifest: &ProjectManifest,
+    load_options: &LoadOptions,
+) -> Result<CargoConfig, ExtractionError> {
+    let sysroot: RustLibSource = match &load_options.sysroot {
+        Some( sysroot ) => RustLibSource::Path( resolve_sysroot_path( sysroot )? ),
+        None => RustLibSource::Discover,
+    };
+
+    let sysroot_src: Option<AbsPathBuf> = match &load_options.sysroot_src {
+        Some( sysroot_src ) => Some( resolve_sysroot_path( sysroot_src )? ),
+        None => None,
+    };
+
+    let mut cargo_config: CargoConfig = CargoConfig {
+        sysroot: Some( sysroot ),
+        sysroot_src,
+        ..CargoConfig::default()
+    };
+
+    if let Some( toolchain ) = &load_options.toolchain {
+        cargo_config.extra_env.insert(
+            "RUSTUP_TOOLCHAIN".to_string(),
+            toolchain.clone(),
+        );
+    }
+
+    Ok( cargo_config )
 }
 
-pub fn progress( _message: String ) -> () {
-    // println!( "{}", _message );
+/// Converts a user supplied sysroot path to an `AbsPathBuf`, checking that it
+/// exists. Unlike `convert_to_abs_path_buf`, this never creates directories.
+fn resolve_sysroot_path( path: &str ) -> Result<AbsPathBuf, ExtractionError> {
+    let path_buf: PathBuf = PathBuf::from( path );
+    if !path_buf.is_dir() {
+        return Err( ExtractionError::Sysroot(
+            format!("Sysroot path does not exist: {}", path)
+        ) );
+    }
+
+    let canonical: PathBuf = path_buf.canonicalize()?;
+    let canonical: String = canonical
+        .to_string_lossy()
+        .replace(r"\\?\", "");
+
+    AbsPathBuf::try_from( canonical.as_str() )
+        .map_err( |_| ExtractionError::Sysroot(
+            format!("Sysroot path is not a valid UTF-8 path: {}", path)
+        ) )
+}
+
+/// Checks that the sysroot (and in particular the std library sources) was
+/// found when loading the workspace.
+/// # Returns
+/// - `Ok(())` if the std sources are available
+/// - `Err(ExtractionError::Sysroot)` if the sysroot could not be loaded
+pub fn check_sysroot( workspace: &ProjectWorkspace ) -> Result<(), ExtractionError> {
+    if let Some( err ) = workspace.sysroot.error() {
+        return Err( ExtractionError::Sysroot( err.to_string() ) );
+    }
+
+    if workspace.sysroot.src_root().is_none() {
+        return Err( ExtractionError::Sysroot(
+            "Could not find the standard library sources. Install them with `rustup component add rust-src`, or pass --sysroot-src".to_string()
+        ) );
+    }
+
+    Ok(())
 }
 
 /// Loads a project workspace from a `ProjectManifest` and `CargoConfig`
+/// Status messages from `cargo metadata` are passed on to `progress`
 pub fn load_project_workspace(
     project_manifest: &ProjectManifest,
     cargo_config: &CargoConfig,
-) -> ProjectWorkspace {
+    progress: &dyn Fn(String),
+) -> Result<ProjectWorkspace, ExtractionError> {
     ProjectWorkspace::load(
         project_manifest.clone(),
         cargo_config,
-        &progress
-    ).unwrap()
+        progress
+    ).map_err(|e| ExtractionError::WorkspaceLoad( e.into() ))
+}
+
+/// Converts a cargo `ProjectWorkspace` into the equivalent `rust-project.json`.
+/// Every package contributes its library target, and workspace members also
+/// contribute their binaries, examples, tests and benches.
+/// Returns `None` if the workspace was not loaded from cargo.
+pub fn cargo_to_project_json( workspace: &ProjectWorkspace ) -> Option<Value> {
+    let cargo: &CargoWorkspace = match &workspace.kind {
+        ProjectWorkspaceKind::Cargo { cargo, .. } => cargo,
+        _ => return None,
+    };
+
+    let targets: Vec<Target> = cargo
+        .packages()
+        .flat_map(|pkg| cargo[pkg].targets.iter().copied())
+        .filter(|&tgt| {
+            is_lib_target( cargo, tgt ) || (
+                cargo[ cargo[tgt].package ].is_member
+                && matches!(
+                    cargo[tgt].kind,
+                    TargetKind::Bin | TargetKind::Example | TargetKind::Test | TargetKind::Bench
+                )
+            )
+        })
+        .collect();
+
+    let crate_idx: HashMap<Target, usize> = targets
+        .iter()
+        .enumerate()
+        .map(|(idx, &tgt)| (tgt, idx))
+        .collect();
+
+    let crates: Vec<Value> = targets
+        .iter()
+        .map(|&tgt| {
+            let package: Package = cargo[tgt].package;
+            let mut deps: Vec<Value> = Vec::new();
+            let mut seen: Vec<usize> = Vec::new();
+
+            // Binaries, tests etc. may also use their own package's library
+            let own_lib: Option<(Target, String)> = lib_target( cargo, package )
+                .filter(|&lib| lib != tgt)
+                .map(|lib| (lib, crate_name( cargo, lib )));
+            let dependencies = cargo[package]
+                .dependencies
+                .iter()
+                .filter_map(|dep| lib_target( cargo, dep.pkg ).map(|lib| (lib, dep.name.replace( '-', "_" ))));
+
+            for (dep_target, dep_name) in own_lib.into_iter().chain( dependencies ) {
+                if let Some( &idx ) = crate_idx.get( &dep_target ) {
+                    if !seen.contains( &idx ) {
+                        seen.push( idx );
+                        deps.push( json!({ "crate": idx, "name": dep_name }) );
+                    }
+                }
+            }
+
+            crate_json( cargo, tgt, deps )
+        })
+        .collect();
+
+    Some( json!({
+        "sysroot": workspace.sysroot.root().map(|path| path.as_str()),
+        "sysroot_src": workspace.sysroot.src_root().map(|path| path.as_str()),
+        "crates": crates,
+    }) )
+}
+
+/// Prunes a `rust-project.json` down to the workspace crate containing
+/// `input_path` and its direct dependencies. The dependencies keep their
+/// sources, but lose their own dependencies.
+pub fn prune_project_json(
+    project_json: &Value,
+    input_path: &AbsPathBuf,
+) -> Result<Value, ExtractionError> {
+    let crates: &Vec<Value> = project_json["crates"]
+        .as_array()
+        .ok_or_else(|| ExtractionError::MinimalLoad( "Project has no crates".to_string() ))?;
+
+    let input: &Path = Path::new( input_path.as_str() );
+
+    // Prefer the crate whose root is the file itself, then the crate with the
+    // deepest root directory, then library crates
+    let target_idx: usize = crates
+        .iter()
+        .enumerate()
+        .filter(|(_, krate)| krate["is_workspace_member"].as_bool().unwrap_or(false))
+        .filter_map(|(idx, krate)| {
+            let root: &Path = Path::new( krate["root_module"].as_str()? );
+            let root_dir: &Path = root.parent()?;
+            input.starts_with( root_dir ).then(|| (
+                idx,
+                (
+                    root == input,
+                    root_dir.components().count(),
+                    root.ends_with( "lib.rs" ),
+                ),
+            ))
+        })
+        .max_by_key(|(_, rank)| *rank)
+        .map(|(idx, _)| idx)
+        .ok_or_else(|| ExtractionError::MinimalLoad(
+            format!("No crate in the workspace contains {}", input_path)
+        ))?;
+
+    let mut target: Value = crates[target_idx].clone();
+    let mut pruned: Vec<Value> = Vec::new();
+    let mut deps: Vec<Value> = Vec::new();
+    for dep in target["deps"].as_array().cloned().unwrap_or_default() {
+        let Some( dep_idx ) = dep["crate"].as_u64() else { continue };
+        let Some( dep_crate ) = crates.get( dep_idx as usize ) else { continue };
+
+        let mut dep_crate: Value = dep_crate.clone();
+        dep_crate["deps"] = json!([]);
+        pruned.push( dep_crate );
+        deps.push( json!({ "crate": pruned.len(), "name": dep["name"] }) );
+    }
+    target["deps"] = Value::Array( deps );
+    pruned.insert( 0, target );
+
+    info!(
+        "Minimal load strategy: loading {} of {} crates",
+        pruned.len(),
+        crates.len()
+    );
+
+    Ok( json!({
+        "sysroot": project_json["sysroot"],
+        "sysroot_src": project_json["sysroot_src"],
+        "crates": pruned,
+    }) )
+}
+
+/// Loads a `ProjectWorkspace` from a `rust-project.json` value
+pub fn load_project_json(
+    project_json: Value,
+    input_path: &AbsPathBuf,
+    cargo_config: &CargoConfig,
+) -> Result<ProjectWorkspace, ExtractionError> {
+    let data: ProjectJsonData = serde_json::from_value( project_json )
+        .map_err(|e| ExtractionError::MinimalLoad( e.to_string() ))?;
+
+    // Crate roots are absolute, so the base directory is only used to
+    // resolve relative sysroot paths
+    let base: &AbsPath = input_path
+        .parent()
+        .unwrap_or( input_path.as_path() );
+
+    let project_json: ProjectJson = ProjectJson::new( None, base, data );
+
+    Ok( ProjectWorkspace::load_inline( project_json, cargo_config ) )
+}
+
+/// Returns the library target of a package, if it has one
+fn lib_target( cargo: &CargoWorkspace, package: Package ) -> Option<Target> {
+    cargo[package]
+        .targets
+        .iter()
+        .copied()
+        .find(|&tgt| is_lib_target( cargo, tgt ))
+}
+
+fn is_lib_target( cargo: &CargoWorkspace, target: Target ) -> bool {
+    matches!( cargo[target].kind, TargetKind::Lib { .. } )
+}
+
+/// Crate names in `rust-project.json` must be valid identifiers
+fn crate_name( cargo: &CargoWorkspace, target: Target ) -> String {
+    cargo[target].name.replace( '-', "_" )
+}
+
+/// Builds the `rust-project.json` entry for a single cargo target
+fn crate_json(
+    cargo: &CargoWorkspace,
+    target: Target,
+    deps: Vec<Value>,
+) -> Value {
+    let package = &cargo[ cargo[target].package ];
+    let manifest_dir: &str = package.manifest.parent().as_str();
+
+    let cfg: Vec<String> = package
+        .active_features
+        .iter()
+        .map(|feature| format!("feature=\"{}\"", feature))
+        .collect();
+
+    json!({
+        "display_name": crate_name( cargo, target ),
+        "root_module": cargo[target].root.as_str(),
+        "edition": package.edition.to_string(),
+        "version": package.version.to_string(),
+        "deps": deps,
+        "cfg": cfg,
+        "env": {
+            "CARGO_MANIFEST_DIR": manifest_dir,
+            "CARGO_PKG_NAME": package.name,
+            "CARGO_PKG_VERSION": package.version.to_string(),
+            "CARGO_CRATE_NAME": crate_name( cargo, target ),
+        },
+        "is_workspace_member": package.is_member,
+        "is_proc_macro": matches!( cargo[target].kind, TargetKind::Lib { is_proc_macro: true } ),
+        "source": {
+            "include_dirs": [ manifest_dir ],
+            "exclude_dirs": [ package.manifest.parent().join( "target" ).as_str() ],
+        },
+    })
 }
 
 /// Loads a `RootDatabase` containing from a `ProjectWorkspace` and `CargoConfig`
 pub fn load_workspace_data(
     workspace: ProjectWorkspace,
     cargo_config: &CargoConfig,
-) -> (
+) -> Result<(
     RootDatabase,
     Vfs
-) {
+), ExtractionError> {
     let load_cargo_config: LoadCargoConfig = LoadCargoConfig {
         load_out_dirs_from_check: true,
         with_proc_macro_server: ProcMacroServerChoice::None,
@@ -228,13 +541,13 @@ pub fn load_workspace_data(
         workspace,
         &cargo_config.extra_env,
         &load_cargo_config
-    ).unwrap();
+    ).map_err(|e| ExtractionError::WorkspaceLoad( e.into() ))?;
 
-    (db, vfs)
+    Ok( (db, vfs) )
 }
 
 /// Runs the analysis on an AnalysisHost. A wrapper around `AnalysisHost::analysis`
-pub fn run_analysis( host: AnalysisHost ) -> Analysis {
+pub fn run_analysis( host: &AnalysisHost ) -> Analysis {
 
     let analysis: Analysis = host.analysis();
 
@@ -259,12 +572,12 @@ pub fn get_assists (
     vfs: &Vfs,
     input_path: &AbsPathBuf,
     range: (u32, u32), // Tuple of start and end offsets
-) -> Vec<Assist> {
+) -> Result<Vec<Assist>, ExtractionError> {
 
     let assist_config: AssistConfig = generate_assist_config();
     let diagnostics_config: DiagnosticsConfig = generate_diagnostics_config();
     let resolve: AssistResolveStrategy = generate_resolve_strategy();
-    let frange: FileRange = generate_frange(input_path, vfs, range);
+    let frange: FileRange = generate_frange(input_path, vfs, range)?;
 
     // Call the assists_with_fixes method
     let assists: Vec<Assist> = analysis.assists_with_fixes(
@@ -272,9 +585,50 @@ pub fn get_assists (
         &diagnostics_config,
         resolve,
         frange
-    ).unwrap();
+    ).map_err(|_| ExtractionError::AnalysisCancelled)?;
+
+    Ok( assists )
+}
+
+/// Gets the inline assists (`inline_call`, `inline_into_callers`, ...)
+/// available at a range, with their source changes resolved
+pub(crate) fn get_inline_assists(
+    analysis: &Analysis,
+    frange: FileRange,
+) -> Result<Vec<Assist>, ExtractionError> {
+    let mut assist_config: AssistConfig = generate_assist_config();
+    assist_config.allowed = Some( vec![ AssistKind::RefactorInline ] );
+    let diagnostics_config: DiagnosticsConfig = generate_diagnostics_config();
+
+    let assists: Vec<Assist> = analysis.assists_with_fixes(
+        &assist_config,
+        &diagnostics_config,
+        AssistResolveStrategy::All,
+        frange
+    ).map_err(|_| ExtractionError::AnalysisCancelled)?;
+
+    Ok( assists )
+}
 
-    assists
+/// Applies the edits a resolved assist makes to one file. `text` is the text
+/// of the file the assist was computed on.
+pub(crate) fn apply_assist(
+    assist: &Assist,
+    file_id: FileId,
+    text: &str,
+) -> Result<String, ExtractionError> {
+    let src_change: &SourceChange = assist.source_change
+        .as_ref()
+        .ok_or( ExtractionError::NoSourceChange )?;
+    let (text_edit, maybe_snippet_edit) = src_change.get_source_and_snippet_edit(
+        file_id
+    ).ok_or( ExtractionError::NoSourceChange )?;
+
+    Ok( apply_edits(
+        text.to_string(),
+        text_edit.clone(),
+        maybe_snippet_edit.clone(),
+    ) )
 }
 
 // Build out the AssistConfig Object
@@ -312,7 +666,7 @@ fn generate_assist_config() -> AssistConfig {
 }
 
 // Build out the DiagnosticsConfig
-fn generate_diagnostics_config() -> DiagnosticsConfig {
+pub(crate) fn generate_diagnostics_config() -> DiagnosticsConfig {
     DiagnosticsConfig::test_sample()
 }
 
@@ -333,24 +687,31 @@ pub fn generate_frange(
     input_path: &AbsPathBuf,
     vfs: &Vfs,
     range: (u32, u32)
-) -> FileRange {
+) -> Result<FileRange, ExtractionError> {
     let vfs_path: VfsPath = VfsPath::new_real_path(
         input_path
             .as_str()
             .to_string(),
     );
 
-    let file_id_: FileId = vfs.file_id( &vfs_path ).unwrap();
+    let file_id_: FileId = vfs_file_id( vfs, &vfs_path )?;
     let range_: TextRange = TextRange::new(
-        TextSize::try_from( range.0 ).unwrap(),
-        TextSize::try_from( range.1 ).unwrap(),
+        TextSize::new( range.0 ),
+        TextSize::new( range.1 ),
     );
 
     let frange: FileRange = FileRange {
         file_id: file_id_,
         range: range_,
     };
-    frange
+    Ok( frange )
+}
+
+/// Looks up the `FileId` of a path in the VFS
+/// Throws ExtractionError::FileNotInWorkspace if the file was not loaded
+pub fn vfs_file_id( vfs: &Vfs, vfs_path: &VfsPath ) -> Result<FileId, ExtractionError> {
+    vfs.file_id( vfs_path )
+        .ok_or_else(|| ExtractionError::FileNotInWorkspace( vfs_path.to_string() ))
 }
 
 /// Filter the list of assists to only be the extract_function assist
@@ -372,13 +733,18 @@ pub fn filter_extract_function_assist( assists: Vec<Assist> ) -> Result<Assist,
 
 /// Applies the extract_function source change to the given code
 /// Returns the String of the output code
-/// Renames the function from `fun_name` to `callee_name`.
+/// Renames the function from `fun_name` to `callee_name` (see
+/// `rem_extract_fixup`).
 /// Requires the output path to be an `AbsPathBuf`.
+/// `text` is the text of the input file the assist was computed on, and
+/// `edition` the edition of its crate.
 pub fn apply_extract_function(
     assist: &Assist,
     input_path: &AbsPathBuf,
     vfs: &Vfs,
     callee_name: &str,
+    text: &str,
+    edition: Edition,
 ) -> Result<String, ExtractionError> {
 
     let vfs_in_path: VfsPath = VfsPath::new_real_path(
@@ -390,35 +756,30 @@ pub fn apply_extract_function(
     // From here, extract the source change, but apply it to the copied file
     let src_change: SourceChange = assist.source_change
         .as_ref()
-        .unwrap()
+        .ok_or( ExtractionError::NoSourceChange )?
         .clone();
 
-    let in_file_id: FileId = vfs.file_id( &vfs_in_path ).unwrap();
+    let in_file_id: FileId = vfs_file_id( vfs, &vfs_in_path )?;
     let (text_edit, maybe_snippet_edit) = src_change.get_source_and_snippet_edit(
         in_file_id
-    ).unwrap();
+    ).ok_or( ExtractionError::NoSourceChange )?;
 
-    // Get the source from the input file
-    let src_path: PathBuf = vfs_to_pathbuf( &vfs_in_path );
-    let text: String = fs::read_to_string( &src_path ).unwrap();
     let edited_text: String = apply_edits(
-        text,
+        text.to_string(),
         text_edit.clone(),
         maybe_snippet_edit.clone(),
     );
 
-    // Rename the function from fun_name to NEW_FUNCTION_NAME using a search and
-    // replace on the output file
-    let renamed_text: String = rename_function(
+    // Rename the function from fun_name to NEW_FUNCTION_NAME, and ensure that
+    // the output file imports std::ops::ControlFlow if it uses it
+    let fixed_text: String = fixup_extracted(
+        text,
         edited_text,
-        "fun_name",
         callee_name,
+        edition,
     );
 
-    // Ensure that the output file imports std::ops::ControlFlow if it uses it
-    let fixed_cf_text: String = fixup_controlflow( renamed_text );
-
-    Ok( fixed_cf_text )
+    Ok( fixed_text )
 }
 
 /// Applies the edits to a given set of source code (as a String)
@@ -438,117 +799,226 @@ fn apply_edits(
     text
 }
 
-// Rename a function in a file using a search and replace
-fn rename_function(
-    text: String,
-    old_name: &str,
-    new_name: &str,
-) -> String {
-    let mut text = text; // Make the text mutable
-    let old_name: String = old_name.to_string();
-    let new_name: String = new_name.to_string();
-    text = text.replace( &old_name, &new_name );
-    text
+/// Converts an error of the checks in `rem_extract_fixup` to an
+/// `ExtractionError`, locating the comment or brace in the file
+pub fn selection_error(
+    error: SelectionError,
+    file_path: &str,
+    range: &(u32, u32),
+) -> ExtractionError {
+    match error {
+        SelectionError::InvalidFunctionName( reason ) => ExtractionError::InvalidFunctionName( reason ),
+        SelectionError::Comment( comment ) => ExtractionError::CommentNotApplicable( selection_location(
+            &comment,
+            file_path,
+            range,
+            "the selection only covers this comment".to_string(),
+        ) ),
+        SelectionError::Brace( brace ) => ExtractionError::BracesNotApplicable( selection_location(
+            &brace,
+            file_path,
+            range,
+            format!("the selection only covers this `{}`, select the whole item it delimits instead", brace),
+        ) ),
+        SelectionError::ParentMethodNotFound => ExtractionError::ParentMethodNotFound,
+    }
 }
 
-/// Converts a `VfsPath` to a `PathBuf`
-fn vfs_to_pathbuf( vfs_path: &VfsPath ) -> PathBuf {
-    let path_str = vfs_path.to_string();
-    // println!("{}", path_str);
-    PathBuf::from( path_str )
-}
+/// Builds the location of an error caused by the selection. The nodes are the
+/// element covering the selection followed by its parent, so that e.g. the
+/// block a stray brace belongs to can be reported. The source is the text of
+/// the tree the element belongs to, i.e. the text that was checked.
+fn selection_location(
+    covering_element: &SyntaxElement,
+    file_path: &str,
+    range: &(u32, u32),
+    note: String,
+) -> ErrorLocation {
+    let mut nodes: Vec<SyntaxNodeInfo> = vec![ SyntaxNodeInfo::from_element( covering_element ) ];
+    if let Some( parent ) = covering_element.parent() {
+        nodes.push( SyntaxNodeInfo::from_element( &SyntaxElement::Node( parent ) ) );
+    }
+    let root: Option<SyntaxNode> = match covering_element {
+        SyntaxElement::Node( node ) => node.ancestors().last(),
+        SyntaxElement::Token( token ) => token.parent_ancestors().last(),
+    };
 
-/// Checks that there is some input to the function that isn't a comment
-/// # Returns
-/// - `Ok(())` if the input is not a comment
-/// - `Err(ExtractionError::CommentNotApplicable)` if the input is a comment
-pub fn check_comment(
-    source_file: &SourceFile,
-    range: &(u32, u32)
-) -> Result<(), ExtractionError> {
-    let frange: TextRange = TextRange::new(
-        TextSize::new(range.0),
-        TextSize::new(range.1),
-    );
-    let node = source_file
-        .syntax()
-        .covering_element(frange);
+    ErrorLocation {
+        file_path: file_path.to_string(),
+        selection: *range,
+        nodes,
+        note: Some( note ),
+        source: root.map(|root| root.text().to_string()),
+    }
+}
 
-    if node.kind() == COMMENT {
-        return Err(ExtractionError::CommentNotApplicable);
+/// Computes the line-based edits that turn `original` into `modified`, as
+/// replacements of byte ranges of `original`
+pub fn compute_edits( original: &str, modified: &str ) -> Vec<SourceEdit> {
+    let old_lines: Vec<&str> = original.split_inclusive( '\n' ).collect();
+    let new_lines: Vec<&str> = modified.split_inclusive( '\n' ).collect();
+
+    let mut edits: Vec<SourceEdit> = vec![];
+    // Start, end and replacement text of the hunk being built
+    let mut hunk: Option<(usize, usize, String)> = None;
+    let mut offset: usize = 0;
+    for result in diff::slice( &old_lines, &new_lines ) {
+        match result {
+            diff::Result::Left( line ) => {
+                let (_, end, _) = hunk.get_or_insert_with(|| (offset, offset, String::new()));
+                offset += line.len();
+                *end = offset;
+            },
+            diff::Result::Right( line ) => {
+                let (_, _, new_text) = hunk.get_or_insert_with(|| (offset, offset, String::new()));
+                new_text.push_str( line );
+            },
+            diff::Result::Both( line, _ ) => {
+                if let Some( (start, end, new_text) ) = hunk.take() {
+                    edits.push( SourceEdit { range: (start as u32, end as u32), new_text } );
+                }
+                offset += line.len();
+            },
+        }
+    }
+    if let Some( (start, end, new_text) ) = hunk {
+        edits.push( SourceEdit { range: (start as u32, end as u32), new_text } );
     }
 
-    Ok(())
+    edits
 }
 
-/// Checks that there is some input to the function that isn't a brace
-/// For every:
-/// - { there is a }
-/// - [ there is a ]
-/// - ( there is a )
-/// # Returns
-/// - `Ok(())` if the input is not a brace
-/// - `Err(ExtractionError::BracesNotApplicable)` if the input is a brace
-pub fn check_braces(
-    source_file: &SourceFile,
-    range: &(u32, u32)
-) -> Result<(), ExtractionError> {
-    let frange: TextRange = TextRange::new(
-        TextSize::new(range.0),
-        TextSize::new(range.1),
-    );
-    let node = source_file
+/// Finds the function with the given name
+pub fn find_fn( source_file: &SourceFile, name: &str ) -> Option<ast::Fn> {
+    source_file
         .syntax()
-        .covering_element(frange);
+        .descendants()
+        .filter_map( ast::Fn::cast )
+        .find(|f| f.name().is_some_and(|n| n.text() == name))
+}
 
-    if matches!(node.kind(), T!['{'] | T!['}'] | T!['('] | T![')'] | T!['['] | T![']']) {
-        return Err(ExtractionError::BracesNotApplicable);
-    }
+/// Finds the range of the first call to the function `name` (either as a
+/// function or as a method) within the parent method. If the parent method
+/// can't be found, the whole file is searched.
+pub fn find_call_site(
+    source_file: &SourceFile,
+    name: &str,
+    parent_method: &str,
+) -> Option<(u32, u32)> {
+    let scope: SyntaxNode = find_fn( source_file, parent_method )
+        .map_or( source_file.syntax().clone(), |f| f.syntax().clone() );
+
+    let call: SyntaxNode = scope.descendants().find(|node| {
+        if let Some( call ) = ast::CallExpr::cast( node.clone() ) {
+            return match call.expr() {
+                Some( ast::Expr::PathExpr( path ) ) => path
+                    .path()
+                    .and_then(|p| p.segment())
+                    .and_then(|s| s.name_ref())
+                    .is_some_and(|n| n.text() == name),
+                _ => false,
+            };
+        }
+        ast::MethodCallExpr::cast( node.clone() )
+            .and_then(|call| call.name_ref())
+            .is_some_and(|n| n.text() == name)
+    })?;
 
-    Ok(())
+    Some( (call.text_range().start().into(), call.text_range().end().into()) )
+}
 
+/// Describes the signature of the function. `code` is the text the function
+/// was parsed from.
+pub fn signature_info( function: &ast::Fn, code: &str ) -> SignatureInfo {
+    let text = |node: &SyntaxNode| node.text().to_string();
+    let start: usize = function.syntax().text_range().start().into();
+    let end: usize = function
+        .body()
+        .map_or( function.syntax().text_range().end(), |body| body.syntax().text_range().start() )
+        .into();
+    let param_list: Option<ast::ParamList> = function.param_list();
+
+    SignatureInfo {
+        text: code.get( start..end ).unwrap_or_default().trim().to_string(),
+        self_param: param_list
+            .as_ref()
+            .and_then(|p| p.self_param())
+            .map(|p| text( p.syntax() )),
+        params: param_list
+            .iter()
+            .flat_map(|p| p.params())
+            .map(|p| ParamInfo {
+                pattern: p.pat().map_or( String::new(), |pat| text( pat.syntax() ) ),
+                ty: p.ty().map(|ty| text( ty.syntax() )),
+            })
+            .collect(),
+        return_type: function
+            .ret_type()
+            .and_then(|r| r.ty())
+            .map(|ty| text( ty.syntax() )),
+        generic_params: function.generic_param_list().map(|g| text( g.syntax() )),
+        where_clause: function.where_clause().map(|w| text( w.syntax() )),
+        is_async: function.async_token().is_some(),
+        is_const: function.const_token().is_some(),
+        is_unsafe: function.unsafe_token().is_some(),
+    }
 }
 
-/// Trims the selected range to remove any whitespace
-pub fn trim_range(
-    source_file: &SourceFile,
-    range: &(u32, u32)
-) -> (u32, u32) {
-    let start = TextSize::new(range.0);
-    let end = TextSize::new(range.1);
-    let left = source_file
-        .syntax()
-        .token_at_offset( start )
-        .right_biased()
-        .and_then(|t| algo::skip_whitespace_token(t, rowan::Direction::Next))
-        .map(|t| t.text_range().start().clamp(start, end));
-    let right = source_file
-        .syntax()
-        .token_at_offset( end )
-        .left_biased()
-        .and_then(|t| algo::skip_whitespace_token(t, rowan::Direction::Prev))
-        .map(|t| t.text_range().end().clamp(start, end));
-
-    let trimmed_range = match (left, right) {
-        (Some(left), Some(right)) if left <= right => TextRange::new(left, right),
-        // Selection solely consists of whitespace so just fall back to the original
-        _ => TextRange::new(start, end),
-    };
+/// Temporary projects on disk, for the tests of the modules that need one
+#[cfg(test)]
+pub(crate) mod test_project {
+    use std::{fs, path::PathBuf};
 
-    ( trimmed_range.start().into(), trimmed_range.end().into() )
+    use ra_ap_ide::AnalysisHost;
+    use ra_ap_vfs::{FileId, Vfs, VfsPath};
+    use tempfile::TempDir;
 
-}
+    use super::{convert_to_abs_path_buf, vfs_file_id};
+    use crate::extraction::{find_cargo_toml, load_project, LoadOptions, PhaseReporter, ProgressEvent};
 
-/// Checks if a file contains a reference to ControlFlow::, and if so, adds  use
-/// std::ops::ControlFlow;\n\n to the start of the file, saving it back to the input path
-/// Returns the new text with the ControlFlow:: reference fixed up
-fn fixup_controlflow( text: String, ) -> String {
-    let mut text: String = text; // Make the text mutable
-    let controlflow_ref: &str = "ControlFlow::";
-    if text.contains( controlflow_ref ) {
-        text = format!("use std::ops::ControlFlow;\n\n{}", text);
+    /// A package in a temporary directory, removed when this is dropped
+    pub(crate) struct TestProject {
+        dir: TempDir,
+    }
+
+    impl TestProject {
+        /// Writes an edition 2021 package named `name` with the given files,
+        /// whose paths are relative to the package root (e.g. `src/main.rs`)
+        pub(crate) fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = TempDir::new().unwrap();
+            let manifest = format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n", name);
+            fs::write(dir.path().join("Cargo.toml"), manifest).unwrap();
+            for (path, text) in files {
+                let path = dir.path().join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, text).unwrap();
+            }
+            TestProject { dir }
+        }
+
+        pub(crate) fn cargo_toml(&self) -> PathBuf {
+            self.path("Cargo.toml")
+        }
+
+        /// The path of a file of the package
+        pub(crate) fn path(&self, relative: &str) -> PathBuf {
+            self.dir.path().join(relative)
+        }
+
+        /// Loads the package with the default load options, the same way as an
+        /// extraction from the file at `relative`, and returns the id of that
+        /// file
+        pub(crate) fn load(&self, relative: &str) -> (AnalysisHost, Vfs, FileId) {
+            let input_abs_path = convert_to_abs_path_buf(&self.path(relative).to_string_lossy()).unwrap();
+            let cargo_toml = find_cargo_toml(&input_abs_path).unwrap();
+            let progress = |_: ProgressEvent| {};
+            let mut reporter = PhaseReporter::new(&progress);
+            let (host, vfs) = load_project(&cargo_toml, &input_abs_path, &LoadOptions::default(), &mut reporter).unwrap();
+            let vfs_path = VfsPath::new_real_path(input_abs_path.as_str().to_string());
+            let file_id = vfs_file_id(&vfs, &vfs_path).unwrap();
+            (host, vfs, file_id)
+        }
     }
-    text
 }
 
 #[cfg(test)]
@@ -558,6 +1028,8 @@ mod tests {
     use std::io::Write;
     use std::env;
     use camino::Utf8Path;
+    use tempfile::TempDir;
+    use crate::extraction::{extract_method_configured, ExtractionInput, ExtractionOptions, LoadStrategy};
 
     // Helper function to create a temporary directory with a Cargo.toml
     fn setup_temp_project() -> PathBuf {
@@ -636,6 +1108,162 @@ mod tests {
         }
     }
 
+    // Test that the default options discover the sysroot
+    #[test]
+    fn test_get_cargo_config_default() {
+        let temp_dir = setup_temp_project();
+        let cargo_toml = get_cargo_toml(&temp_dir).unwrap();
+        let manifest = load_project_manifest(&cargo_toml).unwrap();
+
+        let cargo_config = get_cargo_config(&manifest, &LoadOptions::default()).unwrap();
+        assert!(matches!(cargo_config.sysroot, Some(RustLibSource::Discover)));
+        assert!(cargo_config.sysroot_src.is_none());
+        assert!(!cargo_config.extra_env.contains_key("RUSTUP_TOOLCHAIN"));
+    }
+
+    // Test that the sysroot and toolchain options are mapped onto the config
+    #[test]
+    fn test_get_cargo_config_explicit_sysroot() {
+        let temp_dir = setup_temp_project();
+        let cargo_toml = get_cargo_toml(&temp_dir).unwrap();
+        let manifest = load_project_manifest(&cargo_toml).unwrap();
+
+        let options = LoadOptions {
+            sysroot: Some(temp_dir.to_string_lossy().to_string()),
+            toolchain: Some("nightly-2025-02-08".to_string()),
+            ..LoadOptions::default()
+        };
+        let cargo_config = get_cargo_config(&manifest, &options).unwrap();
+        assert!(matches!(cargo_config.sysroot, Some(RustLibSource::Path(_))));
+        assert_eq!(
+            cargo_config.extra_env.get("RUSTUP_TOOLCHAIN").map(String::as_str),
+            Some("nightly-2025-02-08")
+        );
+    }
+
+    // Test that a sysroot path that does not exist is rejected
+    #[test]
+    fn test_get_cargo_config_missing_sysroot() {
+        let temp_dir = setup_temp_project();
+        let cargo_toml = get_cargo_toml(&temp_dir).unwrap();
+        let manifest = load_project_manifest(&cargo_toml).unwrap();
+
+        let options = LoadOptions {
+            sysroot: Some(temp_dir.join("does_not_exist").to_string_lossy().to_string()),
+            ..LoadOptions::default()
+        };
+        let result = get_cargo_config(&manifest, &options);
+        assert!(matches!(result, Err(ExtractionError::Sysroot(_))));
+    }
+
+    #[test]
+    fn test_compute_edits() {
+        let original = "fn main() {\n    let x = 1 + 2;\n}\n";
+        let modified = "fn main() {\n    let x = add();\n}\n\nfn add() -> i32 { 1 + 2 }\n";
+        let edits = compute_edits(original, modified);
+        assert_eq!(edits, vec![
+            SourceEdit { range: (12, 31), new_text: "    let x = add();\n".to_string() },
+            SourceEdit { range: (33, 33), new_text: "\nfn add() -> i32 { 1 + 2 }\n".to_string() },
+        ]);
+        assert!(compute_edits(original, original).is_empty());
+    }
+
+    #[test]
+    fn test_find_call_site_and_signature() {
+        let code = "fn main() {\n    let x = add(1);\n}\n\nfn add(a: i32) -> i32 {\n    a + 2\n}\n";
+        let source_file = SourceFile::parse(code, Edition::Edition2021).tree();
+
+        let call_site = find_call_site(&source_file, "add", "main").unwrap();
+        assert_eq!(&code[call_site.0 as usize..call_site.1 as usize], "add(1)");
+
+        let signature = signature_info(&find_fn(&source_file, "add").unwrap(), code);
+        assert_eq!(signature.text, "fn add(a: i32) -> i32");
+        assert_eq!(signature.params, vec![ParamInfo { pattern: "a".to_string(), ty: Some("i32".to_string()) }]);
+        assert_eq!(signature.return_type, Some("i32".to_string()));
+        assert!(!signature.is_async);
+    }
+
+    #[test]
+    fn test_prune_project_json() {
+        let root = env::temp_dir().join("prune_project");
+        let path = |rel: &str| root.join(rel).to_string_lossy().to_string();
+        let project_json = json!({
+            "sysroot": path("sysroot"),
+            "sysroot_src": path("sysroot/lib/rustlib/src/rust/library"),
+            "crates": [
+                { "root_module": path("app/src/lib.rs"), "is_workspace_member": true, "deps": [] },
+                {
+                    "root_module": path("app/src/main.rs"),
+                    "is_workspace_member": true,
+                    "deps": [
+                        { "crate": 0, "name": "app" },
+                        { "crate": 2, "name": "renamed" },
+                    ],
+                },
+                { "root_module": path("helper/src/lib.rs"), "is_workspace_member": false, "deps": [{ "crate": 3, "name": "transitive" }] },
+                { "root_module": path("transitive/src/lib.rs"), "is_workspace_member": false, "deps": [] },
+            ],
+        });
+        let input = AbsPathBuf::try_from(path("app/src/main.rs").as_str()).unwrap();
+
+        let pruned = prune_project_json(&project_json, &input).unwrap();
+        assert_eq!(pruned["sysroot"], project_json["sysroot"]);
+        assert_eq!(pruned["sysroot_src"], project_json["sysroot_src"]);
+
+        // The target crate comes first, followed by its direct dependencies
+        // under the names it uses for them, without their own dependencies
+        let crates = pruned["crates"].as_array().unwrap();
+        assert_eq!(crates.len(), 3);
+        assert_eq!(crates[0]["root_module"], json!(path("app/src/main.rs")));
+        assert_eq!(crates[0]["deps"], json!([
+            { "crate": 1, "name": "app" },
+            { "crate": 2, "name": "renamed" },
+        ]));
+        assert_eq!(crates[1]["root_module"], json!(path("app/src/lib.rs")));
+        assert_eq!(crates[2]["root_module"], json!(path("helper/src/lib.rs")));
+        assert_eq!(crates[2]["deps"], json!([]));
+
+        // A file in a module of the library selects the library crate
+        let input = AbsPathBuf::try_from(path("app/src/utils/mod.rs").as_str()).unwrap();
+        let pruned = prune_project_json(&project_json, &input).unwrap();
+        assert_eq!(pruned["crates"][0]["root_module"], json!(path("app/src/lib.rs")));
+
+        // Only workspace members are targets
+        let input = AbsPathBuf::try_from(path("helper/src/lib.rs").as_str()).unwrap();
+        assert!(matches!(prune_project_json(&project_json, &input), Err(ExtractionError::MinimalLoad(_))));
+    }
+
+    const RENAMED_MAIN_RS: &str = "fn main() {\n    let h = renamed::make();\n    let n = h.value + 1;\n    println!(\"{}\", n);\n}\n";
+
+    #[test]
+    fn test_minimal_load_resolves_renamed_dependency() {
+        let dir = TempDir::new().unwrap();
+        let write = |rel: &str, contents: &str| {
+            let path = dir.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\nrenamed = { package = \"helper\", path = \"helper\" }\n");
+        write("src/main.rs", RENAMED_MAIN_RS);
+        write("helper/Cargo.toml", "[package]\nname = \"helper\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\ntransitive = { path = \"../transitive\" }\n");
+        write("helper/src/lib.rs", "pub struct Helper { pub value: u64 }\npub fn make() -> Helper { Helper { value: transitive::VALUE } }\n");
+        write("transitive/Cargo.toml", "[package]\nname = \"transitive\"\nversion = \"0.1.0\"\nedition = \"2021\"\n");
+        write("transitive/src/lib.rs", "pub const VALUE: u64 = 1;\n");
+
+        let main_rs = dir.path().join("src").join("main.rs");
+        let start = RENAMED_MAIN_RS.find("let n").unwrap() as u32;
+        let end = RENAMED_MAIN_RS.find("println").unwrap() as u32;
+        let input = ExtractionInput::new(&main_rs.to_string_lossy(), "fun_name", start, end);
+        let load_options = LoadOptions { load_strategy: LoadStrategy::Minimal, use_cache: false, ..Default::default() };
+
+        // The direct dependency is loaded under its renamed name, so the
+        // types coming from it resolve
+        let code = extract_method_configured(input, &load_options, &ExtractionOptions::default(), &|_| ()).unwrap().code;
+        let signature = code.lines().find(|line| line.starts_with("fn fun_name")).unwrap();
+        assert!(signature.contains("Helper") && !signature.contains(": _"), "{}", signature);
+        assert!(signature.contains("-> u64"), "{}", signature);
+    }
+
     #[test]
     ///Only run this test on Windows as it tests Windows-specific paths
     /// This test is skipped on other platforms
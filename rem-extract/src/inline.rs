@@ -0,0 +1,478 @@
+//! Inlining, the reverse of extraction.
+//!
+//! A function is inlined with rust-analyzer's assists, through the same
+//! loading pipeline as an extraction:
+//!
+//! - `InlineTarget::Function` inlines every call of the function and removes
+//!   it (`inline_into_callers`). Calls in other files of the project are
+//!   inlined too, and returned in `other_files`. Verification covers every
+//!   changed file;
+//! - `InlineTarget::CallSite` inlines the call at an offset (`inline_call`),
+//!   leaving the function in place.
+//!
+//! This undoes an extraction without a backup of the original file, e.g. when
+//! repairing the extracted function fails.
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use ra_ap_hir::Semantics;
+
+use ra_ap_ide::{
+    AnalysisHost,
+    FileRange,
+    RootDatabase,
+    SourceChange,
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_ide_assists::Assist;
+
+use ra_ap_ide_db::EditionedFileId;
+
+use ra_ap_parser::Edition;
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    SourceFile,
+    SyntaxKind,
+};
+
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+    Vfs,
+    VfsPath,
+};
+
+use crate::{
+    error::ExtractionError,
+    extraction::{
+        check_file_exists,
+        find_cargo_toml,
+        load_project,
+        serialize_millis,
+        ExtractionOptions,
+        ExtractionPhase,
+        LoadOptions,
+        PhaseReporter,
+        PhaseTiming,
+        ProgressEvent,
+        SourceEdit,
+        VerifyMode,
+    },
+    extraction_utils::{
+        apply_assist,
+        compute_edits,
+        convert_to_abs_path_buf,
+        get_inline_assists,
+        vfs_file_id,
+    },
+    verify::{
+        new_diagnostics_in_files,
+        ChangedFile,
+        NewDiagnostic,
+    },
+    warnings::{
+        added_imports,
+        ExtractionWarning,
+        WarningCode,
+    },
+};
+
+/// What to inline
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InlineTarget {
+    /// Every call of the function with this name, which is then removed
+    Function( String ),
+    /// The call at this byte offset
+    CallSite( u32 ),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct InlineInput {
+    #[serde(alias = "file")]
+    pub file_path: String,
+    pub target: InlineTarget,
+}
+
+impl InlineInput {
+    pub fn new( file_path: &str, target: InlineTarget ) -> Self {
+        InlineInput {
+            file_path: file_path.to_string(),
+            target,
+        }
+    }
+}
+
+/// A file other than the input file whose calls were inlined
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct InlinedFile {
+    pub file_path: String,
+    /// The whole modified file
+    pub code: String,
+    /// The line-based edits that turn the original file into `code`
+    pub edits: Vec<SourceEdit>,
+    /// Diagnostics of the modified file that the original file didn't have.
+    /// Only computed if verification is enabled.
+    pub new_diagnostics: Vec<NewDiagnostic>,
+}
+
+/// Everything known about a successful inlining. It has the same structure as
+/// `ExtractionOutput` where they overlap.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct InlineOutput {
+    pub file_path: String,
+    /// The whole modified file
+    pub code: String,
+    /// The line-based edits that turn the original file into `code`
+    pub edits: Vec<SourceEdit>,
+    /// The name of the inlined function
+    pub fn_name: String,
+    /// The assist that was applied, `inline_into_callers` or `inline_call`
+    pub assist: String,
+    /// Whether the function was removed from the input file
+    pub removed: bool,
+    /// The other files with calls that were inlined
+    pub other_files: Vec<InlinedFile>,
+    pub warnings: Vec<ExtractionWarning>,
+    /// Diagnostics of the modified file that the original file didn't have.
+    /// Only computed if verification is enabled.
+    pub new_diagnostics: Vec<NewDiagnostic>,
+    pub timings: Vec<PhaseTiming>,
+    #[serde(rename = "total_ms", serialize_with = "serialize_millis")]
+    pub total_time: Duration,
+}
+
+impl InlineOutput {
+    /// The output as a JSON object, as printed by `--format json`
+    pub fn to_json( &self ) -> Value {
+        let mut value: Value = json!( self );
+        value["status"] = json!( "ok" );
+        value
+    }
+}
+
+/// Inlines the function or call, loading the project containing the file.
+/// Only the `verify` option of `ExtractionOptions` applies.
+pub fn inline_function(
+    input: InlineInput,
+    load_options: &LoadOptions,
+    extraction_options: &ExtractionOptions,
+    progress: &dyn Fn(ProgressEvent),
+) -> Result<InlineOutput, ExtractionError> {
+    let start_time: Instant = Instant::now();
+    let mut reporter: PhaseReporter<'_> = PhaseReporter::new( progress );
+
+    let input_abs_path: AbsPathBuf = check_inline_input( &input )?;
+
+    reporter.begin( ExtractionPhase::ManifestDiscovery );
+    let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+    let (mut host, vfs) = load_project(
+        &cargo_toml,
+        &input_abs_path,
+        load_options,
+        &mut reporter,
+    )?;
+
+    inline_in_project(
+        &input,
+        &input_abs_path,
+        &mut host,
+        &vfs,
+        extraction_options,
+        &mut reporter,
+        start_time,
+    )
+}
+
+/// Converts the input path to an `AbsPathBuf` and checks that the file exists
+pub(crate) fn check_inline_input( input: &InlineInput ) -> Result<AbsPathBuf, ExtractionError> {
+    let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( &input.file_path )
+        .map_err(|_| ExtractionError::InvalidPath( input.file_path.to_string() ))?;
+    check_file_exists( &input.file_path )?;
+    Ok( input_abs_path )
+}
+
+/// Runs the inlining against a loaded project. Reports the `Analysis`,
+/// `AssistComputation`, `PostProcessing` and `Verification` phases.
+pub(crate) fn inline_in_project(
+    input: &InlineInput,
+    input_abs_path: &AbsPathBuf,
+    host: &mut AnalysisHost,
+    vfs: &Vfs,
+    extraction_options: &ExtractionOptions,
+    reporter: &mut PhaseReporter<'_>,
+    start_time: Instant,
+) -> Result<InlineOutput, ExtractionError> {
+    reporter.begin( ExtractionPhase::Analysis );
+    let vfs_path: VfsPath = VfsPath::new_real_path( input_abs_path.as_str().to_string() );
+    let file_id: FileId = vfs_file_id( vfs, &vfs_path )?;
+    let ( source_file, edition ): ( SourceFile, Edition ) = {
+        let sema: Semantics<'_, RootDatabase> = Semantics::new( host.raw_database() );
+        let editioned_file_id: EditionedFileId = sema
+            .attach_first_edition( file_id )
+            .unwrap_or_else(|| EditionedFileId::current_edition( file_id ));
+        ( sema.parse( editioned_file_id ), editioned_file_id.edition() )
+    };
+    let original_code: String = source_file.syntax().text().to_string();
+
+    let ( offset, fn_name, assist_id ) = match &input.target {
+        InlineTarget::Function( name ) => {
+            let name_node: ast::Name = find_free_fn( &source_file, name )
+                .and_then(|f| f.name())
+                .ok_or_else(|| ExtractionError::FunctionNotFound( name.clone() ))?;
+            ( name_node.syntax().text_range().start(), name.clone(), "inline_into_callers" )
+        },
+        InlineTarget::CallSite( offset ) => {
+            let name_ref: ast::NameRef = callee_at( &source_file, *offset )
+                .ok_or_else(|| ExtractionError::NoInlineAssist( format!( "there is no call at byte {}", offset ) ))?;
+            ( name_ref.syntax().text_range().start(), name_ref.text().to_string(), "inline_call" )
+        },
+    };
+
+    reporter.begin( ExtractionPhase::AssistComputation );
+    let frange: FileRange = FileRange { file_id, range: TextRange::empty( offset ) };
+    let assist: Assist = get_inline_assists( &host.analysis(), frange )?
+        .into_iter()
+        .find(|assist| assist.id.0 == assist_id)
+        .ok_or_else(|| ExtractionError::NoInlineAssist( format!( "rust-analyzer can't inline `{}` here", fn_name ) ))?;
+
+    reporter.begin( ExtractionPhase::PostProcessing );
+    let source_change: &SourceChange = assist.source_change
+        .as_ref()
+        .ok_or( ExtractionError::NoSourceChange )?;
+    let mut code: String = original_code.clone();
+    // The file id and original text of each other file, to verify them
+    let mut other_files: Vec<( FileId, String, InlinedFile )> = vec![];
+    for &changed_file in source_change.source_file_edits.keys() {
+        if changed_file == file_id {
+            code = apply_assist( &assist, file_id, &original_code )?;
+            continue;
+        }
+        let text: String = host
+            .analysis()
+            .file_text( changed_file )
+            .map_err(|_| ExtractionError::AnalysisCancelled)?
+            .to_string();
+        let modified: String = apply_assist( &assist, changed_file, &text )?;
+        let inlined: InlinedFile = InlinedFile {
+            file_path: vfs.file_path( changed_file ).to_string(),
+            edits: compute_edits( &text, &modified ),
+            code: modified,
+            new_diagnostics: vec![],
+        };
+        other_files.push( ( changed_file, text, inlined ) );
+    }
+    other_files.sort_by(|a, b| a.2.file_path.cmp( &b.2.file_path ));
+
+    let output_file: SourceFile = SourceFile::parse( &code, edition ).tree();
+    // A call site may call a method, which `find_free_fn` can't find, but
+    // `inline_call` never removes the function anyway
+    let kept: Option<ast::Fn> = match input.target {
+        InlineTarget::Function( _ ) => find_free_fn( &output_file, &fn_name ),
+        InlineTarget::CallSite( _ ) => None,
+    };
+    let removed: bool = matches!( input.target, InlineTarget::Function( _ ) ) && kept.is_none();
+    let mut warnings: Vec<ExtractionWarning> = added_imports( &source_file, &output_file );
+    if let Some( kept ) = kept {
+        warnings.push( ExtractionWarning::in_output(
+            WarningCode::FunctionKept,
+            format!( "`{}` is still used, so it was not removed", fn_name ),
+            Some( (
+                kept.syntax().text_range().start().into(),
+                kept.syntax().text_range().end().into(),
+            ) ),
+        ) );
+    }
+
+    let mut diagnostics: Vec<NewDiagnostic> = vec![];
+    if extraction_options.verify != VerifyMode::Off {
+        reporter.begin( ExtractionPhase::Verification );
+        let mut changed_files: Vec<ChangedFile<'_>> = vec![
+            ChangedFile { file_id, original: &original_code, modified: &code },
+        ];
+        changed_files.extend( other_files.iter().map(|( changed_file, text, inlined )| ChangedFile {
+            file_id: *changed_file,
+            original: text,
+            modified: &inlined.code,
+        }) );
+        let mut all_diagnostics: Vec<Vec<NewDiagnostic>> = new_diagnostics_in_files( host, &changed_files )?;
+        diagnostics = all_diagnostics.remove( 0 );
+        for ( ( _, _, inlined ), file_diagnostics ) in other_files.iter_mut().zip( all_diagnostics ) {
+            inlined.new_diagnostics = file_diagnostics;
+        }
+
+        let other_diagnostics: Vec<NewDiagnostic> = other_files
+            .iter()
+            .flat_map(|( _, _, inlined )| inlined.new_diagnostics
+                .iter()
+                .map(|d| in_other_file( d, &inlined.file_path )))
+            .collect();
+        if extraction_options.verify == VerifyMode::Deny
+            && diagnostics.iter().chain( &other_diagnostics ).any( NewDiagnostic::is_error )
+        {
+            let mut failed: Vec<NewDiagnostic> = diagnostics;
+            failed.extend( other_diagnostics );
+            return Err( ExtractionError::VerificationFailed( failed ) );
+        }
+        warnings.extend( diagnostics.iter().map(|d| ExtractionWarning::in_output( WarningCode::NewDiagnostic, d.to_string(), Some( d.range ) )) );
+        // The ranges of the other files don't apply to the output file
+        warnings.extend( other_diagnostics.iter().map(|d| ExtractionWarning::in_output( WarningCode::NewDiagnostic, d.to_string(), None )) );
+    }
+    reporter.finish();
+    let other_files: Vec<InlinedFile> = other_files.into_iter().map(|( _, _, inlined )| inlined).collect();
+
+    Ok( InlineOutput {
+        file_path: input.file_path.clone(),
+        edits: compute_edits( &original_code, &code ),
+        code,
+        fn_name,
+        assist: assist_id.to_string(),
+        removed,
+        other_files,
+        warnings,
+        new_diagnostics: diagnostics,
+        timings: std::mem::take( &mut reporter.timings ),
+        total_time: start_time.elapsed(),
+    } )
+}
+
+/// A diagnostic of another file, with the path of the file in the message
+fn in_other_file( diagnostic: &NewDiagnostic, file_path: &str ) -> NewDiagnostic {
+    NewDiagnostic {
+        message: format!( "in {}: {}", file_path, diagnostic.message ),
+        ..diagnostic.clone()
+    }
+}
+
+/// The free function with this name, at the top level of the file or of an
+/// inline module. Methods, and functions nested in other items, are skipped.
+fn find_free_fn( source_file: &SourceFile, name: &str ) -> Option<ast::Fn> {
+    source_file
+        .syntax()
+        .descendants()
+        .filter_map( ast::Fn::cast )
+        .filter(|f| f.syntax().parent().is_some_and(|parent| match parent.kind() {
+            SyntaxKind::SOURCE_FILE => true,
+            SyntaxKind::ITEM_LIST => parent.parent().is_some_and(|p| p.kind() == SyntaxKind::MODULE),
+            _ => false,
+        }))
+        .find(|f| f.name().is_some_and(|n| n.text() == name))
+}
+
+/// The name of the function called by the innermost call at the offset
+fn callee_at( source_file: &SourceFile, offset: u32 ) -> Option<ast::NameRef> {
+    let offset: TextSize = TextSize::new( offset );
+    if offset > source_file.syntax().text_range().end() {
+        return None;
+    }
+    source_file
+        .syntax()
+        .token_at_offset( offset )
+        .right_biased()?
+        .parent_ancestors()
+        .find_map(|node| {
+            if let Some( call ) = ast::MethodCallExpr::cast( node.clone() ) {
+                return call.name_ref();
+            }
+            match ast::CallExpr::cast( node )?.expr()? {
+                ast::Expr::PathExpr( path ) => path.path()?.segment()?.name_ref(),
+                _ => None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction_utils::test_project::TestProject;
+
+    #[test]
+    fn test_callee_at() {
+        let code = "fn main() { let n = foo(1) + s.bar(foo(2)); }";
+        let file = SourceFile::parse(code, Edition::CURRENT).tree();
+        let at = |text: &str| code.find(text).unwrap() as u32;
+        let callee = |offset: u32| callee_at(&file, offset).map(|name| name.text().to_string());
+        assert_eq!(callee(at("foo(1)")).as_deref(), Some("foo"));
+        assert_eq!(callee(at("(1)") + 1).as_deref(), Some("foo"));
+        assert_eq!(callee(at("s.bar")).as_deref(), Some("bar"));
+        assert_eq!(callee(at("foo(2)")).as_deref(), Some("foo"));
+        assert_eq!(callee(at("let")), None);
+    }
+
+    const LIB: &str = "struct S;
+
+impl S {
+    fn double(&self, n: i32) -> i32 { n * 3 }
+}
+
+fn outer() -> i32 {
+    fn double(n: i32) -> i32 { n * 4 }
+    double(1)
+}
+
+fn double(n: i32) -> i32 {
+    n * 2
+}
+
+pub fn run() -> i32 {
+    double(1) + S.double(2) + outer()
+}
+";
+
+    #[test]
+    fn test_find_free_fn() {
+        let file = SourceFile::parse(LIB, Edition::CURRENT).tree();
+        let found = find_free_fn(&file, "double").unwrap();
+        assert_eq!(found.syntax().text_range().start(), TextSize::new(LIB.find("fn double(n: i32) -> i32 {\n").unwrap() as u32));
+        assert!(find_free_fn(&file, "missing").is_none());
+    }
+
+    #[test]
+    fn test_inline_in_project() {
+        let project = TestProject::new("inline_test", &[("src/lib.rs", LIB)]);
+        let input = InlineInput::new(&project.path("src/lib.rs").to_string_lossy(), InlineTarget::Function("double".to_string()));
+        let input_abs_path = check_inline_input(&input).unwrap();
+        let (mut host, vfs, _) = project.load("src/lib.rs");
+        let progress = |_: ProgressEvent| {};
+        let mut reporter = PhaseReporter::new(&progress);
+
+        let output = inline_in_project(
+            &input,
+            &input_abs_path,
+            &mut host,
+            &vfs,
+            &ExtractionOptions::default(),
+            &mut reporter,
+            Instant::now(),
+        ).unwrap();
+        assert_eq!(output.assist, "inline_into_callers");
+        assert!(output.removed);
+        assert!(output.warnings.iter().all(|w| w.code != WarningCode::FunctionKept));
+        // Only the free function is inlined and removed
+        assert!(!output.code.contains("n * 2\n}"));
+        assert!(output.code.contains("fn double(&self, n: i32) -> i32 { n * 3 }"));
+        assert!(output.code.contains("fn double(n: i32) -> i32 { n * 4 }"));
+        assert!(output.code.contains("S.double(2)"));
+        assert!(output.other_files.is_empty());
+    }
+}
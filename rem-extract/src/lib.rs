@@ -1,5 +1,22 @@
 pub mod extraction;
 pub mod error;
+pub mod cache;
+pub mod diagnostic;
+pub mod session;
+pub mod batch;
+pub mod serve;
+pub mod lsp;
+pub mod verify;
+pub mod sandbox;
+pub mod differential;
+pub mod drop_order;
+pub mod lifetimes;
+pub mod placeholders;
+pub mod round_trip;
+pub mod inline;
+pub mod warnings;
+#[cfg(feature = "python")]
+pub mod python;
 
 mod extract_tests;
 mod test_details;
@@ -0,0 +1,258 @@
+//! Analysis of the lifetimes in the signature of the new function.
+//!
+//! rust-analyzer generates signatures with elided lifetimes, which only
+//! compile if the lifetime elision rules can fill them in. This module reports
+//! the signatures that won't compile without annotations, so that a lifetime
+//! repair step can skip the others:
+//!
+//! - the return type has elided lifetimes, there is no `&self` and the inputs
+//!   have several lifetimes (or none) to choose from (E0106);
+//! - the return type is an `impl Trait` that may capture the lifetime of a
+//!   reference parameter, which it only does implicitly from edition 2024 on
+//!   (E0700).
+//!
+//! Signatures returning a reference borrowed from their only reference input
+//! compile, and are reported for information.
+//!
+//! The analysis is syntactic, so lifetimes hidden in paths (e.g. the lifetime
+//! of `fmt::Formatter`) are not seen.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use ra_ap_parser::Edition;
+
+use ra_ap_syntax::{
+    ast,
+    AstNode,
+    SyntaxNode,
+};
+
+/// A lifetime in a type: `None` if it is elided (`&T` or `'_`)
+type TypeLifetime = Option<String>;
+
+/// A property of the signature related to lifetimes
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LifetimeIssue {
+    /// The return type has elided lifetimes and several inputs have
+    /// lifetimes, so elision can't tell which one it borrows from
+    AmbiguousElidedOutput { inputs: Vec<String> },
+    /// The return type has elided lifetimes and no input has a lifetime
+    NoInputLifetime,
+    /// The return type is an `impl Trait` without a lifetime bound, and the
+    /// hidden type may borrow from these inputs
+    ImplTraitCapture { inputs: Vec<String> },
+    /// The return type borrows from the only input with a lifetime. This
+    /// compiles thanks to elision.
+    BorrowsFromInput { input: String },
+}
+
+impl LifetimeIssue {
+    /// Whether the signature needs explicit lifetimes because of this issue
+    pub fn needs_annotations( &self ) -> bool {
+        !matches!( self, LifetimeIssue::BorrowsFromInput { .. } )
+    }
+}
+
+impl fmt::Display for LifetimeIssue {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            LifetimeIssue::AmbiguousElidedOutput { inputs } => write!(
+                f, "the return type has elided lifetimes but several parameters have lifetimes ({})",
+                inputs.join( ", " ),
+            ),
+            LifetimeIssue::NoInputLifetime => write!(
+                f, "the return type has elided lifetimes but no parameter has a lifetime",
+            ),
+            LifetimeIssue::ImplTraitCapture { inputs } => write!(
+                f, "the returned `impl Trait` may capture the lifetime of {} without a `+ '_` bound",
+                inputs.join( ", " ),
+            ),
+            LifetimeIssue::BorrowsFromInput { input } => write!(
+                f, "the return type borrows from `{}`",
+                input,
+            ),
+        }
+    }
+}
+
+/// The lifetime issues of a signature
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize)]
+pub struct LifetimeReport {
+    /// Whether the signature won't compile without lifetime annotations
+    pub needs_annotations: bool,
+    pub issues: Vec<LifetimeIssue>,
+}
+
+/// Analyses the lifetimes in the signature of `function`, for a crate of the
+/// given edition
+pub fn analyse_lifetimes( function: &ast::Fn, edition: Edition ) -> LifetimeReport {
+    let param_list: Option<ast::ParamList> = function.param_list();
+
+    // The inputs with lifetimes, by name, and their lifetimes
+    let mut inputs: Vec<(String, Vec<TypeLifetime>)> = vec![];
+    let self_param: Option<ast::SelfParam> = param_list.as_ref().and_then(|p| p.self_param());
+    let borrows_self: bool = self_param.as_ref().is_some_and(|p| {
+        p.amp_token().is_some() || matches!( p.ty(), Some( ast::Type::RefType( _ ) ) )
+    });
+    if let Some( self_param ) = &self_param {
+        let lifetimes: Vec<TypeLifetime> = match ( self_param.amp_token(), self_param.ty() ) {
+            ( Some( _ ), _ ) => vec![ self_param.lifetime().map(|l| l.text().to_string()).filter(|l| l != "'_") ],
+            ( None, Some( ty ) ) => type_lifetimes( &ty ),
+            ( None, None ) => vec![],
+        };
+        if !lifetimes.is_empty() {
+            inputs.push( ( "self".to_string(), lifetimes ) );
+        }
+    }
+    for param in param_list.iter().flat_map(|p| p.params()) {
+        let lifetimes: Vec<TypeLifetime> = param.ty().map_or( vec![], |ty| type_lifetimes( &ty ) );
+        if !lifetimes.is_empty() {
+            let name: String = param.pat().map_or( "_".to_string(), |pat| pat.syntax().text().to_string() );
+            inputs.push( ( name, lifetimes ) );
+        }
+    }
+
+    // Elided lifetimes are all distinct, named ones are counted once
+    let mut named: Vec<&str> = vec![];
+    let mut input_lifetimes: usize = 0;
+    for lifetime in inputs.iter().flat_map(|( _, lifetimes )| lifetimes) {
+        match lifetime {
+            None => input_lifetimes += 1,
+            Some( name ) if !named.contains( &name.as_str() ) => {
+                named.push( name );
+                input_lifetimes += 1;
+            },
+            Some( _ ) => {},
+        }
+    }
+    let input_names: Vec<String> = inputs.iter().map(|( name, _ )| name.clone()).collect();
+
+    let ret_ty: Option<ast::Type> = function.ret_type().and_then(|r| r.ty());
+    let output_lifetimes: Vec<TypeLifetime> = ret_ty.as_ref().map_or( vec![], type_lifetimes );
+
+    let mut issues: Vec<LifetimeIssue> = vec![];
+    if output_lifetimes.iter().any( Option::is_none ) {
+        if borrows_self {
+            issues.push( LifetimeIssue::BorrowsFromInput { input: "self".to_string() } );
+        } else if input_lifetimes == 1 {
+            issues.push( LifetimeIssue::BorrowsFromInput { input: input_names[0].clone() } );
+        } else if input_lifetimes == 0 {
+            issues.push( LifetimeIssue::NoInputLifetime );
+        } else {
+            issues.push( LifetimeIssue::AmbiguousElidedOutput { inputs: input_names.clone() } );
+        }
+    }
+
+    let returns_uncaptured_impl_trait: bool = ret_ty.as_ref().is_some_and(|ty| {
+        outer_descendants( ty.syntax() )
+            .filter_map( ast::ImplTraitType::cast )
+            .any(|impl_trait| impl_trait.syntax().descendants().all(|node| !ast::Lifetime::can_cast( node.kind() )))
+    });
+    if returns_uncaptured_impl_trait && !input_names.is_empty() && !edition.at_least_2024() {
+        issues.push( LifetimeIssue::ImplTraitCapture { inputs: input_names } );
+    }
+
+    LifetimeReport {
+        needs_annotations: issues.iter().any( LifetimeIssue::needs_annotations ),
+        issues,
+    }
+}
+
+/// The lifetimes in a type, excluding `'static` and the lifetimes of
+/// function pointer and `Fn` trait signatures, which are bound there
+fn type_lifetimes( ty: &ast::Type ) -> Vec<TypeLifetime> {
+    let named = |lifetime: Option<ast::Lifetime>| -> Option<TypeLifetime> {
+        match lifetime {
+            None => Some( None ),
+            Some( lifetime ) => match lifetime.text().as_str() {
+                "'_" => Some( None ),
+                "'static" => None,
+                name => Some( Some( name.to_string() ) ),
+            },
+        }
+    };
+
+    outer_descendants( ty.syntax() )
+        .filter_map(|node| {
+            if let Some( ref_type ) = ast::RefType::cast( node.clone() ) {
+                return named( ref_type.lifetime() );
+            }
+            let lifetime: ast::Lifetime = ast::Lifetime::cast( node.clone() )?;
+            // The lifetime of a reference is counted with the reference
+            if node.parent().is_some_and(|parent| ast::RefType::can_cast( parent.kind() )) {
+                return None;
+            }
+            named( Some( lifetime ) )
+        })
+        .collect()
+}
+
+/// The descendants of a type that are not inside a function pointer, `Fn`
+/// trait signature or `for<'a>` binder
+fn outer_descendants( root: &SyntaxNode ) -> impl Iterator<Item = SyntaxNode> + '_ {
+    root.descendants().filter(move |node| {
+        !node
+            .ancestors()
+            .take_while(|ancestor| ancestor != root)
+            .any(|ancestor| {
+                ast::FnPtrType::can_cast( ancestor.kind() )
+                    || ast::ParamList::can_cast( ancestor.kind() )
+                    || ast::RetType::can_cast( ancestor.kind() )
+                    || ast::ForType::can_cast( ancestor.kind() )
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_ap_syntax::SourceFile;
+
+    fn analyse(signature: &str, edition: Edition) -> LifetimeReport {
+        let file = SourceFile::parse(&format!("{} {{}}", signature), edition).tree();
+        let function = file.syntax().descendants().find_map(ast::Fn::cast).unwrap();
+        analyse_lifetimes(&function, edition)
+    }
+
+    #[test]
+    fn test_elided_output_lifetimes() {
+        let report = analyse("fn f(a: &str, b: &str) -> &str", Edition::Edition2021);
+        assert!(report.needs_annotations);
+        assert_eq!(report.issues, vec![LifetimeIssue::AmbiguousElidedOutput {
+            inputs: vec!["a".to_string(), "b".to_string()],
+        }]);
+
+        let report = analyse("fn f(a: String) -> &str", Edition::Edition2021);
+        assert_eq!(report.issues, vec![LifetimeIssue::NoInputLifetime]);
+
+        let report = analyse("fn f(a: &Vec<i32>, n: usize) -> &i32", Edition::Edition2021);
+        assert!(!report.needs_annotations);
+        assert_eq!(report.issues, vec![LifetimeIssue::BorrowsFromInput { input: "a".to_string() }]);
+
+        let report = analyse("fn f(&self, a: &str) -> &str", Edition::Edition2021);
+        assert!(!report.needs_annotations);
+        assert_eq!(report.issues, vec![LifetimeIssue::BorrowsFromInput { input: "self".to_string() }]);
+    }
+
+    #[test]
+    fn test_named_and_bound_lifetimes() {
+        assert_eq!(analyse("fn f<'a>(a: &'a str, b: &'a str) -> &'a str", Edition::Edition2021), LifetimeReport::default());
+        assert_eq!(analyse("fn f(a: &'static str, b: i32) -> &'static str", Edition::Edition2021), LifetimeReport::default());
+        let report = analyse("fn f(g: impl Fn(&str) -> &str, s: &str) -> &str", Edition::Edition2021);
+        assert_eq!(report.issues, vec![LifetimeIssue::BorrowsFromInput { input: "s".to_string() }]);
+    }
+
+    #[test]
+    fn test_impl_trait_capture() {
+        let report = analyse("fn f(v: &[i32]) -> impl Iterator<Item = i32>", Edition::Edition2021);
+        assert!(report.needs_annotations);
+        assert_eq!(report.issues, vec![LifetimeIssue::ImplTraitCapture { inputs: vec!["v".to_string()] }]);
+
+        assert!(!analyse("fn f(v: &[i32]) -> impl Iterator<Item = i32> + '_", Edition::Edition2021).needs_annotations);
+        assert!(!analyse("fn f(v: &[i32]) -> impl Iterator<Item = i32>", Edition::Edition2024).needs_annotations);
+        assert!(!analyse("fn f(v: Vec<i32>) -> impl Iterator<Item = i32>", Edition::Edition2021).needs_annotations);
+    }
+}
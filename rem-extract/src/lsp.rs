@@ -0,0 +1,602 @@
+//! LSP mode: a minimal language server that offers extract method as a code
+//! action, for editors that aren't running a full rust-analyzer.
+//!
+//! The server speaks LSP over stdin/stdout using `lsp-server`, the same
+//! transport as rust-analyzer. It supports:
+//! - `textDocument/didOpen`, `didChange` (full or incremental) and `didClose`,
+//!   which keep the contents of open documents as overlays in the
+//!   `ExtractionSession`, so unsaved changes are extracted from
+//! - `textDocument/codeAction`, which offers "Extract method (REM)" when the
+//!   extract function assist is applicable to the selected range
+//! - `workspace/executeCommand` with `EXTRACT_COMMAND`, whose argument is
+//!   `{"uri", "range", "name"?}`. The server sends the edit to the client in a
+//!   `workspace/applyEdit` request and responds with `null`.
+//!
+//! LSP has no request for asking the user for a name, so the code action's
+//! command has no `name` and the new function is called `DEFAULT_FN_NAME`
+//! (it can then be renamed with the editor). Clients with their own prompt,
+//! e.g. an editor extension, can add the `name` to the command's argument.
+//!
+//! Positions are UTF-16 based, the LSP default. A request that panics gets an
+//! `InternalError` response, and the server keeps running with the loaded
+//! projects dropped.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+};
+
+use log::info;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use serde_json::Value;
+
+use lsp_server::{
+    Connection,
+    ErrorCode,
+    Message,
+    Notification,
+    Request,
+    RequestId,
+    Response,
+};
+
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument,
+        DidCloseTextDocument,
+        DidOpenTextDocument,
+        Notification as _,
+    },
+    request::{
+        ApplyWorkspaceEdit,
+        CodeActionRequest,
+        ExecuteCommand,
+        Request as _,
+    },
+    ApplyWorkspaceEditParams,
+    ApplyWorkspaceEditResponse,
+    CodeAction,
+    CodeActionKind,
+    CodeActionOptions,
+    CodeActionOrCommand,
+    CodeActionParams,
+    CodeActionProviderCapability,
+    Command,
+    DidChangeTextDocumentParams,
+    DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams,
+    ExecuteCommandOptions,
+    ExecuteCommandParams,
+    Position,
+    Range,
+    ServerCapabilities,
+    TextDocumentSyncCapability,
+    TextDocumentSyncKind,
+    TextEdit,
+    Url,
+    WorkspaceEdit,
+};
+
+use ra_ap_ide::{
+    LineCol,
+    LineIndex,
+};
+
+use ra_ap_ide_db::line_index::{
+    WideEncoding,
+    WideLineCol,
+};
+
+use crate::{
+    error::{
+        panic_message,
+        ExtractionError,
+    },
+    extraction::{
+        ExtractionInput,
+        ExtractionOutput,
+    },
+    session::ExtractionSession,
+};
+
+/// The command run by the code action
+pub const EXTRACT_COMMAND: &str = "rem.extractMethod";
+
+/// The title of the code action
+pub const CODE_ACTION_TITLE: &str = "Extract method (REM)";
+
+/// The name used when the command is not given one
+const DEFAULT_FN_NAME: &str = "fun_name";
+
+/// The argument of `EXTRACT_COMMAND`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ExtractCommandArgs {
+    pub uri: Url,
+    pub range: Range,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// An error response
+struct LspError {
+    code: i32,
+    message: String,
+}
+
+impl LspError {
+    fn new( code: ErrorCode, message: impl Into<String> ) -> Self {
+        LspError { code: code as i32, message: message.into() }
+    }
+}
+
+impl From<ExtractionError> for LspError {
+    fn from( error: ExtractionError ) -> Self {
+        LspError::new( ErrorCode::RequestFailed, error.to_string() )
+    }
+}
+
+impl From<serde_json::Error> for LspError {
+    fn from( error: serde_json::Error ) -> Self {
+        LspError::new( ErrorCode::InternalError, error.to_string() )
+    }
+}
+
+/// Requests from the server to the client, queued while a request is handled
+/// and sent before its response
+#[derive(Default)]
+struct ClientRequests {
+    next_id: i32,
+    queued: Vec<Request>,
+}
+
+impl ClientRequests {
+    /// Queues a `workspace/applyEdit` request
+    fn apply_edit( &mut self, label: &str, edit: WorkspaceEdit ) {
+        self.next_id += 1;
+        let params: ApplyWorkspaceEditParams = ApplyWorkspaceEditParams {
+            label: Some( label.to_string() ),
+            edit,
+        };
+        self.queued.push( Request::new( self.next_id.into(), ApplyWorkspaceEdit::METHOD.to_string(), params ) );
+    }
+
+    fn take( &mut self ) -> Vec<Request> {
+        std::mem::take( &mut self.queued )
+    }
+}
+
+/// Runs the server on stdin/stdout until the client shuts it down
+pub fn run_stdio( session: &mut ExtractionSession ) -> io::Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    serve_lsp( session, &connection )?;
+    drop( connection );
+    io_threads.join()
+}
+
+/// Runs the initialize handshake and serves messages from the connection
+/// until the client sends `shutdown` and `exit`
+pub fn serve_lsp( session: &mut ExtractionSession, connection: &Connection ) -> io::Result<()> {
+    let capabilities: Value = serde_json::to_value( server_capabilities() )?;
+    connection.initialize( capabilities ).map_err( io::Error::other )?;
+    info!("LSP server initialized");
+
+    // Contents of the open documents, keyed by URI
+    let mut documents: HashMap<Url, String> = HashMap::new();
+    let mut client_requests: ClientRequests = ClientRequests::default();
+
+    for message in &connection.receiver {
+        match message {
+            Message::Request( request ) => {
+                if connection.handle_shutdown( &request ).map_err( io::Error::other )? {
+                    info!("Shutting down");
+                    return Ok(());
+                }
+                let id: RequestId = request.id.clone();
+                let response: Response = catch_panic( session, &mut client_requests, id, |session, client_requests| {
+                    handle_request( session, &documents, client_requests, request )
+                });
+                for request in client_requests.take() {
+                    connection.sender.send( Message::Request( request ) ).map_err( io::Error::other )?;
+                }
+                connection.sender.send( Message::Response( response ) ).map_err( io::Error::other )?;
+            },
+            Message::Notification( notification ) => {
+                handle_notification( session, &mut documents, notification );
+            },
+            Message::Response( response ) => handle_response( response ),
+        }
+    }
+
+    Ok(())
+}
+
+fn server_capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some( TextDocumentSyncCapability::Kind( TextDocumentSyncKind::FULL ) ),
+        code_action_provider: Some( CodeActionProviderCapability::Options( CodeActionOptions {
+            code_action_kinds: Some( vec![CodeActionKind::REFACTOR_EXTRACT] ),
+            work_done_progress_options: Default::default(),
+            resolve_provider: None,
+        } ) ),
+        execute_command_provider: Some( ExecuteCommandOptions {
+            commands: vec![EXTRACT_COMMAND.to_string()],
+            work_done_progress_options: Default::default(),
+        } ),
+        ..Default::default()
+    }
+}
+
+/// Runs `handle`, answering the request `id` with an internal error if it
+/// panics. The requests it queued are dropped, and so are the loaded
+/// projects, as the panic may have left them inconsistent.
+fn catch_panic(
+    session: &mut ExtractionSession,
+    client_requests: &mut ClientRequests,
+    id: RequestId,
+    handle: impl FnOnce( &mut ExtractionSession, &mut ClientRequests ) -> Response,
+) -> Response {
+    let payload = match panic::catch_unwind( AssertUnwindSafe(|| handle( &mut *session, &mut *client_requests )) ) {
+        Ok( response ) => return response,
+        Err( payload ) => payload,
+    };
+    let message: String = panic_message( payload.as_ref() );
+    info!("Request {} panicked: {}", id, message);
+    session.drop_projects();
+    client_requests.take();
+    Response::new_err( id, ErrorCode::InternalError as i32, format!( "Internal error: {}", message ) )
+}
+
+fn handle_request(
+    session: &mut ExtractionSession,
+    documents: &HashMap<Url, String>,
+    client_requests: &mut ClientRequests,
+    request: Request,
+) -> Response {
+    let id: RequestId = request.id.clone();
+    info!("Request {}: {}", id, request.method);
+
+    let result: Result<Value, LspError> = match request.method.as_str() {
+        CodeActionRequest::METHOD => parse_params::<CodeActionParams>( request.params )
+            .and_then(|params| code_actions( session, documents, params )),
+        ExecuteCommand::METHOD => parse_params::<ExecuteCommandParams>( request.params )
+            .and_then(|params| execute_command( session, documents, client_requests, params )),
+        method => Err( LspError::new( ErrorCode::MethodNotFound, format!( "Unknown method `{}`", method ) ) ),
+    };
+
+    match result {
+        Ok( result ) => Response::new_ok( id, result ),
+        Err( error ) => Response::new_err( id, error.code, error.message ),
+    }
+}
+
+/// Logs the edits that the client did not apply
+fn handle_response( response: Response ) {
+    if let Some( error ) = response.error {
+        info!("Request {} failed: {}", response.id, error.message);
+        return;
+    }
+    let applied: Option<ApplyWorkspaceEditResponse> = response.result
+        .and_then(|result| serde_json::from_value( result ).ok());
+    if let Some( ApplyWorkspaceEditResponse { applied: false, failure_reason, .. } ) = applied {
+        info!("Edit {} not applied: {}", response.id, failure_reason.unwrap_or_default());
+    }
+}
+
+fn handle_notification(
+    session: &mut ExtractionSession,
+    documents: &mut HashMap<Url, String>,
+    notification: Notification,
+) {
+    let changed: Option<(Url, Option<String>)> = match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => serde_json::from_value::<DidOpenTextDocumentParams>( notification.params )
+            .ok()
+            .map(|params| {
+                let document = params.text_document;
+                documents.insert( document.uri.clone(), document.text.clone() );
+                ( document.uri, Some( document.text ) )
+            }),
+        DidChangeTextDocument::METHOD => serde_json::from_value::<DidChangeTextDocumentParams>( notification.params )
+            .ok()
+            .and_then(|params| {
+                let uri: Url = params.text_document.uri;
+                let text: &mut String = documents.get_mut( &uri )?;
+                for change in params.content_changes {
+                    match change.range.and_then(|range| byte_range( text, range )) {
+                        Some( (start, end) ) => text.replace_range( start as usize..end as usize, &change.text ),
+                        None => *text = change.text,
+                    }
+                }
+                Some( (uri, Some( text.clone() )) )
+            }),
+        DidCloseTextDocument::METHOD => serde_json::from_value::<DidCloseTextDocumentParams>( notification.params )
+            .ok()
+            .map(|params| {
+                documents.remove( &params.text_document.uri );
+                ( params.text_document.uri, None )
+            }),
+        _ => None,
+    };
+
+    // Closing a document drops its overlay, going back to the file on disk
+    if let Some( (uri, text) ) = changed {
+        let Some( path ) = file_path( &uri ) else {
+            return;
+        };
+        if let Err( e ) = session.update_file( &path, text ) {
+            info!("Failed to update {}: {}", path, e);
+        }
+    }
+}
+
+/// Offers the code action if the extract function assist is applicable to
+/// the range. The reason it isn't applicable is only logged.
+fn code_actions(
+    session: &mut ExtractionSession,
+    documents: &HashMap<Url, String>,
+    params: CodeActionParams,
+) -> Result<Value, LspError> {
+    let actions: Vec<CodeActionOrCommand> = vec![];
+
+    let wanted: bool = match &params.context.only {
+        Some( only ) => only.iter().any(|kind| CodeActionKind::REFACTOR_EXTRACT.as_str().starts_with( kind.as_str() )),
+        None => true,
+    };
+    if !wanted || params.range.start == params.range.end {
+        return Ok( serde_json::to_value( actions )? );
+    }
+
+    let uri: Url = params.text_document.uri;
+    let (path, text) = document( documents, &uri )?;
+    let (start, end) = byte_range( &text, params.range )
+        .ok_or_else(|| LspError::new( ErrorCode::InvalidParams, "Invalid range" ))?;
+
+    let input: ExtractionInput = ExtractionInput::new( &path, DEFAULT_FN_NAME, start, end );
+    if let Err( e ) = session.check_applicable( &input ) {
+        info!("Extraction not applicable: {}", e);
+        return Ok( serde_json::to_value( actions )? );
+    }
+
+    let args: ExtractCommandArgs = ExtractCommandArgs { uri, range: params.range, name: None };
+    let action: CodeAction = CodeAction {
+        title: CODE_ACTION_TITLE.to_string(),
+        kind: Some( CodeActionKind::REFACTOR_EXTRACT ),
+        command: Some( Command::new(
+            CODE_ACTION_TITLE.to_string(),
+            EXTRACT_COMMAND.to_string(),
+            Some( vec![serde_json::to_value( args )?] ),
+        ) ),
+        ..Default::default()
+    };
+    Ok( serde_json::to_value( vec![CodeActionOrCommand::CodeAction( action )] )? )
+}
+
+/// Runs the extraction and asks the client to apply the edit to the document
+fn execute_command(
+    session: &mut ExtractionSession,
+    documents: &HashMap<Url, String>,
+    client_requests: &mut ClientRequests,
+    params: ExecuteCommandParams,
+) -> Result<Value, LspError> {
+    if params.command != EXTRACT_COMMAND {
+        return Err( LspError::new( ErrorCode::InvalidParams, format!( "Unknown command `{}`", params.command ) ) );
+    }
+    let args: ExtractCommandArgs = params.arguments
+        .into_iter()
+        .next()
+        .ok_or_else(|| LspError::new( ErrorCode::InvalidParams, "Missing command argument" ))
+        .and_then( parse_params )?;
+
+    let (path, text) = document( documents, &args.uri )?;
+    let (start, end) = byte_range( &text, args.range )
+        .ok_or_else(|| LspError::new( ErrorCode::InvalidParams, "Invalid range" ))?;
+    let name: &str = args.name.as_deref().unwrap_or( DEFAULT_FN_NAME );
+
+    let output: ExtractionOutput = session.extract( ExtractionInput::new( &path, name, start, end ) )?;
+    client_requests.apply_edit( CODE_ACTION_TITLE, workspace_edit( &args.uri, &text, &output ) );
+    Ok( Value::Null )
+}
+
+/// Converts the byte ranges of the edits to LSP ranges in `text`, the document
+/// the extraction ran on
+fn workspace_edit( uri: &Url, text: &str, output: &ExtractionOutput ) -> WorkspaceEdit {
+    let edits: Vec<TextEdit> = output.edits
+        .iter()
+        .map(|edit| TextEdit::new(
+            Range::new( position( text, edit.range.0 ), position( text, edit.range.1 ) ),
+            edit.new_text.clone(),
+        ))
+        .collect();
+    WorkspaceEdit::new( HashMap::from( [(uri.clone(), edits)] ) )
+}
+
+/// The path and contents of a document, read from disk if it isn't open
+fn document( documents: &HashMap<Url, String>, uri: &Url ) -> Result<(String, String), LspError> {
+    let path: String = file_path( uri )
+        .ok_or_else(|| LspError::new( ErrorCode::InvalidParams, format!( "Not a file URI: {}", uri ) ))?;
+    let text: String = match documents.get( uri ) {
+        Some( text ) => text.clone(),
+        None => fs::read_to_string( &path ).map_err( ExtractionError::Io )?,
+    };
+    Ok( (path, text) )
+}
+
+fn file_path( uri: &Url ) -> Option<String> {
+    uri.to_file_path()
+        .ok()
+        .and_then(|path| path.to_str().map( str::to_string ))
+}
+
+/// Converts an LSP range to a byte range. Columns past the end of a line are
+/// clamped to the start of the next line.
+fn byte_range( text: &str, range: Range ) -> Option<(u32, u32)> {
+    let index: LineIndex = LineIndex::new( text );
+    let offset = |position: Position| -> Option<u32> {
+        let line_col: LineCol = index.to_utf8(
+            WideEncoding::Utf16,
+            WideLineCol { line: position.line, col: position.character },
+        )?;
+        let line = index.line( line_col.line )?;
+        let col: u32 = line_col.col.min( u32::from( line.len() ) );
+        Some( u32::from( line.start() ) + col )
+    };
+    let (start, end) = ( offset( range.start )?, offset( range.end )? );
+    ( start <= end ).then_some( (start, end) )
+}
+
+/// Converts a byte offset to an LSP position
+fn position( text: &str, offset: u32 ) -> Position {
+    let index: LineIndex = LineIndex::new( text );
+    let offset: u32 = offset.min( text.len() as u32 );
+    let line_col: LineCol = index.line_col( offset.into() );
+    match index.to_wide( WideEncoding::Utf16, line_col ) {
+        Some( wide ) => Position::new( wide.line, wide.col ),
+        None => Position::new( line_col.line, line_col.col ),
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>( params: Value ) -> Result<T, LspError> {
+    serde_json::from_value( params ).map_err(|e| LspError::new( ErrorCode::InvalidParams, e.to_string() ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::LoadOptions;
+    use crate::extraction_utils::test_project::TestProject;
+    use serde_json::json;
+    use std::thread;
+
+    #[test]
+    fn test_byte_range_and_position() {
+        let text = "fn main() {\n    let s = \"é\"; foo();\n}\n";
+        // `foo` starts at UTF-16 column 17 of line 1, but byte 30
+        let range = Range::new(Position::new(1, 17), Position::new(1, 22));
+        assert_eq!(byte_range(text, range), Some((30, 35)));
+        assert_eq!(position(text, 30), Position::new(1, 17));
+        assert_eq!(byte_range(text, Range::new(Position::new(1, 0), Position::new(0, 0))), None);
+    }
+
+    #[test]
+    fn test_protocol() {
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || {
+            let mut session = ExtractionSession::new(LoadOptions::default());
+            serve_lsp(&mut session, &server)
+        });
+
+        let request = |id: i32, method: &str, params: Value| {
+            client.sender.send(Message::Request(Request::new(id.into(), method.to_string(), params))).unwrap();
+            match client.receiver.recv().unwrap() {
+                Message::Response(response) => response,
+                message => panic!("Unexpected message {:?}", message),
+            }
+        };
+        let notify = |method: &str, params: Value| {
+            client.sender.send(Message::Notification(Notification::new(method.to_string(), params))).unwrap();
+        };
+
+        let initialize = request(1, "initialize", json!({ "capabilities": {} }));
+        let capabilities = &initialize.result.unwrap()["capabilities"];
+        assert_eq!(capabilities["executeCommandProvider"]["commands"], json!([EXTRACT_COMMAND]));
+        notify("initialized", json!({}));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let uri = Url::from_file_path(dir.path().join("src").join("main.rs")).unwrap();
+        notify(DidOpenTextDocument::METHOD, json!({
+            "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": "fn main() {\n    foo();\n}\n" }
+        }));
+
+        // The project can't be loaded, so no action is offered
+        let actions = request(2, CodeActionRequest::METHOD, json!({
+            "textDocument": { "uri": uri },
+            "range": { "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 10 } },
+            "context": { "diagnostics": [] },
+        }));
+        assert_eq!(actions.result, Some(json!([])));
+
+        let unknown = request(3, ExecuteCommand::METHOD, json!({ "command": "frobnicate", "arguments": [] }));
+        assert_eq!(unknown.error.unwrap().code, ErrorCode::InvalidParams as i32);
+
+        let shutdown = request(4, "shutdown", Value::Null);
+        assert!(shutdown.error.is_none());
+        notify("exit", Value::Null);
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_panics_are_internal_errors() {
+        let mut session = ExtractionSession::new(LoadOptions::default());
+        let mut client_requests = ClientRequests::default();
+        let response = catch_panic(&mut session, &mut client_requests, 3.into(), |_, client_requests| {
+            client_requests.apply_edit(CODE_ACTION_TITLE, WorkspaceEdit::default());
+            panic!("boom")
+        });
+        assert_eq!(response.id, 3.into());
+        let error = response.error.unwrap();
+        assert_eq!(error.code, ErrorCode::InternalError as i32);
+        assert!(error.message.contains("boom"));
+        // The edit queued before the panic is not sent
+        assert!(client_requests.take().is_empty());
+    }
+
+    #[test]
+    fn test_execute_command_applies_edit() {
+        let project = TestProject::new("lsp_test", &[("src/main.rs", "fn main() {\n    let x = 1;\n    println!(\"{}\", x);\n}\n")]);
+        let uri = Url::from_file_path(project.path("src/main.rs")).unwrap();
+
+        let (server, client) = Connection::memory();
+        let handle = thread::spawn(move || {
+            let mut session = ExtractionSession::new(LoadOptions::default());
+            serve_lsp(&mut session, &server)
+        });
+        let send = |message: Message| client.sender.send(message).unwrap();
+
+        send(Message::Request(Request::new(1.into(), "initialize".to_string(), json!({ "capabilities": {} }))));
+        assert!(matches!(client.receiver.recv().unwrap(), Message::Response(_)));
+        send(Message::Notification(Notification::new("initialized".to_string(), json!({}))));
+
+        let args = ExtractCommandArgs {
+            uri: uri.clone(),
+            range: Range::new(Position::new(1, 4), Position::new(1, 14)),
+            name: Some("make_x".to_string()),
+        };
+        send(Message::Request(Request::new(2.into(), ExecuteCommand::METHOD.to_string(), json!({
+            "command": EXTRACT_COMMAND,
+            "arguments": [args],
+        }))));
+
+        // The edit is sent to the client before the response
+        let apply_edit = match client.receiver.recv().unwrap() {
+            Message::Request(request) => request,
+            message => panic!("Unexpected message {:?}", message),
+        };
+        assert_eq!(apply_edit.method, ApplyWorkspaceEdit::METHOD);
+        let params: ApplyWorkspaceEditParams = serde_json::from_value(apply_edit.params).unwrap();
+        let edits = &params.edit.changes.unwrap()[&uri];
+        assert!(edits.iter().any(|edit| edit.new_text.contains("fn make_x()")));
+        send(Message::Response(Response::new_ok(apply_edit.id, json!({ "applied": true }))));
+
+        match client.receiver.recv().unwrap() {
+            Message::Response(response) => {
+                assert_eq!(response.id, 2.into());
+                assert_eq!(response.result, Some(Value::Null));
+            },
+            message => panic!("Unexpected message {:?}", message),
+        }
+
+        send(Message::Request(Request::new(3.into(), "shutdown".to_string(), Value::Null)));
+        assert!(matches!(client.receiver.recv().unwrap(), Message::Response(_)));
+        send(Message::Notification(Notification::new("exit".to_string(), Value::Null)));
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -4,8 +4,11 @@ mod extraction_utils;
 
 mod extraction;
 use extraction::{
-    extract_method,
-    ExtractionInput
+    extract_method_configured,
+    ExtractionInput,
+    ExtractionOutput,
+    LoadOptions,
+    ProgressEvent,
 };
 
 use log::{
@@ -15,6 +18,8 @@ use log::{
 
 mod args;
 use args::{
+    CacheCommands,
+    OutputFormat,
     EXTRACTArgs,
     EXTRACTCommands
 };
@@ -27,16 +32,98 @@ use extract_tests::{
     test_spammy,
 };
 
+mod output;
+mod session;
+use session::ExtractionSession;
+mod batch;
+use batch::{
+    run_batch,
+    BatchSummary,
+};
+mod serve;
+mod lsp;
+mod verify;
+mod sandbox;
+mod differential;
+mod drop_order;
+mod lifetimes;
+mod placeholders;
+mod round_trip;
+mod inline;
+use inline::{
+    inline_function,
+    InlineInput,
+    InlineOutput,
+    InlineTarget,
+};
+mod warnings;
+use differential::DifferentialMode;
 mod error;
+use error::ErrorClass;
+mod cache;
+mod diagnostic;
 
 use clap::Parser;
 
-fn main() {
+use std::{
+    fs::File,
+    io::{
+        BufRead,
+        BufReader,
+        IsTerminal,
+    },
+    path::PathBuf,
+    process::ExitCode,
+};
+
+/// Exit code of `rem-extract test` when any test fails, and of `rem-extract
+/// batch` when any request fails. The exit codes for extraction errors are
+/// given by `ErrorClass::exit_code`.
+const TEST_FAILURE_EXIT_CODE: u8 = 5;
+
+/// Prints an error (to stderr, or as JSON on stdout) and returns the exit code
+/// for its class
+fn report_error(e: &error::ExtractionError, format: OutputFormat) -> ExitCode {
+    info!("Error: {}", e);
+    match format {
+        OutputFormat::Text => {
+            eprintln!("{}", e.render(std::io::stderr().is_terminal()));
+            // The flag only exists in the CLI, so the hint isn't in the message
+            if matches!(e, error::ExtractionError::SyntaxErrors(_)) {
+                eprintln!("  = help: pass --allow-errors to extract anyway");
+            }
+        },
+        OutputFormat::Json => println!("{}", e.to_json()),
+    }
+    ExitCode::from(e.class().exit_code())
+}
+
+/// Logs every progress event, and prints it to stderr if `--progress` is set
+fn report_progress(event: ProgressEvent, print: bool) {
+    let message: String = match event {
+        ProgressEvent::Started(phase) => format!("{}...", phase.description()),
+        ProgressEvent::Report(_, message) => format!("  {}", message),
+        ProgressEvent::Finished(phase, elapsed) => format!("{} done in {:.2?}", phase.description(), elapsed),
+    };
+    info!("{}", message);
+    if print {
+        eprintln!("{}", message);
+    }
+}
+
+fn main() -> ExitCode {
+    // A panic (e.g. inside rust-analyzer) is an internal error. The default
+    // hook still prints its message, but the exit code is 1 rather than 101.
+    std::panic::catch_unwind(run).unwrap_or_else(|_| ExitCode::from(ErrorClass::Internal.exit_code()))
+}
+
+fn run() -> ExitCode {
     logging::init_logging();
 
     info!("Application Started");
 
     let args: EXTRACTArgs = EXTRACTArgs::parse();
+    let quiet: bool = args.quiet;
 
     match &args.command {
         EXTRACTCommands::Extract {
@@ -45,6 +132,14 @@ fn main() {
             start_index,
             end_index,
             verbose,
+            load,
+            checks,
+            progress,
+            format,
+            in_place,
+            backup,
+            output: output_path,
+            diff,
         } => {
             info!("Running 'run' subcommand");
             info!("File Path: {:?}", file_path);
@@ -52,46 +147,330 @@ fn main() {
             info!("Start Index: {}", start_index);
             info!("End Index: {}", end_index);
             info!("Verbose: {}", if *verbose { "yes" } else { "no" });
+            info!("Load Options: {:?}", load);
+            info!("Checks: {:?}", checks);
+
+            let load_options: LoadOptions = load.load_options();
 
             let input = ExtractionInput::new(
-                file_path.to_str().unwrap(),
+                &file_path.to_string_lossy(),
                 new_fn_name,
                 *start_index as u32,
                 *end_index as u32,
             );
 
-            let extraction_output: Result<(String, String), error::ExtractionError> = extract_method(input);
-            let (output_code, _caller_method) = match extraction_output {
-                Ok((output_code, caller_method)) => {
-                    info!("Output Code: {}", output_code);
-                    info!("Caller Method: {}", caller_method);
-                    (output_code, caller_method)
+            let extraction_output: Result<ExtractionOutput, error::ExtractionError> = extract_method_configured(
+                input,
+                &load_options,
+                &checks.extraction_options(),
+                &|event| report_progress(event, *progress && !quiet),
+            );
+            let extraction: ExtractionOutput = match extraction_output {
+                Ok(output) => {
+                    info!("Output Code: {}", output.code);
+                    info!("Caller Method: {}", output.parent_method);
+                    output
                 },
-                Err(e) => {
-                    info!("Error: {}", e);
-                    return;
+                Err(e) => return report_error(&e, *format),
+            };
+
+            // Read the original before `--in-place` overwrites it
+            let original: Option<String> = if *diff && *format == OutputFormat::Text {
+                match std::fs::read_to_string(file_path) {
+                    Ok(original) => Some(original),
+                    Err(e) => return report_error(&error::ExtractionError::Io(e), *format),
+                }
+            } else {
+                None
+            };
+
+            // Write the modified code to a file if asked to, otherwise it is
+            // printed on stdout
+            let destination: Option<&PathBuf> = if *in_place { Some(file_path) } else { output_path.as_ref() };
+            if let Some(destination) = destination {
+                let written: std::io::Result<()> = (|| {
+                    if *backup {
+                        std::fs::copy(file_path, output::backup_path(file_path))?;
+                    }
+                    output::write_atomic(destination, &extraction.code)
+                })();
+                if let Err(e) = written {
+                    return report_error(&error::ExtractionError::InternalIo(e), *format);
+                }
+                info!("Wrote output to {}", destination.display());
+            }
+
+            match format {
+                OutputFormat::Text => {
+                    if !quiet {
+                        for warning in &extraction.warnings {
+                            eprintln!("warning[{}]: {}", warning.code, warning.message);
+                        }
+                        if let Some(build) = &extraction.build {
+                            for build_error in &build.errors {
+                                if let Some(rendered) = &build_error.rendered {
+                                    eprint!("{}", rendered);
+                                }
+                            }
+                            if let Some(stderr) = &build.cargo_stderr {
+                                eprintln!("{}", stderr);
+                            }
+                        }
+                    }
+                    if let Some(original) = &original {
+                        let diff: String = output::unified_diff(
+                            &file_path.to_string_lossy(),
+                            original,
+                            &extraction.code,
+                            std::io::stdout().is_terminal(),
+                        );
+                        if !diff.is_empty() {
+                            println!("{}", diff);
+                        }
+                    } else if destination.is_none() {
+                        println!("{}", extraction.code);
+                    }
+                    if !quiet {
+                        println!("Extraction Successful");
+                    }
+                },
+                OutputFormat::Json => println!("{}", extraction.to_json()),
+            }
+            ExitCode::SUCCESS
+        }
+
+        EXTRACTCommands::Inline {
+            file_path,
+            function,
+            offset,
+            load,
+            verify,
+            progress,
+            format,
+            in_place,
+            backup,
+            output: output_path,
+            diff,
+        } => {
+            info!("Running 'inline' subcommand");
+            info!("File Path: {:?}", file_path);
+            info!("Function: {:?}", function);
+            info!("Offset: {:?}", offset);
+            info!("Load Options: {:?}", load);
+
+            let target: InlineTarget = match (function, offset) {
+                (Some(function), None) => InlineTarget::Function(function.clone()),
+                (None, Some(offset)) => InlineTarget::CallSite(*offset),
+                _ => {
+                    eprintln!("Exactly one of --function and --offset must be given");
+                    return ExitCode::from(ErrorClass::BadInput.exit_code());
+                }
+            };
+            let input: InlineInput = InlineInput::new(&file_path.to_string_lossy(), target);
+            let extraction_options: extraction::ExtractionOptions = extraction::ExtractionOptions {
+                verify: *verify,
+                ..Default::default()
+            };
+
+            let inlined: InlineOutput = match inline_function(
+                input,
+                &load.load_options(),
+                &extraction_options,
+                &|event| report_progress(event, *progress && !quiet),
+            ) {
+                Ok(output) => output,
+                Err(e) => return report_error(&e, *format),
+            };
+
+            // Read the original before `--in-place` overwrites it
+            let original: Option<String> = if *diff && *format == OutputFormat::Text {
+                match std::fs::read_to_string(file_path) {
+                    Ok(original) => Some(original),
+                    Err(e) => return report_error(&error::ExtractionError::Io(e), *format),
                 }
+            } else {
+                None
             };
 
-            println!("{}", output_code);
-            println!("Extraction Successful");
+            let destination: Option<&PathBuf> = if *in_place { Some(file_path) } else { output_path.as_ref() };
+            if let Some(destination) = destination {
+                let written: std::io::Result<()> = (|| {
+                    if *backup {
+                        std::fs::copy(file_path, output::backup_path(file_path))?;
+                    }
+                    output::write_atomic(destination, &inlined.code)?;
+                    // The calls in other files are only written back in place
+                    if *in_place {
+                        for other in &inlined.other_files {
+                            let other_path: PathBuf = PathBuf::from(&other.file_path);
+                            if *backup {
+                                std::fs::copy(&other_path, output::backup_path(&other_path))?;
+                            }
+                            output::write_atomic(&other_path, &other.code)?;
+                        }
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = written {
+                    return report_error(&error::ExtractionError::InternalIo(e), *format);
+                }
+                info!("Wrote output to {}", destination.display());
+            }
+
+            match format {
+                OutputFormat::Text => {
+                    if !quiet {
+                        for warning in &inlined.warnings {
+                            eprintln!("warning[{}]: {}", warning.code, warning.message);
+                        }
+                        if !*in_place {
+                            for other in &inlined.other_files {
+                                eprintln!("note: calls in {} were inlined too (pass --in-place to write them)", other.file_path);
+                            }
+                        }
+                    }
+                    if let Some(original) = &original {
+                        let diff: String = output::unified_diff(
+                            &file_path.to_string_lossy(),
+                            original,
+                            &inlined.code,
+                            std::io::stdout().is_terminal(),
+                        );
+                        if !diff.is_empty() {
+                            println!("{}", diff);
+                        }
+                    } else if destination.is_none() {
+                        println!("{}", inlined.code);
+                    }
+                    if !quiet {
+                        println!("Inlining Successful");
+                    }
+                },
+                OutputFormat::Json => println!("{}", inlined.to_json()),
+            }
+            ExitCode::SUCCESS
         }
 
         EXTRACTCommands::Test {
             verbose,
-            spammy
+            spammy,
+            differential,
         } => {
-            if verbose.clone() || spammy.clone() {assert_ne!(verbose.clone(), spammy.clone(), "Verbose and Spammy cannot be run at the same time");}
+            if *verbose && *spammy {
+                eprintln!("Verbose and Spammy cannot be run at the same time");
+                return ExitCode::from(ErrorClass::BadInput.exit_code());
+            }
+            if (*verbose || *spammy) && *differential != DifferentialMode::Off {
+                eprintln!("Differential mode cannot be combined with Verbose or Spammy");
+                return ExitCode::from(ErrorClass::BadInput.exit_code());
+            }
             info!("Running 'test' subcommand");
             info!("Verbose: {}", if *verbose { "yes" } else { "no" });
-            if *verbose {
-                test_verbose();
+            let failed_tests: i32 = if *verbose {
+                test_verbose()
             } else if *spammy {
-                test_spammy();
+                test_spammy()
             }else {
-                test();
+                test(*differential)
+            };
+
+            if failed_tests > 0 {
+                ExitCode::from(TEST_FAILURE_EXIT_CODE)
+            } else {
+                ExitCode::SUCCESS
             }
+        }
+
+        EXTRACTCommands::Batch {
+            requests_path,
+            load,
+            checks,
+            progress,
+        } => {
+            info!("Running 'batch' subcommand");
+            info!("Requests: {:?}", requests_path);
+            info!("Load Options: {:?}", load);
+
+            let requests: Box<dyn BufRead> = if requests_path.as_os_str() == "-" {
+                Box::new(std::io::stdin().lock())
+            } else {
+                match File::open(requests_path) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => return report_error(&error::ExtractionError::Io(e), OutputFormat::Text),
+                }
+            };
 
+            let mut session: ExtractionSession = ExtractionSession::with_options(load.load_options(), checks.extraction_options());
+            let summary: std::io::Result<BatchSummary> = run_batch(
+                &mut session,
+                requests,
+                std::io::stdout().lock(),
+                &|event| report_progress(event, *progress && !quiet),
+            );
+            match summary {
+                Ok(summary) => {
+                    info!("Batch finished: {:?}", summary);
+                    if !quiet {
+                        eprintln!("{} succeeded, {} failed", summary.succeeded, summary.failed);
+                    }
+                    if summary.failed > 0 {
+                        ExitCode::from(TEST_FAILURE_EXIT_CODE)
+                    } else {
+                        ExitCode::SUCCESS
+                    }
+                },
+                Err(e) => report_error(&error::ExtractionError::InternalIo(e), OutputFormat::Text),
+            }
+        }
+
+        EXTRACTCommands::Serve { load, checks } => {
+            info!("Running 'serve' subcommand");
+            info!("Load Options: {:?}", load);
+
+            let mut session: ExtractionSession = ExtractionSession::with_options(load.load_options(), checks.extraction_options());
+            match serve::serve(&mut session, std::io::stdin().lock(), std::io::stdout().lock()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => report_error(&error::ExtractionError::InternalIo(e), OutputFormat::Text),
+            }
+        }
+
+        EXTRACTCommands::Lsp { load, checks } => {
+            info!("Running 'lsp' subcommand");
+            info!("Load Options: {:?}", load);
+
+            let mut session: ExtractionSession = ExtractionSession::with_options(load.load_options(), checks.extraction_options());
+            match lsp::run_stdio(&mut session) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => report_error(&error::ExtractionError::InternalIo(e), OutputFormat::Text),
+            }
+        }
+
+        EXTRACTCommands::Cache { command } => {
+            match command {
+                CacheCommands::Clear { manifest_path } => {
+                    info!("Running 'cache clear' subcommand");
+                    let manifest_path = manifest_path
+                        .as_ref()
+                        .map(|path| path.canonicalize().unwrap_or(path.clone()));
+                    match cache::clear(manifest_path.as_deref()) {
+                        Ok(removed) => {
+                            if !quiet {
+                                println!("Removed {} cache entries", removed);
+                            }
+                            ExitCode::SUCCESS
+                        },
+                        Err(e) => {
+                            info!("Error: {}", e);
+                            eprintln!("Failed to clear the cache: {}", e);
+                            ExitCode::from(ErrorClass::Internal.exit_code())
+                        }
+                    }
+                }
+                CacheCommands::Dir => {
+                    println!("{}", cache::cache_dir().display());
+                    ExitCode::SUCCESS
+                }
+            }
         }
 
     }
@@ -10,4 +10,13 @@ Utilises:
 
 Used in:
     - rem-cli: git= https://github.com/RuleBrittonica/rem-cli
+
+Exit codes:
+    0 - success
+    1 - internal error (including a panic)
+    2 - bad input (e.g. an invalid range or function name)
+    3 - the project could not be loaded
+    4 - the selection cannot be extracted
+    5 - one or more tests failed (`test` subcommand), or one or more
+        requests failed (`batch` subcommand)
 "#;
@@ -0,0 +1,186 @@
+//! Writing the result of an extraction: atomic file writes for `--in-place`
+//! and `--output`, and the unified diff printed by `--diff`.
+
+use std::{
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use colored::Colorize;
+
+/// Lines of unchanged context around each hunk of the diff
+const DIFF_CONTEXT: usize = 3;
+
+/// Writes `contents` to `path` by writing a temporary file in the same
+/// directory and renaming it over `path`, so the file is never left half
+/// written. The permissions of an existing file are kept.
+pub fn write_atomic( path: &Path, contents: &str ) -> io::Result<()> {
+    let dir: &Path = match path.parent() {
+        Some( parent ) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new( "." ),
+    };
+    let file_name: String = path
+        .file_name()
+        .map_or( "output".to_string(), |name| name.to_string_lossy().to_string() );
+    let tmp_path: PathBuf = dir.join( format!( ".{}.{}.tmp", file_name, std::process::id() ) );
+
+    let result: io::Result<()> = (|| {
+        let mut file: fs::File = fs::File::create( &tmp_path )?;
+        file.write_all( contents.as_bytes() )?;
+        file.sync_all()?;
+        if let Ok( metadata ) = fs::metadata( path ) {
+            fs::set_permissions( &tmp_path, metadata.permissions() )?;
+        }
+        fs::rename( &tmp_path, path )
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file( &tmp_path );
+    }
+    result
+}
+
+/// Returns the path of the backup of `path` made by `--backup`
+pub fn backup_path( path: &Path ) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push( ".bak" );
+    PathBuf::from( backup )
+}
+
+/// Renders a unified diff between the original and the modified file, with
+/// `DIFF_CONTEXT` lines of context around each change
+pub fn unified_diff(
+    path: &str,
+    original: &str,
+    modified: &str,
+    color: bool,
+) -> String {
+    let lines: Vec<diff::Result<&str>> = diff::lines( original, modified );
+
+    // Line numbers (0-based) in the original and modified file before each
+    // entry of `lines`
+    let mut positions: Vec<(usize, usize)> = Vec::with_capacity( lines.len() );
+    let (mut old_line, mut new_line) = (0, 0);
+    for line in &lines {
+        positions.push( (old_line, new_line) );
+        match line {
+            diff::Result::Left( _ ) => old_line += 1,
+            diff::Result::Right( _ ) => new_line += 1,
+            diff::Result::Both( _, _ ) => {
+                old_line += 1;
+                new_line += 1;
+            },
+        }
+    }
+
+    // Group the changes into hunks of entries, merging hunks whose context
+    // overlaps
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        if matches!( line, diff::Result::Both( _, _ ) ) {
+            continue;
+        }
+        let start: usize = i.saturating_sub( DIFF_CONTEXT );
+        let end: usize = (i + DIFF_CONTEXT + 1).min( lines.len() );
+        match hunks.last_mut() {
+            Some( (_, last_end) ) if start <= *last_end => *last_end = end,
+            _ => hunks.push( (start, end) ),
+        }
+    }
+
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let paint = |text: String, style: fn(&str) -> String| if color { style( &text ) } else { text };
+
+    let mut out: Vec<String> = vec![];
+    out.push( paint( format!( "--- a/{}", path ), |s| s.bold().to_string() ) );
+    out.push( paint( format!( "+++ b/{}", path ), |s| s.bold().to_string() ) );
+
+    for (start, end) in hunks {
+        let entries: &[diff::Result<&str>] = &lines[start..end];
+        let old_count: usize = entries.iter().filter(|l| !matches!( l, diff::Result::Right( _ ) )).count();
+        let new_count: usize = entries.iter().filter(|l| !matches!( l, diff::Result::Left( _ ) )).count();
+        let (old_start, new_start) = positions[start];
+        out.push( paint(
+            format!(
+                "@@ -{} +{} @@",
+                hunk_range( old_start, old_count ),
+                hunk_range( new_start, new_count ),
+            ),
+            |s| s.cyan().to_string(),
+        ) );
+
+        for entry in entries {
+            out.push( match entry {
+                diff::Result::Left( l ) => paint( format!( "-{}", l ), |s| s.red().to_string() ),
+                diff::Result::Right( r ) => paint( format!( "+{}", r ), |s| s.green().to_string() ),
+                diff::Result::Both( b, _ ) => format!( " {}", b ),
+            } );
+        }
+    }
+
+    out.join( "\n" )
+}
+
+/// Formats the range of a hunk header. Empty ranges start at the line before
+/// the change, as in GNU diff.
+fn hunk_range( start: usize, count: usize ) -> String {
+    match count {
+        0 => format!( "{},0", start ),
+        1 => format!( "{}", start + 1 ),
+        _ => format!( "{},{}", start + 1, count ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("main.rs");
+        fs::write(&path, "fn main() {}").unwrap();
+
+        write_atomic(&path, "fn main() { foo() }").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fn main() { foo() }");
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_backup_path() {
+        assert_eq!(backup_path(Path::new("src/main.rs")), PathBuf::from("src/main.rs.bak"));
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let original = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let modified = "a\nb\nc\nd\nE\nf\ng\nh\ni\n";
+        let expected = "\
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -2,7 +2,8 @@
+ b
+ c
+ d
+-e
++E
+ f
+ g
+ h
++i";
+        assert_eq!(unified_diff("src/main.rs", original, modified, false), expected);
+        assert_eq!(unified_diff("src/main.rs", original, original, false), "");
+    }
+}
@@ -0,0 +1,481 @@
+//! Placeholder types (`_`) in the signature of the new function.
+//!
+//! rust-analyzer writes `_` for the types it can't infer, which never compiles
+//! in a function signature. Every placeholder is reported, and depending on
+//! the `PlaceholderMode` the extraction tries to resolve them:
+//!
+//! - from the annotations in the parent method: a parameter takes the type of
+//!   the `let` or parameter that declared the local it is passed, and the
+//!   return type the type of a `let` initialised with the call;
+//! - from rustc: `cargo check` runs on the result in a sandbox (see
+//!   `sandbox`), and the types rustc suggests for placeholders (E0121) are
+//!   used. rustc can usually infer return types, but not parameter types.
+//!
+//! Term search is not used, as it builds expressions of a known type rather
+//! than finding the type of an expression.
+
+use std::collections::HashMap;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use ra_ap_parser::Edition;
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    SourceFile,
+    TextRange,
+    TextSize,
+};
+
+use crate::{
+    error::ExtractionError,
+    extraction_utils::{
+        find_call_site,
+        find_fn,
+    },
+    sandbox::{
+        BuildReport,
+        BuildSuggestion,
+    },
+};
+
+/// The rustc error for placeholders in item signatures
+const PLACEHOLDER_ERROR_CODE: &str = "E0121";
+
+/// How hard to try to resolve placeholder types
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceholderMode {
+    /// Only report the placeholders
+    #[default]
+    Off,
+    /// Use the annotations in the parent method
+    Annotations,
+    /// Use the annotations, then the types suggested by `cargo check`
+    Build,
+}
+
+/// Where the type of a placeholder came from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaceholderSource {
+    ParentAnnotation,
+    Rustc,
+}
+
+/// A placeholder type in the signature of the new function
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Placeholder {
+    /// The parameter whose type contains the placeholder, `None` for the
+    /// return type
+    pub param: Option<String>,
+    /// The type that replaced the placeholder, `None` if it is still `_`
+    pub resolved: Option<String>,
+    pub source: Option<PlaceholderSource>,
+}
+
+impl Placeholder {
+    /// Describes the placeholder, e.g. "the type of parameter `x`"
+    pub fn position( &self ) -> String {
+        match &self.param {
+            Some( param ) => format!( "the type of parameter `{}`", param ),
+            None => "the return type".to_string(),
+        }
+    }
+}
+
+/// The code with the resolved placeholders replaced, and every placeholder
+pub(crate) struct PlaceholderResolution {
+    pub(crate) code: String,
+    pub(crate) placeholders: Vec<Placeholder>,
+}
+
+/// A `_` in the signature of the new function
+struct Site {
+    param: Option<String>,
+    range: TextRange,
+}
+
+/// Finds the placeholders in the signature of `new_fn_name` and tries to
+/// resolve them. `check` runs `cargo check` on some code, for `Build` mode.
+pub(crate) fn resolve_placeholders(
+    code: String,
+    edition: Edition,
+    new_fn_name: &str,
+    parent_method: &str,
+    mode: PlaceholderMode,
+    check: &dyn Fn( &str ) -> Result<BuildReport, ExtractionError>,
+) -> Result<PlaceholderResolution, ExtractionError> {
+    let mut code: String = code;
+    let mut placeholders: Vec<Placeholder> = vec![];
+
+    if mode != PlaceholderMode::Off {
+        let file: SourceFile = SourceFile::parse( &code, edition ).tree();
+        let replacements: Vec<(TextRange, String, Option<String>)> = annotation_replacements( &file, new_fn_name, parent_method );
+        placeholders.extend( replacements.iter().map(|( _, ty, param )| Placeholder {
+            param: param.clone(),
+            resolved: Some( ty.clone() ),
+            source: Some( PlaceholderSource::ParentAnnotation ),
+        }) );
+        code = apply( &code, replacements.into_iter().map(|( range, ty, _ )| ( range, ty )).collect() );
+    }
+
+    if mode == PlaceholderMode::Build {
+        let sites: Vec<Site> = placeholder_sites( &SourceFile::parse( &code, edition ).tree(), new_fn_name );
+        if !sites.is_empty() {
+            let report: BuildReport = check( &code )?;
+            let suggestions: Vec<&BuildSuggestion> = report.errors
+                .iter()
+                .filter(|error| error.code.as_deref() == Some( PLACEHOLDER_ERROR_CODE ))
+                .flat_map(|error| &error.suggestions)
+                .collect();
+
+            let mut replacements: Vec<(TextRange, String)> = vec![];
+            for site in &sites {
+                let Some( suggestion ) = suggestions.iter().find(|s| to_text_range( s.range ).contains_range( site.range )) else {
+                    continue;
+                };
+                placeholders.push( Placeholder {
+                    param: site.param.clone(),
+                    resolved: Some( suggestion.replacement.clone() ),
+                    source: Some( PlaceholderSource::Rustc ),
+                } );
+                let range: TextRange = to_text_range( suggestion.range );
+                if !replacements.iter().any(|( r, _ )| *r == range) {
+                    replacements.push( ( range, suggestion.replacement.clone() ) );
+                }
+            }
+            code = apply( &code, replacements );
+        }
+    }
+
+    let unresolved: Vec<Site> = placeholder_sites( &SourceFile::parse( &code, edition ).tree(), new_fn_name );
+    placeholders.extend( unresolved.into_iter().map(|site| Placeholder {
+        param: site.param,
+        resolved: None,
+        source: None,
+    }) );
+
+    Ok( PlaceholderResolution { code, placeholders } )
+}
+
+/// The `_` types in the parameters and return type of the function
+fn placeholder_sites( file: &SourceFile, new_fn_name: &str ) -> Vec<Site> {
+    let Some( new_fn ) = find_fn( file, new_fn_name ) else {
+        return vec![];
+    };
+    let infer_types = |ty: &ast::Type| -> Vec<TextRange> {
+        ty.syntax()
+            .descendants()
+            .filter(|node| ast::InferType::can_cast( node.kind() ))
+            .map(|node| node.text_range())
+            .collect()
+    };
+
+    let mut sites: Vec<Site> = vec![];
+    for param in new_fn.param_list().iter().flat_map(|p| p.params()) {
+        let name: Option<String> = param.pat().map(|pat| pat.syntax().text().to_string());
+        for range in param.ty().map_or( vec![], |ty| infer_types( &ty ) ) {
+            sites.push( Site { param: name.clone(), range } );
+        }
+    }
+    if let Some( ret_ty ) = new_fn.ret_type().and_then(|r| r.ty()) {
+        sites.extend( infer_types( &ret_ty ).into_iter().map(|range| Site { param: None, range }) );
+    }
+    sites
+}
+
+/// Matches the signature of the new function against the annotations in the
+/// parent method. Returns the range of each placeholder, its type and its
+/// parameter.
+fn annotation_replacements(
+    file: &SourceFile,
+    new_fn_name: &str,
+    parent_method: &str,
+) -> Vec<(TextRange, String, Option<String>)> {
+    let ( Some( new_fn ), Some( parent ), Some( call_site ) ) = (
+        find_fn( file, new_fn_name ),
+        find_fn( file, parent_method ),
+        find_call_site( file, new_fn_name, parent_method ),
+    ) else {
+        return vec![];
+    };
+    let call_site: TextRange = to_text_range( call_site );
+
+    let annotations: HashMap<String, Option<ast::Type>> = bindings_in_scope( &parent, call_site );
+    let return_annotation: Option<ast::Type> = parent
+        .syntax()
+        .descendants()
+        .filter_map( ast::LetStmt::cast )
+        .find(|let_stmt| let_stmt.initializer().is_some_and(|init| init.syntax().text_range() == call_site))
+        .and_then(|let_stmt| let_stmt.ty());
+
+    let mut replacements: Vec<(TextRange, String, Option<String>)> = vec![];
+    for param in new_fn.param_list().iter().flat_map(|p| p.params()) {
+        let ( Some( ast::Pat::IdentPat( pat ) ), Some( ty ) ) = ( param.pat(), param.ty() ) else {
+            continue;
+        };
+        let name: String = pat.syntax().text().to_string();
+        let binding: Option<String> = pat.name().map(|binding| binding.text().to_string());
+        if let Some( Some( annotation ) ) = binding.and_then(|binding| annotations.get( &binding )) {
+            let mut matches: Vec<(TextRange, String)> = vec![];
+            match_types( &ty, annotation, &mut matches );
+            replacements.extend( matches.into_iter().map(|( range, ty )| ( range, ty, Some( name.clone() ) )) );
+        }
+    }
+    if let ( Some( ret_ty ), Some( annotation ) ) = ( new_fn.ret_type().and_then(|r| r.ty()), return_annotation ) {
+        let mut matches: Vec<(TextRange, String)> = vec![];
+        match_types( &ret_ty, &annotation, &mut matches );
+        replacements.extend( matches.into_iter().map(|( range, ty )| ( range, ty, None )) );
+    }
+    replacements
+}
+
+/// The bindings of the parent method that are in scope at the call, with their
+/// type annotations. A later binding of a name shadows the earlier ones, so
+/// one without an annotation (`None`) hides an annotated one.
+fn bindings_in_scope( parent: &ast::Fn, call_site: TextRange ) -> HashMap<String, Option<ast::Type>> {
+    let mut bindings: HashMap<String, Option<ast::Type>> = HashMap::new();
+    for pat in parent.syntax().descendants().filter_map( ast::IdentPat::cast ) {
+        if pat.syntax().text_range().end() > call_site.start() {
+            continue;
+        }
+        let Some( ( scope, annotation ) ) = binding_scope( &pat ) else {
+            continue;
+        };
+        if let Some( name ) = pat.name().filter(|_| scope.contains_range( call_site )) {
+            bindings.insert( name.text().to_string(), annotation );
+        }
+    }
+    bindings
+}
+
+/// The range a binding is visible in, and its type annotation if it is the
+/// whole pattern of an annotated `let` or parameter
+fn binding_scope( pat: &ast::IdentPat ) -> Option<(TextRange, Option<ast::Type>)> {
+    let is_whole = |whole: Option<ast::Pat>| whole.is_some_and(|whole| whole.syntax() == pat.syntax());
+    for node in pat.syntax().ancestors().skip( 1 ) {
+        if let Some( let_stmt ) = ast::LetStmt::cast( node.clone() ) {
+            // From the end of the statement to the end of its block
+            let block_end: TextSize = let_stmt.syntax().parent()?.text_range().end();
+            let annotation: Option<ast::Type> = let_stmt.ty().filter(|_| is_whole( let_stmt.pat() ));
+            return Some( ( TextRange::new( let_stmt.syntax().text_range().end(), block_end ), annotation ) );
+        }
+        if let Some( param ) = ast::Param::cast( node.clone() ) {
+            // The function or closure the parameter belongs to
+            let owner: TextRange = param.syntax().parent()?.parent()?.text_range();
+            return Some( ( owner, param.ty().filter(|_| is_whole( param.pat() )) ) );
+        }
+        if ast::MatchArm::can_cast( node.kind() ) || ast::ForExpr::can_cast( node.kind() ) {
+            return Some( ( node.text_range(), None ) );
+        }
+        if ast::LetExpr::can_cast( node.kind() ) {
+            // The whole `if let` or `while let`, which is a little too wide
+            let owner: TextRange = node
+                .ancestors()
+                .find(|n| ast::IfExpr::can_cast( n.kind() ) || ast::WhileExpr::can_cast( n.kind() ))?
+                .text_range();
+            return Some( ( owner, None ) );
+        }
+    }
+    None
+}
+
+/// Walks `pattern` and `annotation` together, and collects the part of the
+/// annotation at each `_` of the pattern. References in the pattern that the
+/// annotation doesn't have (a parameter taking a local by reference) are
+/// skipped.
+fn match_types( pattern: &ast::Type, annotation: &ast::Type, matches: &mut Vec<(TextRange, String)> ) {
+    fn both( pattern: Option<ast::Type>, annotation: Option<ast::Type>, matches: &mut Vec<(TextRange, String)> ) {
+        if let ( Some( pattern ), Some( annotation ) ) = ( pattern, annotation ) {
+            match_types( &pattern, &annotation, matches );
+        }
+    }
+
+    match ( pattern, annotation ) {
+        ( _, ast::Type::InferType( _ ) ) => {},
+        ( ast::Type::InferType( infer ), _ ) => {
+            matches.push( ( infer.syntax().text_range(), annotation.syntax().text().to_string() ) );
+        },
+        ( ast::Type::RefType( pattern ), ast::Type::RefType( annotation ) ) => both( pattern.ty(), annotation.ty(), matches ),
+        ( ast::Type::RefType( pattern ), _ ) => both( pattern.ty(), Some( annotation.clone() ), matches ),
+        ( ast::Type::SliceType( pattern ), ast::Type::SliceType( annotation ) ) => both( pattern.ty(), annotation.ty(), matches ),
+        ( ast::Type::ArrayType( pattern ), ast::Type::ArrayType( annotation ) ) => both( pattern.ty(), annotation.ty(), matches ),
+        ( ast::Type::TupleType( pattern ), ast::Type::TupleType( annotation ) ) => {
+            if pattern.fields().count() == annotation.fields().count() {
+                for ( p, a ) in pattern.fields().zip( annotation.fields() ) {
+                    both( Some( p ), Some( a ), matches );
+                }
+            }
+        },
+        ( ast::Type::PathType( pattern ), ast::Type::PathType( annotation ) ) => {
+            if let ( Some( ( p_name, p_args ) ), Some( ( a_name, a_args ) ) ) = ( type_args( pattern ), type_args( annotation ) ) {
+                if p_name == a_name && p_args.len() == a_args.len() {
+                    for ( p, a ) in p_args.into_iter().zip( a_args ) {
+                        both( Some( p ), Some( a ), matches );
+                    }
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+/// The name of the last segment of a path type and its type arguments
+fn type_args( path_type: &ast::PathType ) -> Option<(String, Vec<ast::Type>)> {
+    let segment: ast::PathSegment = path_type.path()?.segment()?;
+    let name: String = segment.name_ref()?.text().to_string();
+    let args: Vec<ast::Type> = segment
+        .generic_arg_list()
+        .iter()
+        .flat_map(|list| list.generic_args())
+        .filter_map(|arg| match arg {
+            ast::GenericArg::TypeArg( arg ) => arg.ty(),
+            _ => None,
+        })
+        .collect();
+    Some( ( name, args ) )
+}
+
+/// Applies non-overlapping replacements to the code
+fn apply( code: &str, replacements: Vec<(TextRange, String)> ) -> String {
+    let mut replacements: Vec<(TextRange, String)> = replacements;
+    replacements.sort_by_key(|( range, _ )| std::cmp::Reverse( range.start() ));
+    let mut code: String = code.to_string();
+    let mut end: TextSize = TextSize::of( code.as_str() );
+    for ( range, replacement ) in replacements {
+        if range.end() > end {
+            continue;
+        }
+        code.replace_range( std::ops::Range::<usize>::from( range ), &replacement );
+        end = range.start();
+    }
+    code
+}
+
+fn to_text_range( range: (u32, u32) ) -> TextRange {
+    TextRange::new( TextSize::new( range.0 ), TextSize::new( range.1 ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::sandbox::{BuildError, BuildErrorLocation};
+
+    const CODE: &str = "fn main() {
+    let v: Vec<i32> = vec![1];
+    let n: usize = fun_name(&v);
+}
+
+fn fun_name(v: &Vec<_>) -> _ {
+    v.len()
+}
+";
+
+    fn no_build(_: &str) -> Result<BuildReport, ExtractionError> {
+        panic!("cargo check should not run")
+    }
+
+    #[test]
+    fn test_placeholders_are_reported() {
+        let resolution = resolve_placeholders(CODE.to_string(), Edition::CURRENT, "fun_name", "main", PlaceholderMode::Off, &no_build).unwrap();
+        assert_eq!(resolution.code, CODE);
+        assert_eq!(resolution.placeholders, vec![
+            Placeholder { param: Some("v".to_string()), resolved: None, source: None },
+            Placeholder { param: None, resolved: None, source: None },
+        ]);
+    }
+
+    #[test]
+    fn test_placeholders_resolved_from_annotations() {
+        let resolution = resolve_placeholders(CODE.to_string(), Edition::CURRENT, "fun_name", "main", PlaceholderMode::Annotations, &no_build).unwrap();
+        assert!(resolution.code.contains("fn fun_name(v: &Vec<i32>) -> usize {"));
+        assert!(resolution.placeholders.iter().all(|p| p.source == Some(PlaceholderSource::ParentAnnotation)));
+        assert_eq!(resolution.placeholders.len(), 2);
+    }
+
+    fn resolved_param(code: &str) -> Option<String> {
+        let resolution = resolve_placeholders(code.to_string(), Edition::CURRENT, "fun_name", "main", PlaceholderMode::Annotations, &no_build).unwrap();
+        resolution.placeholders.into_iter().find(|p| p.param.as_deref() == Some("v")).unwrap().resolved
+    }
+
+    #[test]
+    fn test_unannotated_binding_shadows_annotation() {
+        let code = CODE.replace(
+            "    let n: usize",
+            "    let v = v.iter().map(|x| *x as i64).collect::<Vec<_>>();\n    let n: usize",
+        );
+        assert_eq!(resolved_param(&code), None);
+        assert_eq!(resolved_param(CODE).as_deref(), Some("i32"));
+    }
+
+    #[test]
+    fn test_annotations_out_of_scope_are_ignored() {
+        let code = "fn main() {
+    let v = vec![1];
+    {
+        let v: Vec<u8> = vec![1];
+    }
+    let f = |v: Vec<u16>| v.len();
+    fn inner(v: Vec<u32>) {}
+    let n: usize = fun_name(&v);
+}
+
+fn fun_name(v: &Vec<_>) -> _ {
+    v.len()
+}
+";
+        assert_eq!(resolved_param(code), None);
+
+        let code = "fn main() {
+    let v: Vec<i32> = vec![1];
+    {
+        let n: usize = fun_name(&v);
+    }
+}
+
+fn fun_name(v: &Vec<_>) -> _ {
+    v.len()
+}
+";
+        assert_eq!(resolved_param(code).as_deref(), Some("i32"));
+    }
+
+    #[test]
+    fn test_placeholders_resolved_by_rustc() {
+        let code = CODE.replace("let n: usize = ", "");
+        let build = |code: &str| -> Result<BuildReport, ExtractionError> {
+            let at = code.find("-> _").unwrap() as u32 + 3;
+            Ok(BuildReport {
+                success: false,
+                errors: vec![BuildError {
+                    code: Some("E0121".to_string()),
+                    message: "the placeholder `_` is not allowed within types on item signatures for return types".to_string(),
+                    rendered: None,
+                    location: BuildErrorLocation::NewFunction,
+                    range: Some((at, at + 1)),
+                    suggestions: vec![BuildSuggestion { range: (at, at + 1), replacement: "usize".to_string() }],
+                }],
+                cargo_stderr: None,
+                duration: Duration::ZERO,
+            })
+        };
+
+        let resolution = resolve_placeholders(code, Edition::CURRENT, "fun_name", "main", PlaceholderMode::Build, &build).unwrap();
+        assert!(resolution.code.contains("fn fun_name(v: &Vec<i32>) -> usize {"));
+        assert_eq!(resolution.placeholders.len(), 2);
+        assert_eq!(resolution.placeholders[1], Placeholder {
+            param: None,
+            resolved: Some("usize".to_string()),
+            source: Some(PlaceholderSource::Rustc),
+        });
+    }
+}
@@ -0,0 +1,315 @@
+//! Python bindings, built with the `python` feature (with maturin, see
+//! `pyproject.toml`).
+//!
+//! Results are returned as dicts with the same structure as the JSON output:
+//! `extract` returns the object of `ExtractionOutput::to_json` (and `inline`
+//! that of `InlineOutput::to_json`), and failures raise
+//! `rem_extract.ExtractionError`, whose arguments are the message and the
+//! error object of `ExtractionError::to_json`.
+//!
+//! ```python
+//! import rem_extract
+//!
+//! session = rem_extract.ExtractionSession(load_strategy="minimal")
+//! for candidate in session.list_candidates("src/main.rs"):
+//!     print(candidate["range"], candidate["parent_method"])
+//! output = session.extract("src/main.rs", "foo", 120, 180)
+//! print(output["code"])
+//! ```
+
+use pyo3::{
+    create_exception,
+    exceptions::{
+        PyException,
+        PyValueError,
+    },
+    prelude::*,
+    types::PyDict,
+};
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    differential::DifferentialMode,
+    error,
+    extraction::{
+        extract_method_configured,
+        ExtractionInput,
+        ExtractionOptions,
+        ExtractionOutput,
+        LoadOptions,
+        LoadStrategy,
+        VerifyMode,
+    },
+    inline::{
+        InlineInput,
+        InlineOutput,
+        InlineTarget,
+    },
+    placeholders::PlaceholderMode,
+    session,
+};
+
+create_exception!(
+    rem_extract,
+    ExtractionError,
+    PyException,
+    "Raised when an extraction fails. The arguments are the message and a dict with the `code`, `class`, `message`, `location` and `causes` of the error."
+);
+
+/// Converts a serializable value to Python objects by way of JSON
+fn to_python<T: Serialize>( py: Python<'_>, value: &T ) -> PyResult<PyObject> {
+    let json: String = serde_json::to_string( value )
+        .map_err(|e| PyValueError::new_err( e.to_string() ))?;
+    let object: Bound<'_, PyAny> = PyModule::import_bound( py, "json" )?
+        .call_method1( "loads", ( json, ) )?;
+    Ok( object.unbind() )
+}
+
+fn to_py_err( py: Python<'_>, error: error::ExtractionError ) -> PyErr {
+    match to_python( py, &error.to_json()["error"] ) {
+        Ok( details ) => ExtractionError::new_err( ( error.to_string(), details ) ),
+        Err( e ) => e,
+    }
+}
+
+fn output_to_python( py: Python<'_>, output: &ExtractionOutput ) -> PyResult<PyObject> {
+    to_python( py, &output.to_json() )
+}
+
+/// The keyword arguments of `ExtractionSession`. They have the same names and
+/// values as the CLI options (with `_` instead of `-`), and unknown names or
+/// values raise a `ValueError`.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SessionOptions {
+    load_strategy: LoadStrategy,
+    sysroot: Option<String>,
+    sysroot_src: Option<String>,
+    toolchain: Option<String>,
+    use_cache: bool,
+    build_scripts: bool,
+    verify: VerifyMode,
+    verify_build: bool,
+    differential: DifferentialMode,
+    resolve_placeholders: PlaceholderMode,
+    allow_errors: bool,
+    round_trip: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            load_strategy: LoadStrategy::default(),
+            sysroot: None,
+            sysroot_src: None,
+            toolchain: None,
+            use_cache: true,
+            build_scripts: false,
+            verify: VerifyMode::default(),
+            verify_build: false,
+            differential: DifferentialMode::default(),
+            resolve_placeholders: PlaceholderMode::default(),
+            allow_errors: false,
+            round_trip: false,
+        }
+    }
+}
+
+impl SessionOptions {
+    /// Reads the options from the keyword arguments, by way of JSON
+    fn from_python( py: Python<'_>, kwargs: &Bound<'_, PyDict> ) -> PyResult<Self> {
+        let json: String = PyModule::import_bound( py, "json" )?
+            .call_method1( "dumps", ( kwargs, ) )?
+            .extract()?;
+        Self::from_json( &json )
+    }
+
+    fn from_json( json: &str ) -> PyResult<Self> {
+        serde_json::from_str( json ).map_err(|e| PyValueError::new_err( e.to_string() ))
+    }
+
+    fn split( self ) -> ( LoadOptions, ExtractionOptions ) {
+        let load_options: LoadOptions = LoadOptions {
+            load_strategy: self.load_strategy,
+            sysroot: self.sysroot,
+            sysroot_src: self.sysroot_src,
+            toolchain: self.toolchain,
+            use_cache: self.use_cache,
+            build_scripts: self.build_scripts,
+        };
+        let extraction_options: ExtractionOptions = ExtractionOptions {
+            verify: self.verify,
+            verify_build: self.verify_build,
+            differential: self.differential,
+            placeholders: self.resolve_placeholders,
+            allow_errors: self.allow_errors,
+            round_trip: self.round_trip,
+        };
+        ( load_options, extraction_options )
+    }
+}
+
+/// Same as `ExtractionSession`, with the load options as keyword arguments
+#[pyclass(name = "ExtractionSession", module = "rem_extract", unsendable)]
+pub struct PyExtractionSession {
+    session: session::ExtractionSession,
+}
+
+#[pymethods]
+impl PyExtractionSession {
+    #[new]
+    #[pyo3(signature = (**options))]
+    fn new( py: Python<'_>, options: Option<&Bound<'_, PyDict>> ) -> PyResult<Self> {
+        let options: SessionOptions = match options {
+            Some( options ) => SessionOptions::from_python( py, options )?,
+            None => SessionOptions::default(),
+        };
+        let ( load_options, extraction_options ) = options.split();
+        Ok( PyExtractionSession {
+            session: session::ExtractionSession::with_options( load_options, extraction_options ),
+        } )
+    }
+
+    /// Extracts the selection into a new function named `new_fn_name`
+    fn extract(
+        &mut self,
+        py: Python<'_>,
+        file_path: &str,
+        new_fn_name: &str,
+        start_idx: u32,
+        end_idx: u32,
+    ) -> PyResult<PyObject> {
+        let input: ExtractionInput = ExtractionInput::new( file_path, new_fn_name, start_idx, end_idx );
+        let output: ExtractionOutput = self.session.extract( input ).map_err(|e| to_py_err( py, e ))?;
+        output_to_python( py, &output )
+    }
+
+    /// Inlines every call of the function named `function` and removes it, or
+    /// the call at the byte `offset`
+    #[pyo3(signature = (file_path, function = None, offset = None))]
+    fn inline(
+        &mut self,
+        py: Python<'_>,
+        file_path: &str,
+        function: Option<String>,
+        offset: Option<u32>,
+    ) -> PyResult<PyObject> {
+        let target: InlineTarget = match ( function, offset ) {
+            ( Some( function ), None ) => InlineTarget::Function( function ),
+            ( None, Some( offset ) ) => InlineTarget::CallSite( offset ),
+            _ => return Err( PyValueError::new_err( "exactly one of `function` and `offset` must be given" ) ),
+        };
+        let output: InlineOutput = self.session
+            .inline( &InlineInput::new( file_path, target ) )
+            .map_err(|e| to_py_err( py, e ))?;
+        to_python( py, &output.to_json() )
+    }
+
+    /// Returns `{"applicable": bool, "error": dict | None}` for the selection
+    #[pyo3(signature = (file_path, start_idx, end_idx, new_fn_name = "fun_name"))]
+    fn check_applicable(
+        &mut self,
+        py: Python<'_>,
+        file_path: &str,
+        start_idx: u32,
+        end_idx: u32,
+        new_fn_name: &str,
+    ) -> PyResult<PyObject> {
+        let input: ExtractionInput = ExtractionInput::new( file_path, new_fn_name, start_idx, end_idx );
+        let result: serde_json::Value = match self.session.check_applicable( &input ) {
+            Ok(()) => serde_json::json!({ "applicable": true, "error": null }),
+            Err( e ) => serde_json::json!({ "applicable": false, "error": e.to_json()["error"] }),
+        };
+        to_python( py, &result )
+    }
+
+    /// Lists the selections in the file that can be extracted, optionally only
+    /// those inside the byte range `within`
+    #[pyo3(signature = (file_path, within = None))]
+    fn list_candidates(
+        &mut self,
+        py: Python<'_>,
+        file_path: &str,
+        within: Option<(u32, u32)>,
+    ) -> PyResult<PyObject> {
+        let candidates = self.session
+            .list_candidates( file_path, within )
+            .map_err(|e| to_py_err( py, e ))?;
+        to_python( py, &candidates )
+    }
+
+    /// Updates the contents of a file, or re-reads it from disk if `text` is
+    /// `None`. Returns whether a loaded project was affected.
+    #[pyo3(signature = (file_path, text = None))]
+    fn update_file( &mut self, py: Python<'_>, file_path: &str, text: Option<String> ) -> PyResult<bool> {
+        self.session.update_file( file_path, text ).map_err(|e| to_py_err( py, e ))
+    }
+
+    /// Drops every loaded project
+    fn clear( &mut self ) {
+        self.session.clear();
+    }
+
+    /// The number of projects currently loaded
+    #[getter]
+    fn loaded_projects( &self ) -> usize {
+        self.session.loaded_projects()
+    }
+}
+
+/// Extracts the selection without a session, loading the project from
+/// scratch with the default options
+#[pyfunction]
+fn extract(
+    py: Python<'_>,
+    file_path: &str,
+    new_fn_name: &str,
+    start_idx: u32,
+    end_idx: u32,
+) -> PyResult<PyObject> {
+    let input: ExtractionInput = ExtractionInput::new( file_path, new_fn_name, start_idx, end_idx );
+    let output: ExtractionOutput = extract_method_configured(
+        input,
+        &LoadOptions::default(),
+        &ExtractionOptions::default(),
+        &|_| (),
+    ).map_err(|e| to_py_err( py, e ))?;
+    output_to_python( py, &output )
+}
+
+#[pymodule]
+fn rem_extract( m: &Bound<'_, PyModule> ) -> PyResult<()> {
+    m.add_class::<PyExtractionSession>()?;
+    m.add_function( wrap_pyfunction!( extract, m )? )?;
+    m.add( "ExtractionError", m.py().get_type_bound::<ExtractionError>() )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_options() {
+        let options: SessionOptions = SessionOptions::from_json(r#"{"load_strategy": "minimal", "verify": "deny", "resolve_placeholders": "annotations", "use_cache": false}"#).unwrap();
+        let (load_options, extraction_options) = options.split();
+        assert_eq!(load_options.load_strategy, LoadStrategy::Minimal);
+        assert!(!load_options.use_cache);
+        assert_eq!(extraction_options.verify, VerifyMode::Deny);
+        assert_eq!(extraction_options.placeholders, PlaceholderMode::Annotations);
+        assert_eq!(extraction_options.differential, DifferentialMode::Off);
+
+        let (load_options, _) = SessionOptions::from_json("{}").unwrap().split();
+        assert!(load_options.use_cache);
+    }
+
+    #[test]
+    fn test_session_options_are_checked() {
+        assert!(SessionOptions::from_json(r#"{"load_strategy": "partial"}"#).is_err());
+        assert!(SessionOptions::from_json(r#"{"use_cahce": false}"#).is_err());
+    }
+}
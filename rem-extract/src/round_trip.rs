@@ -0,0 +1,327 @@
+//! Round-trip check of an extraction: the new function is inlined back.
+//!
+//! rust-analyzer's `inline_call` assist is run on the call to the new function
+//! in the modified file, in memory, and the result is compared with the
+//! original file. If the extraction preserved the code, inlining it gives the
+//! original back, up to:
+//!
+//! - whitespace and comments;
+//! - parentheses that don't change how an expression parses, e.g. around a
+//!   path, a call or a whole argument;
+//! - the block the inlined body is wrapped in (`{ ... };`);
+//! - the new function itself, which `inline_call` leaves in place.
+//!
+//! Anything else is reported as a difference, such as a lost `mut`, a
+//! reordered statement or a `let` binding introduced for an argument. The
+//! check is a cheap self-consistency oracle rather than a proof: inlining
+//! may itself add bindings that the original didn't have.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use ra_ap_ide::{
+    AnalysisHost,
+    FileRange,
+    TextRange,
+    TextSize,
+};
+
+use ra_ap_ide_assists::Assist;
+
+use ra_ap_parser::Edition;
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    SourceFile,
+    SyntaxKind,
+    SyntaxToken,
+};
+
+use ra_ap_vfs::FileId;
+
+use crate::{
+    error::ExtractionError,
+    extraction_utils::{
+        apply_assist,
+        get_inline_assists,
+    },
+    verify::set_file_text,
+};
+
+/// A run of tokens that differs between the original file and the inlined one
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct RoundTripDifference {
+    /// Byte range of the tokens in the original file, empty if tokens were
+    /// only added
+    pub original_range: (u32, u32),
+    /// The original tokens, separated by spaces
+    pub original: String,
+    /// The tokens after inlining, separated by spaces
+    pub inlined: String,
+}
+
+impl fmt::Display for RoundTripDifference {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match ( self.original.is_empty(), self.inlined.is_empty() ) {
+            ( true, _ ) => write!( f, "`{}` was added at byte {}", self.inlined, self.original_range.0 ),
+            ( _, true ) => write!(
+                f, "`{}` was lost (bytes {}..{})",
+                self.original, self.original_range.0, self.original_range.1,
+            ),
+            _ => write!(
+                f, "`{}` became `{}` (bytes {}..{})",
+                self.original, self.inlined, self.original_range.0, self.original_range.1,
+            ),
+        }
+    }
+}
+
+/// The result of inlining the new function back
+#[derive(Debug, PartialEq, Eq, Clone, Default, Serialize)]
+pub struct RoundTripReport {
+    /// Whether rust-analyzer could inline the call. If not, there are no
+    /// differences.
+    pub inlined: bool,
+    pub differences: Vec<RoundTripDifference>,
+}
+
+impl RoundTripReport {
+    pub fn is_equivalent( &self ) -> bool {
+        self.inlined && self.differences.is_empty()
+    }
+}
+
+/// Inlines the call to `new_fn_name` at `call_site_range` in the modified
+/// text and compares the result with the original text. The original text is
+/// put back in the database afterwards.
+pub(crate) fn round_trip(
+    host: &mut AnalysisHost,
+    file_id: FileId,
+    original: &str,
+    modified: &str,
+    new_fn_name: &str,
+    call_site_range: Option<(u32, u32)>,
+    edition: Edition,
+) -> Result<RoundTripReport, ExtractionError> {
+    let output_file: SourceFile = SourceFile::parse( modified, edition ).tree();
+    let Some( offset ) = call_site_range.and_then(|range| callee_offset( &output_file, new_fn_name, range )) else {
+        return Ok( RoundTripReport::default() );
+    };
+
+    set_file_text( host, file_id, modified );
+    let frange: FileRange = FileRange { file_id, range: TextRange::empty( offset ) };
+    let assists: Result<Vec<Assist>, ExtractionError> = get_inline_assists( &host.analysis(), frange );
+    let inlined: Result<Option<String>, ExtractionError> = assists.and_then(|assists| {
+        assists
+            .iter()
+            .find(|assist| assist.id.0 == "inline_call")
+            .map(|assist| apply_assist( assist, file_id, modified ))
+            .transpose()
+    });
+    set_file_text( host, file_id, original );
+
+    let Some( inlined ) = inlined? else {
+        return Ok( RoundTripReport::default() );
+    };
+    Ok( RoundTripReport {
+        inlined: true,
+        differences: compare(
+            &SourceFile::parse( original, edition ).tree(),
+            &SourceFile::parse( &inlined, edition ).tree(),
+            new_fn_name,
+        ),
+    } )
+}
+
+/// The offset of the name of the new function in its call
+fn callee_offset( file: &SourceFile, new_fn_name: &str, call_site_range: (u32, u32) ) -> Option<TextSize> {
+    let call_site: TextRange = TextRange::new( TextSize::new( call_site_range.0 ), TextSize::new( call_site_range.1 ) );
+    file.syntax()
+        .descendants()
+        .filter_map( ast::NameRef::cast )
+        .find(|name| name.text() == new_fn_name && call_site.contains_range( name.syntax().text_range() ))
+        .map(|name| name.syntax().text_range().start())
+}
+
+/// Compares the significant tokens of the original and the inlined file,
+/// leaving out the new function
+fn compare( original: &SourceFile, inlined: &SourceFile, new_fn_name: &str ) -> Vec<RoundTripDifference> {
+    let before: Vec<SyntaxToken> = significant_tokens( original, None );
+    let after: Vec<SyntaxToken> = significant_tokens( inlined, Some( new_fn_name ) );
+    let before_text: Vec<&str> = before.iter().map(|token| token.text()).collect();
+    let after_text: Vec<&str> = after.iter().map(|token| token.text()).collect();
+
+    let mut differences: Vec<RoundTripDifference> = vec![];
+    // The original and inlined tokens of the difference being built
+    let mut lost: Vec<&SyntaxToken> = vec![];
+    let mut added: Vec<&str> = vec![];
+    let mut position: usize = 0;
+    let mut flush = |lost: &mut Vec<&SyntaxToken>, added: &mut Vec<&str>, position: usize| {
+        if lost.is_empty() && added.is_empty() {
+            return;
+        }
+        let original_range: (u32, u32) = match ( lost.first(), lost.last() ) {
+            ( Some( first ), Some( last ) ) => ( first.text_range().start().into(), last.text_range().end().into() ),
+            _ => {
+                let at: TextSize = before
+                    .get( position )
+                    .map_or( original.syntax().text_range().end(), |token| token.text_range().start() );
+                ( at.into(), at.into() )
+            },
+        };
+        differences.push( RoundTripDifference {
+            original_range,
+            original: lost.iter().map(|token| token.text()).collect::<Vec<&str>>().join( " " ),
+            inlined: added.join( " " ),
+        } );
+        lost.clear();
+        added.clear();
+    };
+
+    for result in diff::slice( &before_text, &after_text ) {
+        match result {
+            diff::Result::Left( _ ) => {
+                lost.push( &before[position] );
+                position += 1;
+            },
+            diff::Result::Right( text ) => added.push( *text ),
+            diff::Result::Both( _, _ ) => {
+                flush( &mut lost, &mut added, position );
+                position += 1;
+            },
+        }
+    }
+    flush( &mut lost, &mut added, position );
+    differences
+}
+
+/// The tokens of the file without trivia, trivial parentheses, the braces of
+/// blocks used as statements and the function named `skip_fn`
+fn significant_tokens( file: &SourceFile, skip_fn: Option<&str> ) -> Vec<SyntaxToken> {
+    file.syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| !token.kind().is_trivia())
+        .filter(|token| !is_trivial_delimiter( token ))
+        .filter(|token| !skip_fn.is_some_and(|name| in_fn( token, name )))
+        .collect()
+}
+
+fn in_fn( token: &SyntaxToken, name: &str ) -> bool {
+    token
+        .parent_ancestors()
+        .filter_map( ast::Fn::cast )
+        .any(|f| f.name().is_some_and(|n| n.text() == name))
+}
+
+fn is_trivial_delimiter( token: &SyntaxToken ) -> bool {
+    let Some( parent ) = token.parent() else {
+        return false;
+    };
+    match token.kind() {
+        SyntaxKind::L_PAREN | SyntaxKind::R_PAREN => ast::ParenExpr::cast( parent ).is_some_and(|p| is_trivial_paren( &p )),
+        SyntaxKind::L_CURLY | SyntaxKind::R_CURLY => parent
+            .parent()
+            .and_then( ast::BlockExpr::cast )
+            .is_some_and(|block| is_statement_block( &block )),
+        SyntaxKind::SEMICOLON => ast::ExprStmt::cast( parent )
+            .and_then(|stmt| stmt.expr())
+            .is_some_and(|expr| matches!( expr, ast::Expr::BlockExpr( block ) if is_statement_block( &block ) )),
+        _ => false,
+    }
+}
+
+/// Whether removing the parentheses keeps the meaning: the parent is not an
+/// expression, or the inner expression binds tighter than anything
+fn is_trivial_paren( paren: &ast::ParenExpr ) -> bool {
+    let parent_is_expr: bool = paren.syntax().parent().is_some_and(|parent| ast::Expr::can_cast( parent.kind() ));
+    !parent_is_expr || matches!(
+        paren.expr(),
+        Some(
+            ast::Expr::PathExpr( _ )
+            | ast::Expr::Literal( _ )
+            | ast::Expr::CallExpr( _ )
+            | ast::Expr::MethodCallExpr( _ )
+            | ast::Expr::FieldExpr( _ )
+            | ast::Expr::IndexExpr( _ )
+            | ast::Expr::ParenExpr( _ )
+            | ast::Expr::MacroExpr( _ )
+            | ast::Expr::TupleExpr( _ )
+            | ast::Expr::ArrayExpr( _ )
+        )
+    )
+}
+
+/// A plain block (no label, `unsafe`, `async` or `const`) that is a statement
+/// or the tail of another block
+fn is_statement_block( block: &ast::BlockExpr ) -> bool {
+    block.modifier().is_none() && block.syntax().parent().is_some_and(|parent| {
+        ast::ExprStmt::can_cast( parent.kind() ) || ast::StmtList::can_cast( parent.kind() )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn differences(original: &str, inlined: &str) -> Vec<RoundTripDifference> {
+        compare(
+            &SourceFile::parse(original, Edition::CURRENT).tree(),
+            &SourceFile::parse(inlined, Edition::CURRENT).tree(),
+            "fun_name",
+        )
+    }
+
+    #[test]
+    fn test_round_trip_ignores_trivial_changes() {
+        let original = "fn main() {
+    let mut v = vec![1];
+    // push
+    v.push(2);
+    let n = v.len();
+}
+";
+        let inlined = "fn main() {
+    let mut v = vec![1];
+    {
+        (v).push(2);
+    };
+    let n = (v.len());
+}
+
+fn fun_name(v: &mut Vec<i32>) {
+    v.push(2);
+}
+";
+        assert_eq!(differences(original, inlined), vec![]);
+    }
+
+    #[test]
+    fn test_round_trip_reports_differences() {
+        let original = "fn main() {
+    let mut x = 1;
+    x += 1;
+    let y = x * (x + 1);
+}
+";
+        let inlined = "fn main() {
+    let x = 1;
+    x += 1;
+    let y = x * x + 1;
+}
+";
+        let differences = differences(original, inlined);
+        assert_eq!(differences.len(), 3);
+        assert_eq!(differences[0].original, "mut");
+        assert_eq!(differences[0].inlined, "");
+        let (start, end) = differences[0].original_range;
+        assert_eq!(&original[start as usize..end as usize], "mut");
+        assert_eq!(differences[1].original, "(");
+        assert_eq!(differences[2].original, ")");
+    }
+}
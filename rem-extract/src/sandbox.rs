@@ -0,0 +1,541 @@
+//! Sandboxed builds of the result of an extraction, for `--verify-build`.
+//!
+//! The workspace containing the input file is copied into a temporary
+//! directory in the cache directory (see `cache::cache_dir`), without the
+//! target directory and the `.git` directory at its root, and the modified file is written into the
+//! copy. Every sandbox has its own copy, which is removed when it is dropped,
+//! so concurrent extractions don't see each other's files. `cargo check` then
+//! runs offline on the copy, with a target directory shared by every sandbox of
+//! the workspace, so that the dependencies are only built once. The project
+//! itself is never modified.
+//!
+//! Symbolic links are copied as links. Path dependencies outside the
+//! workspace root are not copied, so they are refused.
+//!
+//! The errors reported by rustc are mapped back onto the extraction: whether
+//! they are in the new function, at its call site, elsewhere in the file or
+//! in another file.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{
+        Hash,
+        Hasher,
+    },
+    io,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Command,
+        Output,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use log::info;
+
+use serde::Serialize;
+
+use serde_json::Value;
+
+use tempfile::TempDir;
+
+use crate::{
+    cache::cache_dir,
+    extraction::serialize_millis,
+};
+
+/// Lines of cargo's stderr kept when it fails without reporting any errors
+const STDERR_TAIL_LINES: usize = 20;
+
+/// A copy of a workspace to build the result of an extraction in
+pub struct Sandbox {
+    /// The workspace that was copied
+    workspace_root: PathBuf,
+    /// The copy of the workspace, removed when the sandbox is dropped
+    root: TempDir,
+    /// Target directory shared by every sandbox of the workspace
+    target_dir: PathBuf,
+}
+
+impl Sandbox {
+    /// Copies the workspace containing the manifest into a fresh sandbox.
+    /// Fails if a member has a path dependency outside the workspace root.
+    pub fn create( cargo_toml: &Path ) -> io::Result<Sandbox> {
+        let workspace_root: PathBuf = workspace_root( cargo_toml );
+        let metadata: Option<Value> = workspace_metadata( cargo_toml )?;
+        if let Some( metadata ) = &metadata {
+            check_path_dependencies( metadata, &workspace_root )?;
+        }
+        let mut hasher: DefaultHasher = DefaultHasher::new();
+        workspace_root.hash( &mut hasher );
+        let dir: PathBuf = cache_dir().join( "sandbox" ).join( format!( "{:016x}", hasher.finish() ) );
+        fs::create_dir_all( &dir )?;
+
+        let root: TempDir = tempfile::Builder::new().prefix( "workspace-" ).tempdir_in( &dir )?;
+        copy_dir( &workspace_root, root.path(), &skipped_dirs( &workspace_root, metadata.as_ref() ) )?;
+        info!("Copied {} to {}", workspace_root.display(), root.path().display());
+
+        Ok( Sandbox {
+            workspace_root,
+            root,
+            target_dir: dir.join( "target" ),
+        } )
+    }
+
+    /// The copy of the workspace
+    pub fn root( &self ) -> &Path {
+        self.root.path()
+    }
+
+    /// The path of the copy of a file in the workspace
+    pub fn path_of( &self, path: &Path ) -> PathBuf {
+        let path: PathBuf = fs::canonicalize( path ).unwrap_or_else(|_| path.to_path_buf());
+        match path.strip_prefix( &self.workspace_root ) {
+            Ok( relative ) => self.root().join( relative ),
+            Err( _ ) => path,
+        }
+    }
+
+    /// Overwrites the copy of a file in the workspace
+    pub fn write_file( &self, path: &Path, contents: &str ) -> io::Result<()> {
+        fs::write( self.path_of( path ), contents )
+    }
+
+    /// A `cargo` command (e.g. `check`) for the copy of the package with the
+    /// given manifest, run offline with the sandbox's target directory
+    pub fn cargo( &self, subcommand: &str, cargo_toml: &Path ) -> Command {
+        let mut command: Command = Command::new( "cargo" );
+        command
+            .arg( subcommand )
+            .arg( "--offline" )
+            .arg( "--manifest-path" )
+            .arg( self.path_of( cargo_toml ) )
+            .env( "CARGO_TARGET_DIR", &self.target_dir )
+            .current_dir( self.root() );
+        command
+    }
+}
+
+/// Where a build error is, relative to the extraction
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildErrorLocation {
+    NewFunction,
+    CallSite,
+    /// Elsewhere in the modified file
+    File,
+    /// In another file (or without a location)
+    Elsewhere,
+}
+
+/// An error reported by rustc for the result of the extraction
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BuildError {
+    /// The error code, e.g. `E0499`
+    pub code: Option<String>,
+    pub message: String,
+    /// The error as rustc prints it
+    pub rendered: Option<String>,
+    pub location: BuildErrorLocation,
+    /// Byte range of the primary span, if it is in the modified file
+    pub range: Option<(u32, u32)>,
+    /// The replacements rustc suggests in the modified file
+    pub suggestions: Vec<BuildSuggestion>,
+}
+
+/// A replacement suggested by rustc, e.g. the type to use instead of `_`
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BuildSuggestion {
+    /// Byte range in the modified file
+    pub range: (u32, u32),
+    pub replacement: String,
+}
+
+/// The result of running `cargo check` on the result of the extraction
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct BuildReport {
+    /// Whether `cargo check` succeeded
+    pub success: bool,
+    pub errors: Vec<BuildError>,
+    /// The end of cargo's stderr, if it failed without reporting any error
+    /// (e.g. because a dependency is not available offline)
+    pub cargo_stderr: Option<String>,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+impl BuildReport {
+    /// The number of errors at the given location
+    pub fn count( &self, location: BuildErrorLocation ) -> usize {
+        self.errors.iter().filter(|e| e.location == location).count()
+    }
+}
+
+/// Runs `cargo check` on a copy of the workspace in which `file_path` has been
+/// replaced with `code`, and maps the errors onto the new function and its
+/// call site (byte ranges into `code`)
+pub fn check_build(
+    cargo_toml: &Path,
+    file_path: &Path,
+    code: &str,
+    new_fn_range: Option<(u32, u32)>,
+    call_site_range: Option<(u32, u32)>,
+) -> io::Result<BuildReport> {
+    let start: Instant = Instant::now();
+    let sandbox: Sandbox = Sandbox::create( cargo_toml )?;
+    sandbox.write_file( file_path, code )?;
+
+    let output: Output = sandbox
+        .cargo( "check", cargo_toml )
+        .args( ["--all-targets", "--message-format=json"] )
+        .output()?;
+
+    let errors: Vec<BuildError> = build_errors(
+        &String::from_utf8_lossy( &output.stdout ),
+        sandbox.root(),
+        &sandbox.path_of( file_path ),
+        new_fn_range,
+        call_site_range,
+    );
+    let success: bool = output.status.success();
+    let cargo_stderr: Option<String> = ( !success && errors.is_empty() ).then(|| {
+        let stderr: String = String::from_utf8_lossy( &output.stderr ).to_string();
+        let lines: Vec<&str> = stderr.lines().collect();
+        lines[lines.len().saturating_sub( STDERR_TAIL_LINES )..].join( "\n" )
+    });
+    info!("cargo check finished ({}) with {} errors", output.status, errors.len());
+
+    Ok( BuildReport {
+        success,
+        errors,
+        cargo_stderr,
+        duration: start.elapsed(),
+    } )
+}
+
+/// Collects the errors from cargo's JSON messages. Relative file names in the
+/// spans are relative to the workspace root.
+fn build_errors(
+    stdout: &str,
+    workspace_root: &Path,
+    file_path: &Path,
+    new_fn_range: Option<(u32, u32)>,
+    call_site_range: Option<(u32, u32)>,
+) -> Vec<BuildError> {
+    let file_path: PathBuf = fs::canonicalize( file_path ).unwrap_or_else(|_| file_path.to_path_buf());
+    let contains = |outer: Option<(u32, u32)>, inner: (u32, u32)| {
+        outer.is_some_and(|outer| outer.0 <= inner.0 && inner.1 <= outer.1)
+    };
+
+    let mut errors: Vec<BuildError> = vec![];
+    for line in stdout.lines() {
+        let Ok( message ) = serde_json::from_str::<Value>( line ) else {
+            continue;
+        };
+        if message["reason"] != "compiler-message" {
+            continue;
+        }
+        let diagnostic: &Value = &message["message"];
+        let spans: &[Value] = diagnostic["spans"].as_array().map_or( &[], |spans| spans.as_slice() );
+        // Errors without a span are summaries, e.g. "aborting due to 2 previous errors"
+        if diagnostic["level"] != "error" || spans.is_empty() {
+            continue;
+        }
+
+        let in_file = |span: &Value| {
+            let span_file: PathBuf = workspace_root.join( span["file_name"].as_str().unwrap_or_default() );
+            fs::canonicalize( &span_file ).unwrap_or( span_file ) == file_path
+        };
+        let span_range = |span: &Value| (
+            span["byte_start"].as_u64().unwrap_or_default() as u32,
+            span["byte_end"].as_u64().unwrap_or_default() as u32,
+        );
+
+        let span: &Value = spans.iter().find(|span| span["is_primary"] == true).unwrap_or( &spans[0] );
+        let range: Option<(u32, u32)> = in_file( span ).then(|| span_range( span ));
+        let suggestions: Vec<BuildSuggestion> = diagnostic["children"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|child| child["spans"].as_array().into_iter().flatten())
+            .filter(|span| in_file( span ))
+            .filter_map(|span| Some( BuildSuggestion {
+                range: span_range( span ),
+                replacement: span["suggested_replacement"].as_str()?.to_string(),
+            } ))
+            .collect();
+
+        let location: BuildErrorLocation = match range {
+            Some( range ) if contains( new_fn_range, range ) => BuildErrorLocation::NewFunction,
+            Some( range ) if contains( call_site_range, range ) => BuildErrorLocation::CallSite,
+            Some( _ ) => BuildErrorLocation::File,
+            None => BuildErrorLocation::Elsewhere,
+        };
+
+        errors.push( BuildError {
+            code: diagnostic["code"]["code"].as_str().map( str::to_string ),
+            message: diagnostic["message"].as_str().unwrap_or_default().to_string(),
+            rendered: diagnostic["rendered"].as_str().map( str::to_string ),
+            location,
+            range,
+            suggestions,
+        } );
+    }
+    errors
+}
+
+/// Finds the root of the workspace containing the package, falling back to
+/// the package itself
+fn workspace_root( cargo_toml: &Path ) -> PathBuf {
+    let package_dir: PathBuf = cargo_toml.parent().map_or( PathBuf::from( "." ), Path::to_path_buf );
+    let located: Option<PathBuf> = Command::new( "cargo" )
+        .args( ["locate-project", "--workspace", "--message-format", "plain", "--manifest-path"] )
+        .arg( cargo_toml )
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| {
+            let manifest: PathBuf = PathBuf::from( String::from_utf8_lossy( &output.stdout ).trim() );
+            manifest.parent().map( Path::to_path_buf )
+        });
+    let root: PathBuf = located.unwrap_or( package_dir );
+    fs::canonicalize( &root ).unwrap_or( root )
+}
+
+/// Runs `cargo metadata --no-deps` for the workspace. Returns `None` if cargo
+/// fails, as `cargo check` will report the problem with the manifest.
+fn workspace_metadata( cargo_toml: &Path ) -> io::Result<Option<Value>> {
+    let output: Output = Command::new( "cargo" )
+        .args( ["metadata", "--format-version", "1", "--no-deps", "--offline", "--manifest-path"] )
+        .arg( cargo_toml )
+        .output()?;
+    if !output.status.success() {
+        return Ok( None );
+    }
+    Ok( Some( serde_json::from_slice( &output.stdout )? ) )
+}
+
+/// The directories that are not copied into the sandbox: the `target` and
+/// `.git` directories at the workspace root, and the target directory from
+/// `cargo metadata`. Directories with the same names further down (e.g. a
+/// `src/target` module) are copied.
+fn skipped_dirs( workspace_root: &Path, metadata: Option<&Value> ) -> Vec<PathBuf> {
+    let mut skipped: Vec<PathBuf> = vec![
+        workspace_root.join( "target" ),
+        workspace_root.join( ".git" ),
+    ];
+    if let Some( target_dir ) = metadata.and_then(|metadata| metadata["target_directory"].as_str()) {
+        let target_dir: PathBuf = PathBuf::from( target_dir );
+        skipped.push( fs::canonicalize( &target_dir ).unwrap_or( target_dir ) );
+    }
+    skipped
+}
+
+/// Refuses path dependencies of the workspace members that are outside the
+/// workspace root, as they would not be in the copy
+fn check_path_dependencies( metadata: &Value, workspace_root: &Path ) -> io::Result<()> {
+    match outside_path_dependencies( metadata, workspace_root ).first() {
+        Some( ( name, path ) ) => Err( io::Error::other( format!(
+            "the path dependency `{}` at {} is outside the workspace root {}, so it can't be copied into the sandbox",
+            name,
+            path.display(),
+            workspace_root.display(),
+        ) ) ),
+        None => Ok(()),
+    }
+}
+
+/// The name and path of the path dependencies in `cargo metadata` that are
+/// outside the workspace root
+fn outside_path_dependencies( metadata: &Value, workspace_root: &Path ) -> Vec<(String, PathBuf)> {
+    metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|package| package["dependencies"].as_array().into_iter().flatten())
+        .filter_map(|dependency| {
+            let path: PathBuf = PathBuf::from( dependency["path"].as_str()? );
+            let path: PathBuf = fs::canonicalize( &path ).unwrap_or( path );
+            ( !path.starts_with( workspace_root ) ).then(|| ( dependency["name"].as_str().unwrap_or_default().to_string(), path ))
+        })
+        .collect()
+}
+
+/// Copies a directory recursively, skipping the `skipped` directories.
+/// Symbolic links are copied as links, not followed.
+fn copy_dir( from: &Path, to: &Path, skipped: &[PathBuf] ) -> io::Result<()> {
+    fs::create_dir_all( to )?;
+    for entry in fs::read_dir( from )? {
+        let entry: fs::DirEntry = entry?;
+        let path: PathBuf = entry.path();
+        let destination: PathBuf = to.join( entry.file_name() );
+        let file_type: fs::FileType = fs::symlink_metadata( &path )?.file_type();
+        if file_type.is_symlink() {
+            copy_symlink( &path, &destination )?;
+        } else if file_type.is_dir() {
+            if skipped.contains( &path ) {
+                continue;
+            }
+            copy_dir( &path, &destination, skipped )?;
+        } else {
+            fs::copy( &path, &destination )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink( link: &Path, destination: &Path ) -> io::Result<()> {
+    std::os::unix::fs::symlink( fs::read_link( link )?, destination )
+}
+
+#[cfg(windows)]
+fn copy_symlink( link: &Path, destination: &Path ) -> io::Result<()> {
+    let target: PathBuf = fs::read_link( link )?;
+    if fs::metadata( link ).is_ok_and(|metadata| metadata.is_dir()) {
+        std::os::windows::fs::symlink_dir( target, destination )
+    } else {
+        std::os::windows::fs::symlink_file( target, destination )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_dir_skips_target() {
+        let from = TempDir::new().unwrap();
+        fs::create_dir_all(from.path().join("src/target")).unwrap();
+        fs::create_dir_all(from.path().join("target/debug")).unwrap();
+        fs::create_dir_all(from.path().join(".git")).unwrap();
+        fs::write(from.path().join("Cargo.toml"), "[package]").unwrap();
+        fs::write(from.path().join("src/main.rs"), "mod target;\nfn main() {}").unwrap();
+        fs::write(from.path().join("src/target/mod.rs"), "").unwrap();
+        fs::write(from.path().join("target/debug/main"), "").unwrap();
+
+        let to = TempDir::new().unwrap();
+        copy_dir(from.path(), &to.path().join("copy"), &skipped_dirs(from.path(), None)).unwrap();
+        assert!(to.path().join("copy/src/main.rs").is_file());
+        assert!(to.path().join("copy/Cargo.toml").is_file());
+        assert!(!to.path().join("copy/target").exists());
+        assert!(!to.path().join("copy/.git").exists());
+        // Only the directories at the root are skipped
+        assert!(to.path().join("copy/src/target/mod.rs").is_file());
+    }
+
+    #[test]
+    fn test_skipped_dirs_include_the_target_directory() {
+        let root = Path::new("/workspace");
+        let metadata = serde_json::json!({ "target_directory": "/workspace/build" });
+        let skipped = skipped_dirs(root, Some(&metadata));
+        assert!(skipped.contains(&PathBuf::from("/workspace/build")));
+        assert!(skipped.contains(&PathBuf::from("/workspace/target")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir_copies_symlinks() {
+        let from = TempDir::new().unwrap();
+        fs::create_dir_all(from.path().join("src")).unwrap();
+        fs::write(from.path().join("src/main.rs"), "fn main() {}").unwrap();
+        std::os::unix::fs::symlink("main.rs", from.path().join("src/link.rs")).unwrap();
+        std::os::unix::fs::symlink("src", from.path().join("sources")).unwrap();
+
+        let to = TempDir::new().unwrap();
+        copy_dir(from.path(), &to.path().join("copy"), &[]).unwrap();
+        let link = to.path().join("copy/src/link.rs");
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("main.rs"));
+        assert!(fs::symlink_metadata(to.path().join("copy/sources")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(to.path().join("copy/sources/main.rs")).unwrap(), "fn main() {}");
+    }
+
+    #[test]
+    fn test_outside_path_dependencies() {
+        let root = TempDir::new().unwrap();
+        let root_path = fs::canonicalize(root.path()).unwrap();
+        let inside = root_path.join("crates/inside");
+        let outside = root_path.parent().unwrap().join("outside");
+        let metadata = serde_json::json!({
+            "packages": [{
+                "dependencies": [
+                    { "name": "inside", "path": inside },
+                    { "name": "outside", "path": outside },
+                    { "name": "serde" },
+                ],
+            }],
+        });
+        assert_eq!(outside_path_dependencies(&metadata, &root_path), vec![("outside".to_string(), outside)]);
+    }
+
+    #[test]
+    fn test_build_errors_are_mapped_to_the_extraction() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        fs::write(root.path().join("src/main.rs"), "").unwrap();
+
+        let message = |start: u32, end: u32, file: &str| serde_json::json!({
+            "reason": "compiler-message",
+            "message": {
+                "level": "error",
+                "code": { "code": "E0499" },
+                "message": "cannot borrow `x` as mutable more than once at a time",
+                "rendered": null,
+                "spans": [{ "file_name": file, "byte_start": start, "byte_end": end, "is_primary": true }],
+            },
+        }).to_string();
+        let stdout = [
+            message(60, 65, "src/main.rs"),
+            message(20, 25, "src/main.rs"),
+            message(5, 6, "src/main.rs"),
+            message(5, 6, "src/lib.rs"),
+            r#"{"reason": "compiler-message", "message": {"level": "error", "message": "aborting due to 4 previous errors", "spans": []}}"#.to_string(),
+            r#"{"reason": "build-finished", "success": false}"#.to_string(),
+        ].join("\n");
+
+        let errors = build_errors(&stdout, root.path(), &root.path().join("src/main.rs"), Some((50, 100)), Some((18, 30)));
+        let locations: Vec<BuildErrorLocation> = errors.iter().map(|e| e.location).collect();
+        assert_eq!(locations, vec![
+            BuildErrorLocation::NewFunction,
+            BuildErrorLocation::CallSite,
+            BuildErrorLocation::File,
+            BuildErrorLocation::Elsewhere,
+        ]);
+        assert_eq!(errors[0].code.as_deref(), Some("E0499"));
+        assert_eq!(errors[0].range, Some((60, 65)));
+        assert_eq!(errors[3].range, None);
+    }
+
+    #[test]
+    fn test_build_errors_collect_suggestions() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("src")).unwrap();
+        fs::write(root.path().join("src/main.rs"), "").unwrap();
+
+        let stdout = serde_json::json!({
+            "reason": "compiler-message",
+            "message": {
+                "level": "error",
+                "code": { "code": "E0121" },
+                "message": "the placeholder `_` is not allowed within types on item signatures for return types",
+                "spans": [{ "file_name": "src/main.rs", "byte_start": 40, "byte_end": 41, "is_primary": true }],
+                "children": [{
+                    "message": "replace with the correct return type",
+                    "spans": [{ "file_name": "src/main.rs", "byte_start": 40, "byte_end": 41, "suggested_replacement": "i32" }],
+                }],
+            },
+        }).to_string();
+
+        let errors = build_errors(&stdout, root.path(), &root.path().join("src/main.rs"), Some((20, 100)), None);
+        assert_eq!(errors[0].suggestions, vec![BuildSuggestion { range: (40, 41), replacement: "i32".to_string() }]);
+    }
+}
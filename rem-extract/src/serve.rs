@@ -0,0 +1,293 @@
+//! Server mode: JSON-RPC 2.0 over stdin/stdout, one message per line.
+//!
+//! The server keeps an `ExtractionSession`, so projects are only loaded by the
+//! first request that needs them. Clients report changes to files with
+//! `reloadFile`, which updates the loaded projects incrementally.
+//!
+//! Methods:
+//! - `extract`: params are an `ExtractionInput`, the result is an
+//!   `ExtractionOutput`
+//! - `checkApplicable`: params are an `ExtractionInput` (`new_fn_name` may be
+//!   left out), the result is `{"applicable": bool, "error": ...}`
+//! - `listCandidates`: params are `{"file_path", "within"?: [start, end]}`,
+//!   the result is a list of `Candidate`s
+//! - `reloadFile`: params are `{"file_path", "text"?}`. Without `text` the
+//!   file is re-read from disk. The result is `{"updated": bool}`.
+//! - `shutdown`: stops the server after responding
+//!
+//! Extraction errors are returned with code `EXTRACTION_ERROR`, with the error
+//! object of `ExtractionError::to_json` as the `data`. A request that panics
+//! gets an `INTERNAL_ERROR` response, and the server keeps running with the
+//! loaded projects dropped.
+
+use std::{
+    io::{
+        self,
+        BufRead,
+        Write,
+    },
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+};
+
+use log::info;
+
+use serde::Deserialize;
+
+use serde_json::{
+    json,
+    Value,
+};
+
+use crate::{
+    error::{
+        panic_message,
+        ExtractionError,
+    },
+    extraction::ExtractionInput,
+    session::ExtractionSession,
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const EXTRACTION_ERROR: i64 = -32000;
+
+/// The name used when `checkApplicable` is not given one
+const DEFAULT_FN_NAME: &str = "fun_name";
+
+#[derive(Debug, Deserialize)]
+struct CheckApplicableParams {
+    #[serde(alias = "file")]
+    file_path: String,
+    #[serde(default, alias = "name")]
+    new_fn_name: Option<String>,
+    #[serde(alias = "start")]
+    start_idx: u32,
+    #[serde(alias = "end")]
+    end_idx: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListCandidatesParams {
+    #[serde(alias = "file")]
+    file_path: String,
+    #[serde(default)]
+    within: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReloadFileParams {
+    #[serde(alias = "file")]
+    file_path: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+/// An error response
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new( code: i64, message: impl Into<String> ) -> Self {
+        RpcError { code, message: message.into(), data: None }
+    }
+
+    fn to_json( &self ) -> Value {
+        let mut error: Value = json!({ "code": self.code, "message": self.message });
+        if let Some( data ) = &self.data {
+            error["data"] = data.clone();
+        }
+        error
+    }
+}
+
+impl From<ExtractionError> for RpcError {
+    fn from( error: ExtractionError ) -> Self {
+        RpcError {
+            code: EXTRACTION_ERROR,
+            message: error.to_string(),
+            data: Some( error.to_json()["error"].clone() ),
+        }
+    }
+}
+
+/// Serves requests from `input` until `shutdown` is received or `input` is
+/// closed. Responses are written to `output`, one per line.
+pub fn serve(
+    session: &mut ExtractionSession,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    for line in input.lines() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (response, shutdown): (Option<Value>, bool) = catch_panic( session, &line, handle_message );
+        if let Some( response ) = response {
+            writeln!( output, "{}", response )?;
+            output.flush()?;
+        }
+        if shutdown {
+            info!("Shutting down");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `handle` on the message, answering it with an internal error if it
+/// panics. The loaded projects are dropped, as the panic may have left them
+/// inconsistent.
+fn catch_panic(
+    session: &mut ExtractionSession,
+    line: &str,
+    handle: impl FnOnce( &mut ExtractionSession, &str ) -> (Option<Value>, bool),
+) -> (Option<Value>, bool) {
+    let payload = match panic::catch_unwind( AssertUnwindSafe(|| handle( &mut *session, line )) ) {
+        Ok( handled ) => return handled,
+        Err( payload ) => payload,
+    };
+    let message: String = panic_message( payload.as_ref() );
+    info!("Request panicked: {}", message);
+    session.drop_projects();
+
+    // Notifications get no response, even when they panic
+    let id: Option<Value> = serde_json::from_str::<Value>( line )
+        .ok()
+        .and_then(|message| message.get( "id" ).cloned());
+    let error: RpcError = RpcError::new( INTERNAL_ERROR, format!( "Internal error: {}", message ) );
+    ( id.map(|id| response( id, Err( error ) )), false )
+}
+
+/// Handles one message. Returns the response (`None` for notifications) and
+/// whether the server should shut down.
+fn handle_message( session: &mut ExtractionSession, line: &str ) -> (Option<Value>, bool) {
+    let message: Value = match serde_json::from_str( line ) {
+        Ok( message ) => message,
+        Err( e ) => return ( Some( response( Value::Null, Err( RpcError::new( PARSE_ERROR, e.to_string() ) ) ) ), false ),
+    };
+
+    let id: Option<Value> = message.get( "id" ).cloned();
+    let Some( method ) = message.get( "method" ).and_then( Value::as_str ) else {
+        let error: RpcError = RpcError::new( INVALID_REQUEST, "Missing method" );
+        return ( Some( response( id.unwrap_or( Value::Null ), Err( error ) ) ), false );
+    };
+    let params: Value = message.get( "params" ).cloned().unwrap_or( Value::Null );
+    info!("Request {:?}: {}", id, method);
+
+    let result: Result<Value, RpcError> = match method {
+        "extract" => parse_params::<ExtractionInput>( params ).and_then(|input| {
+            let output = session.extract( input )?;
+            Ok( json!( output ) )
+        }),
+        "checkApplicable" => parse_params::<CheckApplicableParams>( params ).map(|params| {
+            let input: ExtractionInput = ExtractionInput::new(
+                &params.file_path,
+                params.new_fn_name.as_deref().unwrap_or( DEFAULT_FN_NAME ),
+                params.start_idx,
+                params.end_idx,
+            );
+            match session.check_applicable( &input ) {
+                Ok(()) => json!({ "applicable": true, "error": null }),
+                Err( e ) => json!({ "applicable": false, "error": e.to_json()["error"] }),
+            }
+        }),
+        "listCandidates" => parse_params::<ListCandidatesParams>( params ).and_then(|params| {
+            let candidates = session.list_candidates( &params.file_path, params.within )?;
+            Ok( json!( candidates ) )
+        }),
+        "reloadFile" => parse_params::<ReloadFileParams>( params ).and_then(|params| {
+            let updated: bool = session.update_file( &params.file_path, params.text )?;
+            Ok( json!({ "updated": updated }) )
+        }),
+        "shutdown" => Ok( Value::Null ),
+        _ => Err( RpcError::new( METHOD_NOT_FOUND, format!( "Unknown method `{}`", method ) ) ),
+    };
+
+    let shutdown: bool = method == "shutdown";
+    // Notifications (messages without an id) get no response
+    ( id.map(|id| response( id, result )), shutdown )
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>( params: Value ) -> Result<T, RpcError> {
+    serde_json::from_value( params ).map_err(|e| RpcError::new( INVALID_PARAMS, e.to_string() ))
+}
+
+fn response( id: Value, result: Result<Value, RpcError> ) -> Value {
+    match result {
+        Ok( result ) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err( error ) => json!({ "jsonrpc": "2.0", "id": id, "error": error.to_json() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extraction::LoadOptions;
+
+    fn run(messages: &str) -> Vec<Value> {
+        let mut session = ExtractionSession::new(LoadOptions::default());
+        let mut output: Vec<u8> = vec![];
+        serve(&mut session, messages.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let responses = run("not json\n{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"frobnicate\"}\n{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"extract\", \"params\": {}}\n");
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["error"]["code"], PARSE_ERROR);
+        assert_eq!(responses[1]["id"], 1);
+        assert_eq!(responses[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(responses[2]["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_extraction_error_and_shutdown() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let missing = dir.path().join("missing.rs");
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "checkApplicable", "params": { "file": missing, "start": 1, "end": 2 } }),
+            json!({ "jsonrpc": "2.0", "method": "reloadFile", "params": { "file": missing } }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+        ];
+        let responses = run(&messages.iter().map(|message| format!("{}\n", message)).collect::<String>());
+        // The notification gets no response, and nothing is read after shutdown
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["result"]["applicable"], false);
+        assert_eq!(responses[0]["result"]["error"]["code"], "io");
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"], Value::Null);
+    }
+
+    #[test]
+    fn test_panics_are_internal_errors() {
+        let mut session = ExtractionSession::new(LoadOptions::default());
+        let panicking = |_: &mut ExtractionSession, _: &str| -> (Option<Value>, bool) { panic!("boom") };
+
+        let (response, shutdown) = catch_panic(&mut session, "{\"jsonrpc\": \"2.0\", \"id\": 7, \"method\": \"extract\"}", panicking);
+        let response = response.unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["error"]["code"], INTERNAL_ERROR);
+        assert!(response["error"]["message"].as_str().unwrap().contains("boom"));
+        assert!(!shutdown);
+
+        let (response, _) = catch_panic(&mut session, "{\"jsonrpc\": \"2.0\", \"method\": \"reloadFile\"}", panicking);
+        assert!(response.is_none());
+    }
+}
@@ -0,0 +1,422 @@
+//! Extraction sessions, which keep projects loaded between extractions.
+//!
+//! `extract_method` loads the project containing the input file from scratch
+//! every time, which dominates the time taken by an extraction. A session
+//! loads each project once and runs every later extraction in that project
+//! against the same analysis database.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use ra_ap_hir::{
+    ChangeWithProcMacros,
+    Semantics,
+};
+
+use ra_ap_ide::{
+    Analysis,
+    AnalysisHost,
+    RootDatabase,
+};
+
+use ra_ap_ide_assists::Assist;
+
+use ra_ap_ide_db::EditionedFileId;
+
+use ra_ap_syntax::{
+    ast::{
+        self,
+        HasName,
+    },
+    AstNode,
+    SourceFile,
+    SyntaxNode,
+};
+
+use ra_ap_vfs::{
+    AbsPathBuf,
+    FileId,
+    Vfs,
+    VfsPath,
+};
+
+use crate::{
+    error::ExtractionError,
+    extraction::{
+        check_input,
+        extract_from_project,
+        find_cargo_toml,
+        load_project,
+        ExtractionInput,
+        ExtractionOptions,
+        ExtractionOutput,
+        ExtractionPhase,
+        LoadOptions,
+        LoadStrategy,
+        PhaseReporter,
+        ProgressEvent,
+        prepare_extraction,
+    },
+    inline::{
+        check_inline_input,
+        inline_in_project,
+        InlineInput,
+        InlineOutput,
+    },
+    extraction_utils::{
+        convert_to_abs_path_buf,
+        filter_extract_function_assist,
+        get_assists,
+        run_analysis,
+        vfs_file_id,
+    },
+};
+
+/// A selection that the extract function assist is available for
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct Candidate {
+    /// Byte range of the selection
+    pub range: (u32, u32),
+    /// The `SyntaxKind` of the selected statement or expression
+    pub kind: String,
+    /// The function containing the selection
+    pub parent_method: String,
+}
+
+/// A project loaded into rust-analyzer's database
+struct LoadedProject {
+    host: AnalysisHost,
+    vfs: Vfs,
+}
+
+impl LoadedProject {
+    fn contains( &self, path: &AbsPathBuf ) -> bool {
+        self.vfs
+            .file_id( &VfsPath::new_real_path( path.as_str().to_string() ) )
+            .is_some()
+    }
+
+    /// Sets the contents of the file in the database (`None` removes it).
+    /// Returns whether the file is part of the project.
+    fn change_file( &mut self, path: &AbsPathBuf, text: Option<String> ) -> bool {
+        let vfs_path: VfsPath = VfsPath::new_real_path( path.as_str().to_string() );
+        let Some( file_id ) = self.vfs.file_id( &vfs_path ) else {
+            return false;
+        };
+        let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+        change.change_file( file_id, text );
+        self.host.apply_change( change );
+        true
+    }
+}
+
+/// The statements and tail expressions of every function body in the file,
+/// with the name of the function they are in
+fn candidate_selections( source_file: &SourceFile ) -> Vec<(SyntaxNode, String)> {
+    let mut selections: Vec<(SyntaxNode, String)> = vec![];
+    for function in source_file.syntax().descendants().filter_map( ast::Fn::cast ) {
+        let name: String = function.name().map_or( String::new(), |n| n.text().to_string() );
+        let Some( stmt_list ) = function.body().and_then(|body| body.stmt_list()) else {
+            continue;
+        };
+        for stmt in stmt_list.statements() {
+            if !matches!( stmt, ast::Stmt::Item( _ ) ) {
+                selections.push( (stmt.syntax().clone(), name.clone()) );
+            }
+        }
+        if let Some( tail ) = stmt_list.tail_expr() {
+            selections.push( (tail.syntax().clone(), name) );
+        }
+    }
+    selections
+}
+
+/// Keeps the projects used by extractions loaded, so that only the first
+/// extraction in each project pays for loading it. Changes to the files must
+/// be reported with `update_file`.
+pub struct ExtractionSession {
+    load_options: LoadOptions,
+    extraction_options: ExtractionOptions,
+    /// Loaded projects, keyed by the `Cargo.toml` they were loaded from
+    projects: HashMap<AbsPathBuf, LoadedProject>,
+    /// Contents of files that differ from disk (e.g. unsaved editor buffers),
+    /// applied to every project containing them, including ones loaded later
+    overlays: HashMap<AbsPathBuf, String>,
+}
+
+impl ExtractionSession {
+    /// Creates a session that loads projects with the given options. Nothing
+    /// is loaded until the first extraction.
+    #[allow(dead_code)]
+    pub fn new( load_options: LoadOptions ) -> Self {
+        Self::with_options( load_options, ExtractionOptions::default() )
+    }
+
+    /// Same as `new`, with the options used for every extraction
+    pub fn with_options( load_options: LoadOptions, extraction_options: ExtractionOptions ) -> Self {
+        ExtractionSession {
+            load_options,
+            extraction_options,
+            projects: HashMap::new(),
+            overlays: HashMap::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn load_options( &self ) -> &LoadOptions {
+        &self.load_options
+    }
+
+    pub fn extraction_options( &self ) -> &ExtractionOptions {
+        &self.extraction_options
+    }
+
+    /// The number of projects currently loaded
+    #[allow(dead_code)]
+    pub fn loaded_projects( &self ) -> usize {
+        self.projects.len()
+    }
+
+    /// Same as `extract_method_configured`, but reuses the loaded project
+    pub fn extract( &mut self, input: ExtractionInput ) -> Result<ExtractionOutput, ExtractionError> {
+        self.extract_with_progress( input, &|_| () )
+    }
+
+    /// Same as `extract`, but reports each phase to `progress`. The
+    /// `CargoMetadata`, `BuildScripts` and `VfsLoad` phases are only reported
+    /// when the project has to be loaded.
+    pub fn extract_with_progress(
+        &mut self,
+        input: ExtractionInput,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<ExtractionOutput, ExtractionError> {
+        let extraction_options: ExtractionOptions = self.extraction_options.clone();
+        self.extract_configured( input, &extraction_options, progress )
+    }
+
+    /// Same as `extract_with_progress`, with other options than the session's
+    /// for this extraction
+    pub fn extract_configured(
+        &mut self,
+        input: ExtractionInput,
+        extraction_options: &ExtractionOptions,
+        progress: &dyn Fn(ProgressEvent),
+    ) -> Result<ExtractionOutput, ExtractionError> {
+        let start_time: Instant = Instant::now();
+        let mut reporter: PhaseReporter<'_> = PhaseReporter::new( progress );
+
+        let input_abs_path: AbsPathBuf = check_input( &input )?;
+
+        reporter.begin( ExtractionPhase::ManifestDiscovery );
+        let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+        let project: &mut LoadedProject = self.project( &cargo_toml, &input_abs_path, &mut reporter )?;
+
+        extract_from_project(
+            &input,
+            &input_abs_path,
+            &mut project.host,
+            &project.vfs,
+            extraction_options,
+            &mut reporter,
+            start_time,
+        )
+    }
+
+    /// Same as `inline_function`, but reuses the loaded project
+    pub fn inline( &mut self, input: &InlineInput ) -> Result<InlineOutput, ExtractionError> {
+        let start_time: Instant = Instant::now();
+        let mut reporter: PhaseReporter<'_> = PhaseReporter::new( &|_| () );
+
+        let input_abs_path: AbsPathBuf = check_inline_input( input )?;
+
+        reporter.begin( ExtractionPhase::ManifestDiscovery );
+        let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+        let extraction_options: ExtractionOptions = self.extraction_options.clone();
+        let project: &mut LoadedProject = self.project( &cargo_toml, &input_abs_path, &mut reporter )?;
+
+        inline_in_project(
+            input,
+            &input_abs_path,
+            &mut project.host,
+            &project.vfs,
+            &extraction_options,
+            &mut reporter,
+            start_time,
+        )
+    }
+
+    /// Checks that the selection can be extracted, without computing the
+    /// edit. Returns the reason as an error if it can't.
+    pub fn check_applicable( &mut self, input: &ExtractionInput ) -> Result<(), ExtractionError> {
+        let mut reporter: PhaseReporter<'_> = PhaseReporter::new( &|_| () );
+        let input_abs_path: AbsPathBuf = check_input( input )?;
+        let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+        let project: &LoadedProject = self.project( &cargo_toml, &input_abs_path, &mut reporter )?;
+
+        prepare_extraction(
+            input,
+            &input_abs_path,
+            &project.host,
+            &project.vfs,
+            self.extraction_options.allow_errors,
+            &mut reporter,
+        ).map(|_| ())
+    }
+
+    /// Lists the statements and tail expressions of the functions in the file
+    /// that can be extracted. If `within` is given, only selections inside
+    /// that byte range are checked.
+    pub fn list_candidates(
+        &mut self,
+        file_path: &str,
+        within: Option<(u32, u32)>,
+    ) -> Result<Vec<Candidate>, ExtractionError> {
+        let mut reporter: PhaseReporter<'_> = PhaseReporter::new( &|_| () );
+        let input_abs_path: AbsPathBuf = convert_to_abs_path_buf( file_path )
+            .map_err(|_| ExtractionError::InvalidPath( file_path.to_string() ))?;
+        let cargo_toml: AbsPathBuf = find_cargo_toml( &input_abs_path )?;
+        let project: &LoadedProject = self.project( &cargo_toml, &input_abs_path, &mut reporter )?;
+
+        let file_id: FileId = vfs_file_id(
+            &project.vfs,
+            &VfsPath::new_real_path( input_abs_path.as_str().to_string() ),
+        )?;
+        let sema: Semantics<'_, RootDatabase> = Semantics::new( project.host.raw_database() );
+        let editioned_file_id: EditionedFileId = sema
+            .attach_first_edition( file_id )
+            .unwrap_or_else(|| EditionedFileId::current_edition( file_id ));
+        let source_file: SourceFile = sema.parse( editioned_file_id );
+        let analysis: Analysis = run_analysis( &project.host );
+
+        let mut candidates: Vec<Candidate> = vec![];
+        for (node, parent_method) in candidate_selections( &source_file ) {
+            let range: (u32, u32) = (
+                node.text_range().start().into(),
+                node.text_range().end().into(),
+            );
+            if within.is_some_and(|(start, end)| range.0 < start || range.1 > end) {
+                continue;
+            }
+
+            let assists: Vec<Assist> = get_assists( &analysis, &project.vfs, &input_abs_path, range )?;
+            if filter_extract_function_assist( assists ).is_ok() {
+                candidates.push( Candidate {
+                    range,
+                    kind: format!( "{:?}", node.kind() ),
+                    parent_method,
+                } );
+            }
+        }
+
+        Ok( candidates )
+    }
+
+    /// Updates the contents of a file in every loaded project containing it.
+    /// The text is kept as an overlay, and applied to projects loaded later.
+    /// If `text` is `None`, the overlay is dropped and the file is re-read
+    /// from disk. Changing a
+    /// `Cargo.toml` or `Cargo.lock` drops the projects it belongs to, so they
+    /// are loaded again by the next extraction.
+    /// Returns whether any loaded project was affected.
+    pub fn update_file( &mut self, file_path: &str, text: Option<String> ) -> Result<bool, ExtractionError> {
+        let path: AbsPathBuf = convert_to_abs_path_buf( file_path )
+            .map_err(|_| ExtractionError::InvalidPath( file_path.to_string() ))?;
+
+        let file_name: Option<&str> = Path::new( path.as_str() ).file_name().and_then(|name| name.to_str());
+        if matches!( file_name, Some( "Cargo.toml" ) | Some( "Cargo.lock" ) ) {
+            let changed_dir: &Path = Path::new( path.as_str() ).parent().unwrap_or( Path::new( "/" ) );
+            let before: usize = self.projects.len();
+            // A project loaded from another member of the workspace contains
+            // the changed package's files
+            self.projects.retain(|manifest, project| {
+                let project_dir: &Path = Path::new( manifest.as_str() ).parent().unwrap_or( Path::new( "/" ) );
+                let has_package: bool = project.vfs
+                    .iter()
+                    .filter_map(|(_, vfs_path)| vfs_path.as_path())
+                    .any(|file| Path::new( file.as_str() ).starts_with( changed_dir ));
+                !project_dir.starts_with( changed_dir ) && !changed_dir.starts_with( project_dir ) && !has_package
+            });
+            return Ok( self.projects.len() != before );
+        }
+
+        // A deleted file is removed from the database
+        let text: Option<String> = match text {
+            Some( text ) => {
+                self.overlays.insert( path.clone(), text.clone() );
+                Some( text )
+            },
+            None => {
+                self.overlays.remove( &path );
+                fs::read_to_string( path.as_str() ).ok()
+            },
+        };
+
+        let mut updated: bool = false;
+        for project in self.projects.values_mut() {
+            updated |= project.change_file( &path, text.clone() );
+        }
+
+        Ok( updated )
+    }
+
+    /// Drops every loaded project, keeping the overlays, so the projects are
+    /// loaded again by the next extraction. The servers call this after a
+    /// panic, which may have left a project inconsistent.
+    pub fn drop_projects( &mut self ) {
+        self.projects.clear();
+    }
+
+    /// Drops every loaded project and overlay
+    #[allow(dead_code)]
+    pub fn clear( &mut self ) {
+        self.projects.clear();
+        self.overlays.clear();
+    }
+
+    /// Returns a loaded project containing the file, loading the project of
+    /// the manifest if there is none. With the full load strategy any project
+    /// will do: loading one member of a workspace loads all of them, so the
+    /// other members reuse it. With the minimal load strategy only the crate
+    /// of the first file is loaded (and other crates only have their direct
+    /// dependencies), so only the manifest's own project is reused, and it is
+    /// reloaded if the file isn't part of it.
+    /// Overlays are applied to a newly loaded project.
+    fn project(
+        &mut self,
+        cargo_toml: &AbsPathBuf,
+        input_abs_path: &AbsPathBuf,
+        reporter: &mut PhaseReporter<'_>,
+    ) -> Result<&mut LoadedProject, ExtractionError> {
+        let others: usize = match self.load_options.load_strategy {
+            LoadStrategy::Full => self.projects.len(),
+            LoadStrategy::Minimal => 0,
+        };
+        let loaded: Option<AbsPathBuf> = std::iter::once( cargo_toml )
+            .chain( self.projects.keys().take( others ) )
+            .find(|manifest| self.projects.get( *manifest ).is_some_and(|project| project.contains( input_abs_path )))
+            .cloned();
+        if let Some( manifest ) = loaded {
+            return Ok( self.projects.get_mut( &manifest ).expect( "project is loaded" ) );
+        }
+
+        let (host, vfs) = load_project(
+            cargo_toml,
+            input_abs_path,
+            &self.load_options,
+            reporter,
+        )?;
+        let mut project: LoadedProject = LoadedProject { host, vfs };
+        for (path, text) in &self.overlays {
+            project.change_file( path, Some( text.clone() ) );
+        }
+        self.projects.insert( cargo_toml.clone(), project );
+
+        Ok( self.projects.get_mut( cargo_toml ).expect( "project was just loaded" ) )
+    }
+}
@@ -107,6 +107,31 @@ lazy_static! {
             73,
             80,
         ),
+        TestFile::new(
+            "edition_2015_crate_relative_path",
+            113,
+            118,
+        ),
+        TestFile::new(
+            "edition_2015_crate_root_path_in_selection",
+            85,
+            111,
+        ),
+        TestFile::new(
+            "edition_2018_async_await",
+            45,
+            60,
+        ),
+        TestFile::new(
+            "edition_2021_gen_identifier",
+            41,
+            48,
+        ),
+        TestFile::new(
+            "edition_2024_unsafe_extern",
+            107,
+            117,
+        ),
         TestFile::new(
             "empty_generic_param_list",
             52,
@@ -0,0 +1,203 @@
+//! Checks of the code produced by an extraction, run when enabled in the
+//! `ExtractionOptions`.
+//!
+//! `new_diagnostics` loads the modified file into the analysis database as a
+//! change, runs rust-analyzer's diagnostics on it and reports the diagnostics
+//! that the original file did not have. The original text is put back
+//! afterwards, so the database can be used for later extractions.
+//! `new_diagnostics_in_files` does the same for several files changed
+//! together, e.g. by inlining a function into its callers in other files.
+//!
+//! `inactive_code_ranges` uses the same diagnostics to find the code that
+//! `#[cfg]` disables in the original file.
+
+use std::{
+    collections::HashMap,
+    fmt,
+};
+
+use serde::Serialize;
+
+use ra_ap_hir::ChangeWithProcMacros;
+
+use ra_ap_ide::{
+    AnalysisHost,
+    AssistResolveStrategy,
+    Diagnostic,
+    DiagnosticsConfig,
+    Severity,
+};
+
+use ra_ap_vfs::FileId;
+
+use crate::{
+    error::ExtractionError,
+    extraction_utils::generate_diagnostics_config,
+};
+
+/// A diagnostic reported for the modified file that was not reported for the
+/// original file
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct NewDiagnostic {
+    /// The diagnostic code, e.g. `E0308` or `unresolved-ident`
+    pub code: String,
+    /// `error`, `warning` or `weak_warning`
+    pub severity: String,
+    pub message: String,
+    /// Byte range in the modified file
+    pub range: (u32, u32),
+}
+
+impl NewDiagnostic {
+    fn from_diagnostic( diagnostic: &Diagnostic ) -> Self {
+        NewDiagnostic {
+            code: diagnostic.code.as_str().to_string(),
+            severity: severity_name( diagnostic.severity ).to_string(),
+            message: diagnostic.message.clone(),
+            range: (
+                diagnostic.range.range.start().into(),
+                diagnostic.range.range.end().into(),
+            ),
+        }
+    }
+
+    pub fn is_error( &self ) -> bool {
+        self.severity == "error"
+    }
+}
+
+impl fmt::Display for NewDiagnostic {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!( f, "{}[{}]: {} (bytes {}..{})", self.severity, self.code, self.message, self.range.0, self.range.1 )
+    }
+}
+
+fn severity_name( severity: Severity ) -> &'static str {
+    match severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::WeakWarning => "weak_warning",
+        Severity::Allow => "allow",
+    }
+}
+
+/// A file changed by an extraction or inlining
+pub(crate) struct ChangedFile<'a> {
+    pub file_id: FileId,
+    pub original: &'a str,
+    pub modified: &'a str,
+}
+
+/// Runs the diagnostics on the original and the modified text of the file and
+/// returns the diagnostics only the modified text has. Diagnostics are matched
+/// by code and message, as their ranges move with the edit.
+pub(crate) fn new_diagnostics(
+    host: &mut AnalysisHost,
+    file_id: FileId,
+    original: &str,
+    modified: &str,
+) -> Result<Vec<NewDiagnostic>, ExtractionError> {
+    let mut new: Vec<Vec<NewDiagnostic>> = new_diagnostics_in_files(
+        host,
+        &[ChangedFile { file_id, original, modified }],
+    )?;
+    Ok( new.remove( 0 ) )
+}
+
+/// Same as `new_diagnostics` for several files. Every file is modified before
+/// the diagnostics are run, so that e.g. a call to a function removed from
+/// another file is reported. Returns the new diagnostics of each file, in the
+/// order of `files`.
+pub(crate) fn new_diagnostics_in_files(
+    host: &mut AnalysisHost,
+    files: &[ChangedFile<'_>],
+) -> Result<Vec<Vec<NewDiagnostic>>, ExtractionError> {
+    let config: DiagnosticsConfig = generate_diagnostics_config();
+    let before: Vec<Vec<Diagnostic>> = files
+        .iter()
+        .map(|file| file_diagnostics( host, &config, file.file_id ))
+        .collect::<Result<_, _>>()?;
+
+    for file in files {
+        set_file_text( host, file.file_id, file.modified );
+    }
+    let after: Result<Vec<Vec<Diagnostic>>, ExtractionError> = files
+        .iter()
+        .map(|file| file_diagnostics( host, &config, file.file_id ))
+        .collect();
+    for file in files {
+        set_file_text( host, file.file_id, file.original );
+    }
+
+    Ok( before
+        .iter()
+        .zip( after? )
+        .map(|( before, after )| diagnostics_not_in( before, &after ))
+        .collect() )
+}
+
+/// The diagnostics of `after` that aren't in `before`
+fn diagnostics_not_in( before: &[Diagnostic], after: &[Diagnostic] ) -> Vec<NewDiagnostic> {
+    let mut known: HashMap<(String, String), usize> = HashMap::new();
+    for diagnostic in before {
+        *known.entry( diagnostic_key( diagnostic ) ).or_default() += 1;
+    }
+
+    after
+        .iter()
+        .filter(|diagnostic| match known.get_mut( &diagnostic_key( diagnostic ) ) {
+            Some( count ) if *count > 0 => {
+                *count -= 1;
+                false
+            },
+            _ => true,
+        })
+        .map( NewDiagnostic::from_diagnostic )
+        .collect()
+}
+
+/// The ranges of the file that `#[cfg]` disables, from rust-analyzer's
+/// `inactive-code` diagnostics
+pub(crate) fn inactive_code_ranges(
+    host: &AnalysisHost,
+    file_id: FileId,
+) -> Result<Vec<(u32, u32)>, ExtractionError> {
+    let config: DiagnosticsConfig = generate_diagnostics_config();
+    Ok( file_diagnostics( host, &config, file_id )?
+        .iter()
+        .filter(|diagnostic| diagnostic.code.as_str() == "inactive-code")
+        .map(|diagnostic| (
+            diagnostic.range.range.start().into(),
+            diagnostic.range.range.end().into(),
+        ))
+        .collect() )
+}
+
+fn diagnostic_key( diagnostic: &Diagnostic ) -> (String, String) {
+    ( diagnostic.code.as_str().to_string(), diagnostic.message.clone() )
+}
+
+/// The diagnostics of the file, without the experimental and allowed ones
+fn file_diagnostics(
+    host: &AnalysisHost,
+    config: &DiagnosticsConfig,
+    file_id: FileId,
+) -> Result<Vec<Diagnostic>, ExtractionError> {
+    let diagnostics: Vec<Diagnostic> = host
+        .analysis()
+        .full_diagnostics( config, AssistResolveStrategy::None, file_id )
+        .map_err(|_| ExtractionError::AnalysisCancelled)?;
+
+    Ok( diagnostics
+        .into_iter()
+        .filter(|diagnostic| !diagnostic.experimental && !matches!( diagnostic.severity, Severity::Allow ))
+        .collect() )
+}
+
+/// Replaces the text of a file in the analysis database
+pub(crate) fn set_file_text( host: &mut AnalysisHost, file_id: FileId, text: &str ) {
+    let mut change: ChangeWithProcMacros = ChangeWithProcMacros::new();
+    change.change_file( file_id, Some( text.to_string() ) );
+    host.apply_change( change );
+}
+
@@ -0,0 +1,324 @@
+//! Non-fatal warnings about an extraction.
+//!
+//! An extraction that succeeds may still differ from what the user selected
+//! or intended: the selection was adjusted, an import was added, a comment was
+//! lost, and so on. Each warning has a code, so that tools can decide which
+//! ones they accept, and the range it refers to when there is one.
+//!
+//! The syntactic checks are here. The warnings that need the analysis or a
+//! build are produced where that information is computed, in `extraction`.
+
+use std::{
+    collections::HashSet,
+    fmt,
+};
+
+use serde::Serialize;
+
+use ra_ap_syntax::{
+    ast,
+    AstNode,
+    SourceFile,
+    SyntaxKind,
+    SyntaxNode,
+    TextRange,
+    TextSize,
+};
+
+use crate::placeholders::Placeholder;
+
+/// What a warning is about
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningCode {
+    /// The output has a `use` item the original file didn't have
+    ImportAdded,
+    /// A type in the signature is still `_`
+    PlaceholderType,
+    /// The selection was trimmed of whitespace
+    SelectionTrimmed,
+    /// rust-analyzer extracted a different range than the selection
+    SelectionSnapped,
+    /// A comment in the selection is not in the output
+    CommentDropped,
+    /// A parameter of the new function is a mutable reference
+    MutableParameter,
+    /// The new function returns a `ControlFlow` to carry `break`, `continue`
+    /// or `return` out of the selection
+    ControlFlowIntroduced,
+    /// The selection contains code disabled by `#[cfg]`, which
+    /// rust-analyzer doesn't analyse
+    InactiveCfg,
+    /// The file has syntax errors, which `--allow-errors` let through
+    SyntaxError,
+    /// A value with a significant `Drop` impl is dropped at another point
+    DropOrder,
+    /// The signature needs explicit lifetimes
+    LifetimesNeeded,
+    /// The modified file has a diagnostic the original didn't have
+    NewDiagnostic,
+    /// Inlining the new function back doesn't give the original file
+    RoundTripMismatch,
+    /// `cargo check` failed on the modified project
+    BuildFailed,
+    /// The extracted program behaves differently
+    BehaviourChanged,
+    /// The inlined function is still used, so it was kept
+    FunctionKept,
+}
+
+impl WarningCode {
+    pub fn as_str( &self ) -> &'static str {
+        match self {
+            WarningCode::ImportAdded => "import_added",
+            WarningCode::PlaceholderType => "placeholder_type",
+            WarningCode::SelectionTrimmed => "selection_trimmed",
+            WarningCode::SelectionSnapped => "selection_snapped",
+            WarningCode::CommentDropped => "comment_dropped",
+            WarningCode::MutableParameter => "mutable_parameter",
+            WarningCode::ControlFlowIntroduced => "control_flow_introduced",
+            WarningCode::InactiveCfg => "inactive_cfg",
+            WarningCode::SyntaxError => "syntax_error",
+            WarningCode::DropOrder => "drop_order",
+            WarningCode::LifetimesNeeded => "lifetimes_needed",
+            WarningCode::NewDiagnostic => "new_diagnostic",
+            WarningCode::RoundTripMismatch => "round_trip_mismatch",
+            WarningCode::BuildFailed => "build_failed",
+            WarningCode::BehaviourChanged => "behaviour_changed",
+            WarningCode::FunctionKept => "function_kept",
+        }
+    }
+}
+
+impl fmt::Display for WarningCode {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        f.write_str( self.as_str() )
+    }
+}
+
+/// A warning about the result of an extraction
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct ExtractionWarning {
+    pub code: WarningCode,
+    pub message: String,
+    /// Byte range in the output code, if the warning is about the output
+    pub range: Option<(u32, u32)>,
+    /// Byte range in the original file, if the warning is about the input
+    pub original_range: Option<(u32, u32)>,
+}
+
+impl ExtractionWarning {
+    /// A warning about the output code
+    pub fn in_output( code: WarningCode, message: String, range: Option<(u32, u32)> ) -> Self {
+        ExtractionWarning { code, message, range, original_range: None }
+    }
+
+    /// A warning about the original file
+    pub fn in_original( code: WarningCode, message: String, range: Option<(u32, u32)> ) -> Self {
+        ExtractionWarning { code, message, range: None, original_range: range }
+    }
+}
+
+impl fmt::Display for ExtractionWarning {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        write!( f, "{}: {}", self.code, self.message )
+    }
+}
+
+/// The warnings that follow from comparing the original file and the output:
+/// added imports, `ControlFlow`, dropped comments and `&mut` parameters.
+/// `selection` is the range extracted from the original file.
+pub(crate) fn syntactic_warnings(
+    original: &SourceFile,
+    selection: (u32, u32),
+    output: &SourceFile,
+    new_fn: Option<&ast::Fn>,
+) -> Vec<ExtractionWarning> {
+    let mut warnings: Vec<ExtractionWarning> = vec![];
+    warnings.extend( added_imports( original, output ) );
+    warnings.extend( control_flow( original, new_fn ) );
+    warnings.extend( dropped_comments( original, selection, output ) );
+    if let Some( new_fn ) = new_fn {
+        warnings.extend( mutable_parameters( new_fn ) );
+    }
+    warnings
+}
+
+/// The `use` items of the output that the original file doesn't have
+pub(crate) fn added_imports( original: &SourceFile, output: &SourceFile ) -> Vec<ExtractionWarning> {
+    let existing: HashSet<String> = original
+        .syntax()
+        .descendants()
+        .filter_map( ast::Use::cast )
+        .map(|item| normalise( &item.syntax().text().to_string() ))
+        .collect();
+
+    output
+        .syntax()
+        .descendants()
+        .filter_map( ast::Use::cast )
+        .filter(|item| !existing.contains( &normalise( &item.syntax().text().to_string() ) ))
+        .map(|item| ExtractionWarning::in_output(
+            WarningCode::ImportAdded,
+            format!( "added `{}`", item.syntax().text() ),
+            Some( to_range( item.syntax().text_range() ) ),
+        ))
+        .collect()
+}
+
+fn control_flow( original: &SourceFile, new_fn: Option<&ast::Fn> ) -> Option<ExtractionWarning> {
+    let new_fn: &ast::Fn = new_fn?;
+    let uses_control_flow = |node: &SyntaxNode| -> Option<ast::NameRef> {
+        node.descendants()
+            .filter_map( ast::NameRef::cast )
+            .find(|name| name.text() == "ControlFlow")
+    };
+    if uses_control_flow( original.syntax() ).is_some() {
+        return None;
+    }
+    let name: ast::NameRef = uses_control_flow( new_fn.syntax() )?;
+    Some( ExtractionWarning::in_output(
+        WarningCode::ControlFlowIntroduced,
+        "the new function returns a `ControlFlow` for the `break`, `continue` or `return` in the selection".to_string(),
+        Some( to_range( name.syntax().text_range() ) ),
+    ) )
+}
+
+fn dropped_comments( original: &SourceFile, selection: (u32, u32), output: &SourceFile ) -> Vec<ExtractionWarning> {
+    let selection: TextRange = TextRange::new( TextSize::new( selection.0 ), TextSize::new( selection.1 ) );
+    let kept: HashSet<String> = output
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::COMMENT)
+        .map(|token| token.text().trim().to_string())
+        .collect();
+
+    original
+        .syntax()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .filter(|token| token.kind() == SyntaxKind::COMMENT)
+        .filter(|token| selection.contains_range( token.text_range() ))
+        .filter(|token| !kept.contains( token.text().trim() ))
+        .map(|token| ExtractionWarning::in_original(
+            WarningCode::CommentDropped,
+            format!( "the comment `{}` is not in the output", token.text().trim() ),
+            Some( to_range( token.text_range() ) ),
+        ))
+        .collect()
+}
+
+fn mutable_parameters( new_fn: &ast::Fn ) -> Vec<ExtractionWarning> {
+    new_fn
+        .param_list()
+        .into_iter()
+        .flat_map(|params| params.params())
+        .filter(|param| matches!( param.ty(), Some( ast::Type::RefType( ref_type ) ) if ref_type.mut_token().is_some() ))
+        .map(|param| ExtractionWarning::in_output(
+            WarningCode::MutableParameter,
+            format!( "the parameter `{}` is a mutable reference", param.syntax().text() ),
+            Some( to_range( param.syntax().text_range() ) ),
+        ))
+        .collect()
+}
+
+/// The range of the type containing an unresolved placeholder: the type of
+/// its parameter, or the return type
+pub(crate) fn placeholder_range( new_fn: &ast::Fn, placeholder: &Placeholder ) -> Option<(u32, u32)> {
+    let ty: ast::Type = match &placeholder.param {
+        Some( name ) => new_fn
+            .param_list()?
+            .params()
+            .find(|param| param.pat().is_some_and(|pat| pat.syntax().text() == name.as_str()))?
+            .ty()?,
+        None => new_fn.ret_type()?.ty()?,
+    };
+    Some( to_range( ty.syntax().text_range() ) )
+}
+
+/// The range of the signature of the new function, up to its body
+pub(crate) fn signature_range( new_fn: &ast::Fn ) -> (u32, u32) {
+    let start: TextSize = new_fn.syntax().text_range().start();
+    let end: TextSize = new_fn
+        .body()
+        .map_or( new_fn.syntax().text_range().end(), |body| body.syntax().text_range().start() );
+    to_range( TextRange::new( start, end ) )
+}
+
+fn normalise( text: &str ) -> String {
+    text.split_whitespace().collect()
+}
+
+fn to_range( range: TextRange ) -> (u32, u32) {
+    ( range.start().into(), range.end().into() )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ra_ap_parser::Edition;
+    use ra_ap_syntax::ast::HasName;
+
+    const ORIGINAL: &str = "use std::fmt;
+
+fn main() {
+    let mut v = vec![1];
+    for i in 0..3 {
+        // grow the vector
+        v.push(i);
+        if v.len() > 2 { break; }
+    }
+}
+";
+
+    const OUTPUT: &str = "use std::ops::ControlFlow;
+
+use std::fmt;
+
+fn main() {
+    let mut v = vec![1];
+    for i in 0..3 {
+        if let ControlFlow::Break(_) = fun_name(&mut v, i) { break; }
+    }
+}
+
+fn fun_name(v: &mut Vec<i32>, i: i32) -> ControlFlow<()> {
+    v.push(i);
+    if v.len() > 2 { return ControlFlow::Break(()); }
+    ControlFlow::Continue(())
+}
+";
+
+    fn warnings() -> Vec<ExtractionWarning> {
+        let original = SourceFile::parse(ORIGINAL, Edition::CURRENT).tree();
+        let output = SourceFile::parse(OUTPUT, Edition::CURRENT).tree();
+        let new_fn = output.syntax().descendants().filter_map(ast::Fn::cast).find(|f| f.name().unwrap().text() == "fun_name");
+        let start = ORIGINAL.find("// grow").unwrap() as u32;
+        let end = ORIGINAL.find("break; }").unwrap() as u32 + 8;
+        syntactic_warnings(&original, (start, end), &output, new_fn.as_ref())
+    }
+
+    #[test]
+    fn test_syntactic_warnings() {
+        let codes: Vec<WarningCode> = warnings().iter().map(|w| w.code).collect();
+        assert_eq!(codes, vec![
+            WarningCode::ImportAdded,
+            WarningCode::ControlFlowIntroduced,
+            WarningCode::CommentDropped,
+            WarningCode::MutableParameter,
+        ]);
+    }
+
+    #[test]
+    fn test_warning_ranges() {
+        let warnings = warnings();
+        let import = warnings[0].range.unwrap();
+        assert_eq!(&OUTPUT[import.0 as usize..import.1 as usize], "use std::ops::ControlFlow;");
+        let comment = warnings[2].original_range.unwrap();
+        assert_eq!(&ORIGINAL[comment.0 as usize..comment.1 as usize], "// grow the vector");
+        assert_eq!(warnings[2].range, None);
+        let param = warnings[3].range.unwrap();
+        assert_eq!(&OUTPUT[param.0 as usize..param.1 as usize], "v: &mut Vec<i32>");
+    }
+}
@@ -0,0 +1,67 @@
+"""Smoke test of the Python bindings.
+
+Run after building the extension module into the active environment:
+
+    maturin develop
+    python -m unittest discover -s tests/python
+"""
+
+import os
+import tempfile
+import unittest
+
+import rem_extract
+
+MAIN = """fn main() {
+    let x = 1;
+    let y = x + 2;
+    println!("{}", y);
+}
+"""
+
+SELECTION = "let y = x + 2;"
+
+
+class BindingsTest(unittest.TestCase):
+    def setUp(self):
+        self.dir = tempfile.TemporaryDirectory()
+        root = self.dir.name
+        os.mkdir(os.path.join(root, "src"))
+        with open(os.path.join(root, "Cargo.toml"), "w") as f:
+            f.write('[package]\nname = "smoke"\nversion = "0.1.0"\nedition = "2021"\n')
+        self.main = os.path.join(root, "src", "main.rs")
+        with open(self.main, "w") as f:
+            f.write(MAIN)
+        self.start = MAIN.index(SELECTION)
+        self.end = self.start + len(SELECTION)
+
+    def tearDown(self):
+        self.dir.cleanup()
+
+    def test_extract(self):
+        session = rem_extract.ExtractionSession(load_strategy="minimal", use_cache=False)
+        output = session.extract(self.main, "add_two", self.start, self.end)
+        self.assertIn("fn add_two(x: i32) -> i32", output["code"])
+        self.assertEqual(session.loaded_projects, 1)
+
+    def test_check_applicable(self):
+        session = rem_extract.ExtractionSession()
+        result = session.check_applicable(self.main, self.start, self.end)
+        self.assertTrue(result["applicable"])
+        self.assertIsNone(result["error"])
+
+    def test_extraction_error(self):
+        with self.assertRaises(rem_extract.ExtractionError) as raised:
+            rem_extract.extract(self.main, "foo", self.end, self.start)
+        _, details = raised.exception.args
+        self.assertIn("code", details)
+
+    def test_unknown_options_are_rejected(self):
+        with self.assertRaises(ValueError):
+            rem_extract.ExtractionSession(load_stratgy="minimal")
+        with self.assertRaises(ValueError):
+            rem_extract.ExtractionSession(verify="sometimes")
+
+
+if __name__ == "__main__":
+    unittest.main()